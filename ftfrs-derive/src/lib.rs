@@ -0,0 +1,183 @@
+#![warn(missing_docs)]
+//! Derive macro for `ftfrs::FtfArgs`, turning the fields of a struct
+//! into event arguments. Use it through the `derive` feature of `ftfrs`.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr, Result};
+
+/// Most arguments an event can carry (4 bits of its header)
+const MAX_ARGUMENTS: usize = 15;
+
+/// How a field is written
+enum Kind {
+    Value,
+    Pointer,
+    KernelObjectId,
+}
+
+struct Field {
+    ident: syn::Ident,
+    name: String,
+    kind: Kind,
+}
+
+/// Implement `ftfrs::FtfArgs` for a struct with named fields, writing one
+/// argument per field, named after it. Argument names are static strings,
+/// interned once per writer.
+///
+/// Field attributes:
+/// * `#[ftf(rename = "name")]`: use another argument name
+/// * `#[ftf(skip)]`: don't write the field
+/// * `#[ftf(pointer)]`, `#[ftf(koid)]`: write a `u64` field as a `Pointer`
+///   or `KernelObjectId` argument
+///
+/// ```rust
+/// use ftfrs::FtfArgs;
+///
+/// #[derive(FtfArgs)]
+/// struct Query {
+///     table: String,
+///     #[ftf(rename = "rows")]
+///     row_count: u64,
+///     #[ftf(pointer)]
+///     buffer: u64,
+///     #[ftf(skip)]
+///     _cache: Vec<u8>,
+/// }
+/// assert_eq!(Query::ARGUMENT_COUNT, 3);
+/// ```
+///
+/// A struct with more fields than the 15 arguments an event can carry
+/// doesn't compile:
+///
+/// ```compile_fail
+/// #[derive(ftfrs::FtfArgs)]
+/// struct Wide {
+///     a: u8, b: u8, c: u8, d: u8, e: u8, f: u8, g: u8, h: u8,
+///     i: u8, j: u8, k: u8, l: u8, m: u8, n: u8, o: u8, p: u8,
+/// }
+/// ```
+#[proc_macro_derive(FtfArgs, attributes(ftf))]
+pub fn derive_ftf_args(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "FtfArgs can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "FtfArgs can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut parsed = Vec::new();
+    for field in fields {
+        if let Some(field) = parse_field(field)? {
+            parsed.push(field);
+        }
+    }
+    if parsed.len() > MAX_ARGUMENTS {
+        return Err(Error::new_spanned(
+            &input.ident,
+            format!(
+                "{} arguments, but an event carries at most {}",
+                parsed.len(),
+                MAX_ARGUMENTS
+            ),
+        ));
+    }
+
+    let count = parsed.len();
+    let names = parsed
+        .iter()
+        .map(|f| LitStr::new(&f.name, Span::call_site()));
+    let pushes = parsed.iter().enumerate().map(|(i, field)| {
+        let ident = &field.ident;
+        let name = quote!(writer.static_string(&NAMES[#i]));
+        match field.kind {
+            Kind::Value => quote! {
+                let name = #name;
+                arguments.push(::ftfrs::IntoArgument::into_argument(&self.#ident, name, writer));
+            },
+            Kind::Pointer => quote! {
+                arguments.push(::ftfrs::Argument::Pointer(#name, self.#ident));
+            },
+            Kind::KernelObjectId => quote! {
+                arguments.push(::ftfrs::Argument::KernelObjectId(#name, self.#ident));
+            },
+        }
+    });
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    if count == 0 {
+        return Ok(quote! {
+            impl #impl_generics ::ftfrs::FtfArgs for #ident #ty_generics #where_clause {
+                const ARGUMENT_COUNT: usize = 0;
+
+                fn write_arguments(
+                    &self,
+                    _writer: &mut ::ftfrs::ThreadWriter,
+                    _arguments: &mut ::std::vec::Vec<::ftfrs::Argument>,
+                ) {
+                }
+            }
+        });
+    }
+    Ok(quote! {
+        impl #impl_generics ::ftfrs::FtfArgs for #ident #ty_generics #where_clause {
+            const ARGUMENT_COUNT: usize = #count;
+
+            fn write_arguments(
+                &self,
+                writer: &mut ::ftfrs::ThreadWriter,
+                arguments: &mut ::std::vec::Vec<::ftfrs::Argument>,
+            ) {
+                static NAMES: [::ftfrs::TraceStr; #count] = [#(::ftfrs::trace_str!(#names)),*];
+                #(#pushes)*
+            }
+        }
+    })
+}
+
+/// The field, or None if it is skipped
+fn parse_field(field: &syn::Field) -> Result<Option<Field>> {
+    let ident = field.ident.clone().expect("fields are named");
+    let mut name = ident.to_string();
+    let mut kind = Kind::Value;
+    let mut skip = false;
+
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("ftf")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+            } else if meta.path.is_ident("skip") {
+                skip = true;
+            } else if meta.path.is_ident("pointer") {
+                kind = Kind::Pointer;
+            } else if meta.path.is_ident("koid") {
+                kind = Kind::KernelObjectId;
+            } else {
+                return Err(meta.error("expected `rename`, `skip`, `pointer` or `koid`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok((!skip).then_some(Field { ident, name, kind }))
+}
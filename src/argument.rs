@@ -3,13 +3,15 @@ use thiserror::Error;
 
 use crate::{
     extract_bits,
+    header::check_size,
     wordutils::{pad_and_write_string, read_aligned_str, read_u64_word},
-    Result, StringRef,
+    Result, StringRef, StringTable,
 };
 
 /// Arguments for Events and other records
 /// Each argument (except Null) has a name and a value
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Argument {
     /// Null argument
     Null(StringRef),
@@ -48,6 +50,100 @@ enum ArgumentType {
     Boolean = 9,
 }
 
+/// Value of an `Argument`, without its name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgValue<'a> {
+    /// Null value
+    Null,
+    /// Signed 32-bit integer
+    Int32(i32),
+    /// Unsigned 32-bit integer
+    UInt32(u32),
+    /// Signed 64-bit integer
+    Int64(i64),
+    /// Unsigned 64-bit integer
+    UInt64(u64),
+    /// Double-precision floating point number
+    Float(f64),
+    /// String, inline or interned
+    Str(&'a StringRef),
+    /// Pointer
+    Pointer(u64),
+    /// Kernel object ID
+    KernelObjectId(u64),
+    /// Boolean
+    Boolean(bool),
+}
+
+impl<'a> ArgValue<'a> {
+    /// Whether the value is null
+    pub fn is_null(&self) -> bool {
+        matches!(self, ArgValue::Null)
+    }
+
+    /// Any integer value which fits in an i64
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            ArgValue::Int32(v) => Some(v.into()),
+            ArgValue::UInt32(v) => Some(v.into()),
+            ArgValue::Int64(v) => Some(v),
+            ArgValue::UInt64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// Any integer, pointer or kernel object ID value which fits in a u64
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            ArgValue::Int32(v) => v.try_into().ok(),
+            ArgValue::UInt32(v) => Some(v.into()),
+            ArgValue::Int64(v) => v.try_into().ok(),
+            ArgValue::UInt64(v) | ArgValue::Pointer(v) | ArgValue::KernelObjectId(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Any numeric value, as a float. 64-bit integers may lose precision.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            ArgValue::Int32(v) => Some(v.into()),
+            ArgValue::UInt32(v) => Some(v.into()),
+            ArgValue::Int64(v) => Some(v as f64),
+            ArgValue::UInt64(v) => Some(v as f64),
+            ArgValue::Float(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// A boolean value
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            ArgValue::Boolean(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// An inline string value. Interned strings need a table,
+    /// see `as_str_in`.
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            ArgValue::Str(StringRef::Inline(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// A string value, inline or interned in `strings`
+    pub fn as_str_in<'t>(&self, strings: &'t StringTable) -> Option<&'t str>
+    where
+        'a: 't,
+    {
+        match self {
+            ArgValue::Str(s) => s.resolve(strings),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 #[error("Invalid argument type {0}")]
 pub struct ArgumentTypeParseError(u8);
@@ -127,7 +223,7 @@ impl Argument {
     fn create_header(
         arg_type: ArgumentType,
         arg_name: &StringRef,
-        num_words: u8,
+        num_words: usize,
         data: u32,
     ) -> u64 {
         let mut header: u64 = 0;
@@ -142,6 +238,7 @@ impl Argument {
 
     fn write_header_and_name<W: Write>(&self, writer: &mut W, data: u32) -> Result<()> {
         let num_words = self.encoding_num_words();
+        check_size(num_words)?;
         let arg_name = self.name();
         let header = Argument::create_header(self.arg_type(), arg_name, num_words, data);
         writer.write_all(&header.to_ne_bytes())?;
@@ -184,7 +281,23 @@ impl Argument {
         }
     }
 
-    pub(super) fn encoding_num_words(&self) -> u8 {
+    /// Value of the argument
+    pub fn value(&self) -> ArgValue<'_> {
+        match self {
+            Argument::Null(_) => ArgValue::Null,
+            Argument::Int32(_, v) => ArgValue::Int32(*v),
+            Argument::UInt32(_, v) => ArgValue::UInt32(*v),
+            Argument::Int64(_, v) => ArgValue::Int64(*v),
+            Argument::UInt64(_, v) => ArgValue::UInt64(*v),
+            Argument::Float(_, v) => ArgValue::Float(*v),
+            Argument::Str(_, v) => ArgValue::Str(v),
+            Argument::Pointer(_, v) => ArgValue::Pointer(*v),
+            Argument::KernelObjectId(_, v) => ArgValue::KernelObjectId(*v),
+            Argument::Boolean(_, v) => ArgValue::Boolean(*v),
+        }
+    }
+
+    pub(super) fn encoding_num_words(&self) -> usize {
         let mut num_words = 0;
         num_words += self.name().encoding_num_words();
 
@@ -198,13 +311,7 @@ impl Argument {
             | Argument::Pointer(_, _)
             | Argument::KernelObjectId(_, _)
             | Argument::Float(_, _) => 2,
-            Argument::Str(_, s) => {
-                if let StringRef::Inline(_) = s {
-                    2
-                } else {
-                    1
-                }
-            }
+            Argument::Str(_, s) => 1 + s.encoding_num_words(),
         };
 
         num_words
@@ -254,6 +361,54 @@ impl Argument {
     }
 }
 
+// `("count", 5u32).into()` builds an argument with an inline name
+macro_rules! impl_from_pair {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl<N: Into<String>> From<(N, $ty)> for Argument {
+                fn from((name, value): (N, $ty)) -> Self {
+                    Argument::$variant(StringRef::Inline(name.into()), value.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_pair!(
+    i8 => Int32,
+    i16 => Int32,
+    i32 => Int32,
+    u8 => UInt32,
+    u16 => UInt32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64,
+    f32 => Float,
+    f64 => Float,
+    bool => Boolean,
+);
+
+impl<N: Into<String>> From<(N, &str)> for Argument {
+    fn from((name, value): (N, &str)) -> Self {
+        Argument::Str(
+            StringRef::Inline(name.into()),
+            StringRef::Inline(value.to_string()),
+        )
+    }
+}
+
+impl<N: Into<String>> From<(N, String)> for Argument {
+    fn from((name, value): (N, String)) -> Self {
+        Argument::Str(StringRef::Inline(name.into()), StringRef::Inline(value))
+    }
+}
+
+impl<N: Into<String>> From<(N, ())> for Argument {
+    fn from((name, ()): (N, ())) -> Self {
+        Argument::Null(StringRef::Inline(name.into()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1476,4 +1631,38 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_values() {
+        let count: Argument = ("count", 5u32).into();
+        assert_eq!(
+            count,
+            Argument::UInt32(StringRef::Inline("count".into()), 5)
+        );
+        assert_eq!(count.value().as_i64(), Some(5));
+        assert_eq!(count.value().as_u64(), Some(5));
+        assert_eq!(count.value().as_f64(), Some(5.0));
+        assert_eq!(count.value().as_bool(), None);
+
+        let negative: Argument = ("delta", -3i64).into();
+        assert_eq!(negative.value().as_u64(), None);
+        assert_eq!(Argument::from(("big", u64::MAX)).value().as_i64(), None);
+        assert_eq!(
+            Argument::Pointer(StringRef::Ref(1), 0x1000)
+                .value()
+                .as_u64(),
+            Some(0x1000)
+        );
+        assert!(Argument::from(("none", ())).value().is_null());
+        assert_eq!(Argument::from(("ok", true)).value().as_bool(), Some(true));
+
+        let mut strings = StringTable::new();
+        strings.insert(4, "users".to_string());
+        let inline: Argument = ("table", "users").into();
+        let interned = Argument::Str(StringRef::Inline("table".into()), StringRef::Ref(4));
+        assert_eq!(inline.value().as_str(), Some("users"));
+        assert_eq!(interned.value().as_str(), None);
+        assert_eq!(interned.value().as_str_in(&strings), Some("users"));
+        assert_eq!(inline.value().as_str_in(&strings), Some("users"));
+    }
 }
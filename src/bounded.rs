@@ -0,0 +1,528 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::framing::{RecordSplitter, TraceState};
+use crate::hooks::{self, HookTarget};
+use crate::{extract_bits, header::RecordType, ProviderEventKind, Record};
+
+/// What a `BoundedWriter` does with a record which doesn't fit in its queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the sink to catch up
+    Block,
+    /// Drop the record being written
+    DropNewest,
+    /// Drop the oldest queued events to make room
+    DropOldest,
+}
+
+struct Entry {
+    bytes: Vec<u8>,
+    // only events are dropped; definitions and metadata are needed to
+    // decode whatever is kept
+    droppable: bool,
+    provider: Option<u32>,
+}
+
+#[derive(Default)]
+struct Queue {
+    entries: VecDeque<Entry>,
+    bytes: usize,
+    // whether the worker is writing entries it took off the queue
+    writing: bool,
+    closed: bool,
+    // providers whose drops were reported since the worker last took the queue
+    gaps: HashSet<Option<u32>>,
+    dropped: u64,
+    error: Option<io::Error>,
+}
+
+impl Queue {
+    fn push(&mut self, entry: Entry) {
+        self.bytes += entry.bytes.len();
+        self.entries.push_back(entry);
+    }
+
+    /// Note that records of `provider` were dropped, returning the
+    /// ProviderEvent record marking the gap for the first drop in each
+    /// batch the worker takes off the queue. The caller queues it where
+    /// the dropped records were.
+    fn report_drop(&mut self, provider: Option<u32>) -> Option<Entry> {
+        self.dropped += 1;
+        if !self.gaps.insert(provider) {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        Record::create_provider_event(
+            provider.unwrap_or(0),
+            ProviderEventKind::BufferOverflow.into(),
+        )
+        .write(&mut bytes)
+        .expect("writing to a Vec cannot fail");
+        Some(Entry {
+            bytes,
+            droppable: false,
+            provider,
+        })
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    // signalled when entries are queued or the writer is closed
+    queued: Condvar,
+    // signalled when the worker takes entries off the queue or finishes writing them
+    drained: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl HookTarget for Shared {
+    fn record_panic(&self, _message: &str, _location: &str) {}
+
+    fn flush_now(&self) {
+        // give the worker a little while to write what's queued
+        let deadline = Instant::now() + Duration::from_millis(100);
+        let mut queue = self.lock();
+        while !queue.entries.is_empty() || queue.writing {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            queue = self
+                .drained
+                .wait_timeout(queue, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+/// A sink which queues records in a bounded amount of memory and writes
+/// them to the underlying sink from a background thread, so a slow sink
+/// doesn't stall the threads producing the trace.
+///
+/// When the queue is full, the `OverflowPolicy` decides whether to wait or
+/// which records to drop. Only event records are ever dropped, and every gap
+/// is marked with a ProviderEvent record with event ID 0 ("records may be
+/// missing") for the provider the dropped records belonged to (0 outside of
+/// any provider, which `OverflowSummary` reads back as no provider).
+pub struct BoundedWriter {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+    splitter: RecordSplitter,
+    state: TraceState,
+}
+
+impl BoundedWriter {
+    /// Create a writer over `sink`
+    /// * capacity: maximum number of bytes of records queued, including the
+    ///   ProviderEvent records marking drops. A single record larger than this
+    ///   is still queued once the queue is empty, and definition and metadata
+    ///   records are queued even when it is full.
+    /// * policy: what to do with records which don't fit
+    pub fn new<W: Write + Send + 'static>(
+        mut sink: W,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            queued: Condvar::new(),
+            drained: Condvar::new(),
+            capacity,
+            policy,
+        });
+
+        let worker_shared = shared.clone();
+        let worker = std::thread::spawn(move || {
+            let shared = worker_shared;
+            let mut queue = shared.lock();
+            loop {
+                while queue.entries.is_empty() && !queue.closed {
+                    queue = shared
+                        .queued
+                        .wait(queue)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                if queue.entries.is_empty() {
+                    return;
+                }
+
+                let entries = std::mem::take(&mut queue.entries);
+                queue.bytes = 0;
+                queue.gaps.clear();
+                queue.writing = true;
+                drop(queue);
+                shared.drained.notify_all();
+
+                let mut result = Ok(());
+                for entry in &entries {
+                    result = result.and_then(|_| sink.write_all(&entry.bytes));
+                }
+                let result = result.and_then(|_| sink.flush());
+
+                queue = shared.lock();
+                queue.writing = false;
+                if let Err(e) = result {
+                    queue.error.get_or_insert(e);
+                }
+                shared.drained.notify_all();
+            }
+        });
+
+        Self {
+            shared,
+            worker: Some(worker),
+            splitter: RecordSplitter::default(),
+            state: TraceState::default(),
+        }
+    }
+
+    /// Number of records dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+
+    /// Number of bytes of records currently queued
+    pub fn queued_bytes(&self) -> usize {
+        self.shared.lock().bytes
+    }
+
+    /// Wait until everything queued so far is written to the sink and flushed,
+    /// and report the first error writing to it, if any
+    pub fn sync(&self) -> io::Result<()> {
+        let mut queue = self.shared.lock();
+        while (!queue.entries.is_empty() || queue.writing) && self.worker.is_some() {
+            queue = self
+                .shared
+                .drained
+                .wait(queue)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        match queue.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Give the background thread a little while to write what's queued if
+    /// the process panics or exits, since `flush` doesn't wait for it.
+    /// Register it before the `TraceWriter` writing to it, whose hooks then
+    /// run first. Returns whether this also happens at exit, which is only
+    /// available on unix, see `TraceWriter::register_hooks`.
+    pub fn register_hooks(&self) -> bool {
+        let shared: Arc<dyn HookTarget> = self.shared.clone();
+        hooks::register(Arc::downgrade(&shared));
+        hooks::FLUSHES_AT_EXIT
+    }
+
+    /// Write everything queued and stop the background thread
+    pub fn finish(mut self) -> io::Result<()> {
+        self.sync()?;
+        self.stop();
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.shared.lock().closed = true;
+            self.shared.queued.notify_all();
+            let _ = worker.join();
+        }
+    }
+
+    fn enqueue(&mut self, bytes: &[u8]) {
+        let header = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let droppable = extract_bits!(header, 0, 3) as u8 == RecordType::Event as u8;
+        if !droppable {
+            self.state.absorb(bytes);
+        }
+        let entry = Entry {
+            bytes: bytes.to_vec(),
+            droppable,
+            provider: self.state.current_provider(),
+        };
+
+        let shared = &*self.shared;
+        let mut queue = shared.lock();
+        let fits = |queue: &Queue| {
+            queue.entries.is_empty() || queue.bytes + entry.bytes.len() <= shared.capacity
+        };
+
+        if droppable && !fits(&queue) {
+            match shared.policy {
+                OverflowPolicy::Block => {
+                    while !fits(&queue) && !queue.closed {
+                        queue = shared
+                            .drained
+                            .wait(queue)
+                            .unwrap_or_else(PoisonError::into_inner);
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    if let Some(marker) = queue.report_drop(entry.provider) {
+                        queue.push(marker);
+                    }
+                    return;
+                }
+                OverflowPolicy::DropOldest => {
+                    // markers go where the first dropped record of their
+                    // provider was, before the records kept after it
+                    let mut kept = VecDeque::with_capacity(queue.entries.len());
+                    let mut bytes = queue.bytes;
+                    for old in std::mem::take(&mut queue.entries) {
+                        if old.droppable && bytes + entry.bytes.len() > shared.capacity {
+                            bytes -= old.bytes.len();
+                            if let Some(marker) = queue.report_drop(old.provider) {
+                                bytes += marker.bytes.len();
+                                kept.push_back(marker);
+                            }
+                        } else {
+                            kept.push_back(old);
+                        }
+                    }
+                    queue.entries = kept;
+                    queue.bytes = bytes;
+                }
+            }
+        }
+
+        queue.push(entry);
+        drop(queue);
+        shared.queued.notify_one();
+    }
+}
+
+impl Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.shared.lock().error.take() {
+            return Err(e);
+        }
+
+        self.splitter.push(buf);
+        let mut records = Vec::new();
+        while let Some(record) = self.splitter.next_record() {
+            records.push(record.to_vec());
+        }
+        for record in records {
+            self.enqueue(&record);
+        }
+        Ok(buf.len())
+    }
+
+    /// Hand everything queued so far to the background thread without
+    /// waiting for it to be written, and report the first error writing to
+    /// the sink, if any. `sync` waits for it to be written.
+    fn flush(&mut self) -> io::Result<()> {
+        self.shared.queued.notify_one();
+        match self.shared.lock().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BoundedWriter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Archive, EventRecord, MetadataRecord, Overflow, StringRef, ThreadRef};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A sink whose writes block until it is opened
+    #[derive(Clone, Default)]
+    struct GateSink {
+        data: Arc<Mutex<Vec<u8>>>,
+        entered: Arc<AtomicBool>,
+        gate: Arc<(Mutex<bool>, Condvar)>,
+    }
+
+    impl GateSink {
+        fn wait_entered(&self) {
+            while !self.entered.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+        }
+
+        fn open(&self) {
+            *self.gate.0.lock().unwrap() = true;
+            self.gate.1.notify_all();
+        }
+
+        fn records(&self) -> Vec<Record> {
+            Archive::read(&self.data.lock().unwrap()[..])
+                .unwrap()
+                .records
+        }
+    }
+
+    impl Write for GateSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.entered.store(true, Ordering::SeqCst);
+            let mut open = self.gate.0.lock().unwrap();
+            while !*open {
+                open = self.gate.1.wait(open).unwrap();
+            }
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(ts: u64) -> Record {
+        Record::create_instant_event(
+            ts,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(1),
+            Vec::new(),
+        )
+    }
+
+    fn timestamps(records: &[Record]) -> Vec<u64> {
+        records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Event(EventRecord::Instant(e)) => Some(crate::Event::timestamp(e)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Write the magic number, wait for the worker to block on it,
+    /// then write a string, a thread and 5 events (16 bytes each)
+    fn fill(policy: OverflowPolicy) -> io::Result<(GateSink, BoundedWriter)> {
+        let sink = GateSink::default();
+        // room for the provider info, string and thread (56 bytes),
+        // 3 events and a ProviderEvent
+        let mut writer = BoundedWriter::new(sink.clone(), 56 + 48 + 8, policy);
+        Record::create_magic_number()
+            .write(&mut writer)
+            .map_err(io::Error::other)?;
+        sink.wait_entered();
+
+        let mut records = vec![
+            Record::create_provider_info(3, "provider"),
+            Record::create_string(1, "name"),
+            Record::create_thread(1, 2, 3),
+        ];
+        records.extend((0..5).map(event));
+        for record in records {
+            record.write(&mut writer).map_err(io::Error::other)?;
+        }
+        Ok((sink, writer))
+    }
+
+    #[test]
+    fn test_drop_newest() -> io::Result<()> {
+        let (sink, writer) = fill(OverflowPolicy::DropNewest)?;
+        // check after opening the sink, so a failure doesn't hang the test
+        let dropped = writer.dropped();
+        sink.open();
+        writer.finish()?;
+        assert_eq!(dropped, 2);
+
+        let records = sink.records();
+        assert_eq!(timestamps(&records), vec![0, 1, 2]);
+        assert_eq!(records.last(), Some(&Record::create_provider_event(3, 0)));
+        assert!(records.contains(&Record::create_string(1, "name")));
+        let summary = Archive { records }.overflow_summary();
+        assert_eq!(
+            summary.overflows(Some(3)),
+            [Overflow {
+                record_index: 7,
+                last_before: Some(2),
+                first_after: None,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_oldest() -> io::Result<()> {
+        let (sink, writer) = fill(OverflowPolicy::DropOldest)?;
+        // check after opening the sink, so a failure doesn't hang the test
+        let dropped = writer.dropped();
+        sink.open();
+        writer.finish()?;
+        assert_eq!(dropped, 2);
+
+        let records = sink.records();
+        assert_eq!(timestamps(&records), vec![2, 3, 4]);
+        // one report for the whole gap, where the dropped events were
+        let reports = records
+            .iter()
+            .filter(|r| matches!(r, Record::Metadata(MetadataRecord::ProviderEvent(_))))
+            .count();
+        assert_eq!(reports, 1);
+        assert_eq!(records[4], Record::create_provider_event(3, 0));
+        assert_eq!(timestamps(&records[5..]), vec![2, 3, 4]);
+        let summary = Archive { records }.overflow_summary();
+        assert_eq!(
+            summary.overflows(Some(3)),
+            [Overflow {
+                record_index: 4,
+                last_before: None,
+                first_after: Some(2),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> io::Result<()> {
+        let sink = GateSink::default();
+        let opener = sink.clone();
+        std::thread::spawn(move || {
+            opener.wait_entered();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            opener.open();
+        });
+
+        let mut writer = BoundedWriter::new(sink.clone(), 32, OverflowPolicy::Block);
+        for ts in 0..10 {
+            event(ts).write(&mut writer).map_err(io::Error::other)?;
+            assert!(writer.queued_bytes() <= 32);
+        }
+        writer.finish()?;
+
+        assert_eq!(timestamps(&sink.records()), (0..10).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn test_flush_does_not_wait() -> io::Result<()> {
+        let sink = GateSink::default();
+        let mut writer = BoundedWriter::new(sink.clone(), 1024, OverflowPolicy::Block);
+        event(0).write(&mut writer).map_err(io::Error::other)?;
+        sink.wait_entered();
+        event(1).write(&mut writer).map_err(io::Error::other)?;
+
+        // the sink is still blocked on the first event
+        writer.flush()?;
+        assert_eq!(writer.queued_bytes(), 16);
+        sink.open();
+        writer.sync()?;
+        assert_eq!(writer.queued_bytes(), 0);
+        assert_eq!(timestamps(&sink.records()), vec![0, 1]);
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+
+/// An enable or disable pattern, e.g. `gfx:*` or `-debug`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    enable: bool,
+    glob: String,
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        match pattern.strip_prefix('-') {
+            Some(glob) => Self {
+                enable: false,
+                glob: glob.to_string(),
+            },
+            None => Self {
+                enable: true,
+                glob: pattern.strip_prefix('+').unwrap_or(pattern).to_string(),
+            },
+        }
+    }
+
+    fn to_pattern_string(&self) -> String {
+        if self.enable {
+            self.glob.clone()
+        } else {
+            format!("-{}", self.glob)
+        }
+    }
+}
+
+/// Whether `text` matches `glob`, where `*` matches any sequence
+/// of characters and `?` any single character
+pub(crate) fn glob_match(glob: &str, text: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut g, mut t) = (0, 0);
+    // position of the last `*` in the glob, and of the text it was tried against
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` swallow one more character
+                Some((star, start)) => {
+                    g = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// Whether `category` is enabled by `patterns`.
+/// Patterns are applied in order and the last matching one wins. A category
+/// matching none of them is enabled unless there are enable patterns, so
+/// `-debug` alone enables everything but `debug`, while `gfx:*` alone
+/// enables only the `gfx:` categories.
+fn evaluate(patterns: &[Pattern], category: &str) -> bool {
+    let mut enabled = !patterns.iter().any(|p| p.enable);
+    for pattern in patterns {
+        if glob_match(&pattern.glob, category) {
+            enabled = pattern.enable;
+        }
+    }
+    enabled
+}
+
+/// Handle to the enabled bit of a single category.
+/// Checking it is a single atomic load, and it follows pattern
+/// changes made to the registry it came from.
+#[derive(Debug, Clone)]
+pub struct Category {
+    name: Arc<str>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Category {
+    /// Name of the category
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether events of this category are currently traced
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    patterns: RwLock<Vec<Pattern>>,
+    categories: RwLock<HashMap<Arc<str>, Arc<AtomicBool>>>,
+}
+
+/// Set of enable and disable patterns deciding which event categories are
+/// traced, e.g. `gfx:*` or `-debug`. The enabled bit of every category
+/// looked up is cached, and updated when the patterns change, so the set
+/// can be changed at runtime while the trace keeps going.
+///
+/// Clones share the same patterns. Starts with every category enabled.
+#[derive(Debug, Clone, Default)]
+pub struct CategoryRegistry {
+    inner: Arc<Registry>,
+}
+
+impl CategoryRegistry {
+    /// Create a registry with every category enabled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the patterns. A leading `-` makes a disable pattern, and `*`
+    /// and `?` match any sequence of characters and any single character.
+    pub fn set_patterns<I, S>(&self, patterns: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let patterns = patterns
+            .into_iter()
+            .map(|p| Pattern::parse(p.as_ref()))
+            .collect();
+        self.update(|p| *p = patterns);
+    }
+
+    /// Enable the categories matching `glob`, on top of the current patterns
+    pub fn enable(&self, glob: &str) {
+        self.update(|p| {
+            p.push(Pattern {
+                enable: true,
+                glob: glob.to_string(),
+            })
+        });
+    }
+
+    /// Disable the categories matching `glob`, on top of the current patterns
+    pub fn disable(&self, glob: &str) {
+        self.update(|p| {
+            p.push(Pattern {
+                enable: false,
+                glob: glob.to_string(),
+            })
+        });
+    }
+
+    /// Remove every pattern, enabling every category
+    pub fn enable_all(&self) {
+        self.update(Vec::clear);
+    }
+
+    /// Current patterns, in the form `set_patterns` takes
+    pub fn patterns(&self) -> Vec<String> {
+        self.inner
+            .patterns
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(Pattern::to_pattern_string)
+            .collect()
+    }
+
+    /// Handle to the enabled bit of `name`
+    pub fn category(&self, name: &str) -> Category {
+        let (name, enabled) = self.entry(name);
+        Category { name, enabled }
+    }
+
+    /// Whether events of `category` are currently traced
+    pub fn is_enabled(&self, category: &str) -> bool {
+        if let Some(enabled) = self
+            .inner
+            .categories
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(category)
+        {
+            return enabled.load(Ordering::Relaxed);
+        }
+        self.entry(category).1.load(Ordering::Relaxed)
+    }
+
+    fn entry(&self, name: &str) -> (Arc<str>, Arc<AtomicBool>) {
+        // patterns before categories, like `update`
+        let patterns = self
+            .inner
+            .patterns
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut categories = self
+            .inner
+            .categories
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some((name, enabled)) = categories.get_key_value(name) {
+            return (name.clone(), enabled.clone());
+        }
+        let key: Arc<str> = name.into();
+        let enabled = Arc::new(AtomicBool::new(evaluate(&patterns, name)));
+        categories.insert(key.clone(), enabled.clone());
+        (key, enabled)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Vec<Pattern>)) {
+        let mut patterns = self
+            .inner
+            .patterns
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        f(&mut patterns);
+
+        for (name, enabled) in self
+            .inner
+            .categories
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+        {
+            enabled.store(evaluate(&patterns, name), Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("gfx:*", "gfx:compositor"));
+        assert!(glob_match("gfx:*", "gfx:"));
+        assert!(!glob_match("gfx:*", "gfx"));
+        assert!(glob_match("*:debug", "net:tcp:debug"));
+        assert!(glob_match("n?t*p", "net:tcp"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxbxxa"));
+        assert!(glob_match("debug", "debug"));
+        assert!(!glob_match("debug", "debugger"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let registry = CategoryRegistry::new();
+        assert!(registry.is_enabled("anything"));
+
+        registry.set_patterns(["-debug"]);
+        assert!(registry.is_enabled("gfx"));
+        assert!(!registry.is_enabled("debug"));
+
+        registry.set_patterns(["gfx:*", "-gfx:verbose"]);
+        assert!(registry.is_enabled("gfx:compositor"));
+        assert!(!registry.is_enabled("gfx:verbose"));
+        assert!(!registry.is_enabled("net"));
+        assert_eq!(registry.patterns(), vec!["gfx:*", "-gfx:verbose"]);
+
+        // the last matching pattern wins
+        registry.enable("gfx:verbose");
+        assert!(registry.is_enabled("gfx:verbose"));
+
+        registry.enable_all();
+        assert!(registry.is_enabled("net"));
+    }
+
+    #[test]
+    fn test_cached_bits_follow_changes() {
+        let registry = CategoryRegistry::new();
+        let category = registry.category("net");
+        let shared = registry.clone();
+        assert!(category.is_enabled());
+
+        shared.disable("n*");
+        assert!(!category.is_enabled());
+        shared.set_patterns(["net"]);
+        assert!(category.is_enabled());
+        assert_eq!(category.name(), "net");
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Record;
+
+/// Source of event timestamps.
+/// Timestamps are ticks at `ticks_per_second`, so a trace written with a
+/// clock must start with an Initialization record carrying the same rate,
+/// which `initialization` builds.
+pub trait TraceClock: Send + Sync {
+    /// Number of ticks in a second
+    fn ticks_per_second(&self) -> u64;
+
+    /// Current time, as ticks
+    fn now(&self) -> u64;
+
+    /// Initialization record matching this clock
+    fn initialization(&self) -> Record {
+        Record::create_initialization(self.ticks_per_second())
+    }
+}
+
+/// Which system clock a `MonotonicClock` reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MonotonicSource {
+    /// `CLOCK_MONOTONIC`: doesn't advance while the system is suspended
+    #[default]
+    Monotonic,
+    /// `CLOCK_BOOTTIME`: keeps advancing while the system is suspended.
+    /// Falls back to `CLOCK_MONOTONIC` where it isn't available.
+    Boottime,
+}
+
+/// System monotonic clock, in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MonotonicClock {
+    source: MonotonicSource,
+}
+
+impl MonotonicClock {
+    /// Clock reading `CLOCK_MONOTONIC`
+    pub fn new() -> Self {
+        Self::with_source(MonotonicSource::Monotonic)
+    }
+
+    /// Clock reading `CLOCK_BOOTTIME`
+    pub fn boottime() -> Self {
+        Self::with_source(MonotonicSource::Boottime)
+    }
+
+    /// Clock reading `source`
+    pub fn with_source(source: MonotonicSource) -> Self {
+        Self { source }
+    }
+
+    /// System clock this clock reads
+    pub fn source(&self) -> MonotonicSource {
+        self.source
+    }
+}
+
+#[cfg(unix)]
+fn clock_gettime_ns(source: MonotonicSource) -> u64 {
+    let id = match source {
+        MonotonicSource::Monotonic => libc::CLOCK_MONOTONIC,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        MonotonicSource::Boottime => libc::CLOCK_BOOTTIME,
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        MonotonicSource::Boottime => libc::CLOCK_MONOTONIC,
+    };
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `ts` is a valid timespec to write to, and both clock IDs
+    // are supported on the targets they are selected for
+    let ret = unsafe { libc::clock_gettime(id, &mut ts) };
+    debug_assert_eq!(ret, 0);
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(not(unix))]
+fn clock_gettime_ns(_source: MonotonicSource) -> u64 {
+    // no portable system clock reading; count from the first call instead
+    static EPOCH: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    EPOCH
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_nanos() as u64
+}
+
+impl TraceClock for MonotonicClock {
+    fn ticks_per_second(&self) -> u64 {
+        1_000_000_000
+    }
+
+    fn now(&self) -> u64 {
+        clock_gettime_ns(self.source)
+    }
+}
+
+/// x86-64 time stamp counter.
+/// Much cheaper to read than the system clock, but its rate has to be
+/// measured (see `calibrate`), and it is only reliable on CPUs with an
+/// invariant TSC.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscClock {
+    ticks_per_second: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl TscClock {
+    /// Clock counting at a known rate
+    pub fn with_frequency(ticks_per_second: u64) -> Self {
+        Self { ticks_per_second }
+    }
+
+    /// Measure the TSC rate against the monotonic clock over `period`.
+    /// Longer periods give a more accurate rate; a few tens of
+    /// milliseconds is usually enough.
+    pub fn calibrate(period: Duration) -> Self {
+        let reference = MonotonicClock::new();
+        let (start_ns, start_tsc) = (reference.now(), read_tsc());
+        std::thread::sleep(period);
+        let (end_ns, end_tsc) = (reference.now(), read_tsc());
+
+        let elapsed_ns = end_ns.saturating_sub(start_ns).max(1) as u128;
+        let ticks = end_tsc.saturating_sub(start_tsc) as u128;
+        Self {
+            ticks_per_second: (ticks * 1_000_000_000 / elapsed_ns).max(1) as u64,
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_tsc() -> u64 {
+    // SAFETY: rdtsc is available on every x86-64 CPU
+    unsafe { std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl TraceClock for TscClock {
+    fn ticks_per_second(&self) -> u64 {
+        self.ticks_per_second
+    }
+
+    fn now(&self) -> u64 {
+        read_tsc()
+    }
+}
+
+/// Clock which only moves when told to, for tests and replaying traces.
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    ticks_per_second: u64,
+    now: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Clock at tick 0
+    pub fn new(ticks_per_second: u64) -> Self {
+        Self {
+            ticks_per_second,
+            now: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Set the current time, as ticks
+    pub fn set(&self, ticks: u64) {
+        self.now.store(ticks, Ordering::SeqCst);
+    }
+
+    /// Move the current time forward by `ticks`
+    pub fn advance(&self, ticks: u64) {
+        self.now.fetch_add(ticks, Ordering::SeqCst);
+    }
+}
+
+impl TraceClock for ManualClock {
+    fn ticks_per_second(&self) -> u64 {
+        self.ticks_per_second
+    }
+
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new(1000);
+        let shared = clock.clone();
+        clock.set(5);
+        shared.advance(10);
+        assert_eq!(clock.now(), 15);
+        assert_eq!(clock.initialization(), Record::create_initialization(1000));
+    }
+
+    #[test]
+    fn test_monotonic_clock() {
+        for clock in [MonotonicClock::new(), MonotonicClock::boottime()] {
+            let before = clock.now();
+            std::thread::sleep(Duration::from_millis(2));
+            let after = clock.now();
+            assert!(after - before >= 2_000_000);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_tsc_calibration() {
+        let clock = TscClock::calibrate(Duration::from_millis(20));
+        // any TSC still in use ticks faster than 100 MHz
+        assert!(clock.ticks_per_second() > 100_000_000);
+
+        let before = clock.now();
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = (clock.now() - before) as f64 / clock.ticks_per_second() as f64;
+        assert!(elapsed >= 0.009, "{}", elapsed);
+    }
+}
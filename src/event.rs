@@ -6,23 +6,37 @@ use thiserror::Error;
 use crate::{
     argument::Argument,
     extract_bits,
+    preserved::Preserved,
     wordutils::{read_aligned_str, read_u64_word},
-    RecordHeader, StringRef, ThreadRef,
+    RecordHeader, StringRef, StringTable, ThreadRef,
 };
 
+/// Type of an event, as encoded in its header
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EventType {
+    /// Instant event
     Instant = 0,
+    /// Counter event
     Counter = 1,
+    /// Duration Begin event
     DurationBegin = 2,
+    /// Duration End event
     DurationEnd = 3,
+    /// Duration Complete event
     DurationComplete = 4,
+    /// Async Begin event
     AsyncBegin = 5,
+    /// Async Instant event
     AsyncInstant = 6,
+    /// Async End event
     AsyncEnd = 7,
+    /// Flow Begin event
     FlowBegin = 8,
+    /// Flow Step event
     FlowStep = 9,
+    /// Flow End event
     FlowEnd = 10,
 }
 
@@ -63,15 +77,33 @@ pub trait Event {
     fn name(&self) -> &StringRef;
     /// Arguments to the event
     fn arguments(&self) -> &[Argument];
+
+    /// The first argument named `name`. Arguments with interned
+    /// names are skipped, see `arg_in`.
+    fn arg(&self, name: &str) -> Option<&Argument> {
+        self.arguments()
+            .iter()
+            .find(|a| matches!(a.name(), StringRef::Inline(n) if n == name))
+    }
+
+    /// The first argument named `name`, looking up interned names in `strings`
+    fn arg_in(&self, name: &str, strings: &StringTable) -> Option<&Argument> {
+        self.arguments()
+            .iter()
+            .find(|a| a.name().resolve(strings) == Some(name))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InnerEvent {
     timestamp: u64,
     thread: ThreadRef,
     category: StringRef,
     name: StringRef,
     arguments: Vec<Argument>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: Preserved,
 }
 
 impl InnerEvent {
@@ -88,6 +120,7 @@ impl InnerEvent {
             category,
             name,
             arguments,
+            preserved: Preserved::default(),
         }
     }
 
@@ -127,7 +160,7 @@ impl InnerEvent {
         }
 
         let header = RecordHeader::build_event_header(
-            num_words + event_extra_word.is_some() as u8,
+            num_words + event_extra_word.is_some() as usize,
             event_type,
             self.arguments.len(),
             self.thread.to_field(),
@@ -168,15 +201,66 @@ impl InnerEvent {
     }
 }
 
+// Setters of the fields every event type shares
+macro_rules! event_setters {
+    ($($ty:ty),*) => {
+        $(
+            impl $ty {
+                /// Set the timestamp the event occurred at
+                pub fn set_timestamp(&mut self, timestamp: u64) {
+                    self.event.timestamp = timestamp;
+                    self.event.preserved.clear();
+                }
+
+                /// Set the thread the event occurred on
+                pub fn set_thread(&mut self, thread: ThreadRef) {
+                    self.event.thread = thread;
+                    self.event.preserved.clear();
+                }
+
+                /// Set the category of the event
+                pub fn set_category(&mut self, category: StringRef) {
+                    self.event.category = category;
+                    self.event.preserved.clear();
+                }
+
+                /// Set the name of the event
+                pub fn set_name(&mut self, name: StringRef) {
+                    self.event.name = name;
+                    self.event.preserved.clear();
+                }
+
+                /// Arguments to the event, to edit in place.
+                /// An event carries at most 15 arguments.
+                pub fn arguments_mut(&mut self) -> &mut Vec<Argument> {
+                    self.event.preserved.clear();
+                    &mut self.event.arguments
+                }
+            }
+        )*
+    };
+}
+
+event_setters!(
+    Instant,
+    Counter,
+    DurationBegin,
+    DurationEnd,
+    DurationComplete
+);
+
 /// Instant event
 /// Describes a particular moment in time
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instant {
+    #[cfg_attr(feature = "serde", serde(flatten))]
     event: InnerEvent,
 }
 
 impl Instant {
-    pub(crate) fn new(
+    /// Instant event at `timestamp`
+    pub fn new(
         timestamp: u64,
         thread: ThreadRef,
         category: StringRef,
@@ -219,13 +303,16 @@ impl Event for Instant {
 /// Arguments represent sampled values in the timeseries
 /// represented by the counter
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Counter {
+    #[cfg_attr(feature = "serde", serde(flatten))]
     event: InnerEvent,
     counter_id: u64,
 }
 
 impl Counter {
-    pub(crate) fn new(
+    /// Counter event sampling the counter `counter_id`
+    pub fn new(
         timestamp: u64,
         thread: ThreadRef,
         category: StringRef,
@@ -244,6 +331,12 @@ impl Counter {
         self.counter_id
     }
 
+    /// Set the ID of the counter
+    pub fn set_counter_id(&mut self, counter_id: u64) {
+        self.counter_id = counter_id;
+        self.event.preserved.clear();
+    }
+
     fn parse<U: Read>(reader: &mut U, event: InnerEvent) -> Result<Self> {
         let counter_id = read_u64_word(reader)?;
         Ok(Self { event, counter_id })
@@ -280,12 +373,15 @@ impl Event for Counter {
 /// DurationBegin
 /// Marks the start of an operation
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DurationBegin {
+    #[cfg_attr(feature = "serde", serde(flatten))]
     event: InnerEvent,
 }
 
 impl DurationBegin {
-    pub(crate) fn new(
+    /// Event starting an operation at `timestamp`
+    pub fn new(
         timestamp: u64,
         thread: ThreadRef,
         category: StringRef,
@@ -328,12 +424,15 @@ impl Event for DurationBegin {
 /// Duration End
 /// Marks the end of an operation
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DurationEnd {
+    #[cfg_attr(feature = "serde", serde(flatten))]
     event: InnerEvent,
 }
 
 impl DurationEnd {
-    pub(crate) fn new(
+    /// Event ending an operation at `timestamp`
+    pub fn new(
         timestamp: u64,
         thread: ThreadRef,
         category: StringRef,
@@ -375,13 +474,16 @@ impl Event for DurationEnd {
 /// Duration Complete
 /// Marks start and end of an operation
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DurationComplete {
+    #[cfg_attr(feature = "serde", serde(flatten))]
     event: InnerEvent,
     end_ts: u64,
 }
 
 impl DurationComplete {
-    pub(crate) fn new(
+    /// Duration complete event, from `timestamp` to `end_ts`
+    pub fn new(
         timestamp: u64,
         thread: ThreadRef,
         category: StringRef,
@@ -400,6 +502,12 @@ impl DurationComplete {
         self.end_ts
     }
 
+    /// Set the timestamp of the end of the operation
+    pub fn set_end_ts(&mut self, end_ts: u64) {
+        self.end_ts = end_ts;
+        self.event.preserved.clear();
+    }
+
     fn parse<U: Read>(reader: &mut U, event: InnerEvent) -> Result<Self> {
         let duration_ticks = read_u64_word(reader)?;
         Ok(Self {
@@ -438,6 +546,7 @@ impl Event for DurationComplete {
 
 /// The various types of Events
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EventRecord {
     /// Instant event
     Instant(Instant),
@@ -464,6 +573,65 @@ pub enum EventRecord {
 }
 
 impl EventRecord {
+    /// Type of the event
+    pub fn kind(&self) -> EventType {
+        match self {
+            Self::Instant(_) => EventType::Instant,
+            Self::Counter(_) => EventType::Counter,
+            Self::DurationBegin(_) => EventType::DurationBegin,
+            Self::DurationEnd(_) => EventType::DurationEnd,
+            Self::DurationComplete(_) => EventType::DurationComplete,
+            Self::AsyncBegin => EventType::AsyncBegin,
+            Self::AsyncEnd => EventType::AsyncEnd,
+            Self::AsyncInstant => EventType::AsyncInstant,
+            Self::FlowBegin => EventType::FlowBegin,
+            Self::FlowEnd => EventType::FlowEnd,
+            Self::FlowStep => EventType::FlowStep,
+        }
+    }
+
+    /// The fields every event has (timestamp, thread, category, name and
+    /// arguments). None for the event types which aren't implemented yet,
+    /// which carry no fields.
+    pub fn as_event(&self) -> Option<&dyn Event> {
+        match self {
+            Self::Instant(e) => Some(e),
+            Self::Counter(e) => Some(e),
+            Self::DurationBegin(e) => Some(e),
+            Self::DurationEnd(e) => Some(e),
+            Self::DurationComplete(e) => Some(e),
+            Self::AsyncBegin
+            | Self::AsyncEnd
+            | Self::AsyncInstant
+            | Self::FlowBegin
+            | Self::FlowEnd
+            | Self::FlowStep => None,
+        }
+    }
+
+    /// Bytes the event was read from, for the implemented event types
+    pub(crate) fn preserved_mut(&mut self) -> Option<&mut Preserved> {
+        match self {
+            Self::Instant(e) => Some(&mut e.event.preserved),
+            Self::Counter(e) => Some(&mut e.event.preserved),
+            Self::DurationBegin(e) => Some(&mut e.event.preserved),
+            Self::DurationEnd(e) => Some(&mut e.event.preserved),
+            Self::DurationComplete(e) => Some(&mut e.event.preserved),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn preserved(&self) -> Option<&Preserved> {
+        match self {
+            Self::Instant(e) => Some(&e.event.preserved),
+            Self::Counter(e) => Some(&e.event.preserved),
+            Self::DurationBegin(e) => Some(&e.event.preserved),
+            Self::DurationEnd(e) => Some(&e.event.preserved),
+            Self::DurationComplete(e) => Some(&e.event.preserved),
+            _ => None,
+        }
+    }
+
     pub(crate) fn create_instant(
         timestamp: u64,
         thread: ThreadRef,
@@ -608,13 +776,7 @@ impl EventRecord {
 
         Ok((
             event_type,
-            InnerEvent {
-                timestamp,
-                thread,
-                category,
-                name,
-                arguments,
-            },
+            InnerEvent::new(timestamp, thread, category, name, arguments),
         ))
     }
 
@@ -1107,6 +1269,7 @@ mod tests {
             category: StringRef::Ref(10),
             name: StringRef::Ref(15),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1163,6 +1326,7 @@ mod tests {
             category: StringRef::Ref(2),
             name: StringRef::Ref(3),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let counter_record = EventRecord::Counter(Counter {
@@ -1205,6 +1369,7 @@ mod tests {
             category: StringRef::Ref(12),
             name: StringRef::Ref(20),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let duration_begin_record = EventRecord::DurationBegin(DurationBegin { event });
@@ -1244,6 +1409,7 @@ mod tests {
             category: StringRef::Ref(12),
             name: StringRef::Ref(20),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let duration_end_record = EventRecord::DurationEnd(DurationEnd { event });
@@ -1283,6 +1449,7 @@ mod tests {
             category: StringRef::Ref(15),
             name: StringRef::Ref(22),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let duration_complete_record = EventRecord::DurationComplete(DurationComplete {
@@ -1328,6 +1495,7 @@ mod tests {
             category: StringRef::Ref(2),
             name: StringRef::Ref(3),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1374,6 +1542,7 @@ mod tests {
             category: StringRef::Inline("cat".to_string()),
             name: StringRef::Ref(3),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1416,6 +1585,7 @@ mod tests {
             category: StringRef::Ref(2),
             name: StringRef::Inline("test".to_string()),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1461,6 +1631,7 @@ mod tests {
             category: StringRef::Inline("debug".to_string()),
             name: StringRef::Inline("operation".to_string()),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1536,6 +1707,7 @@ mod tests {
             category: StringRef::Ref(10),
             name: StringRef::Ref(15),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let original_record = EventRecord::Instant(Instant {
@@ -1574,6 +1746,7 @@ mod tests {
             category: StringRef::Ref(2),
             name: StringRef::Ref(3),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let original_record = EventRecord::Counter(Counter {
@@ -1614,6 +1787,7 @@ mod tests {
             category: StringRef::Ref(15),
             name: StringRef::Ref(22),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let original_record = EventRecord::DurationComplete(DurationComplete {
@@ -1657,6 +1831,7 @@ mod tests {
             category: StringRef::Inline("debug".to_string()),
             name: StringRef::Inline("operation".to_string()),
             arguments: Vec::new(),
+            preserved: Preserved::default(),
         };
 
         let original_record = EventRecord::Instant(Instant {
@@ -1785,6 +1960,7 @@ mod tests {
                 Argument::Boolean(StringRef::Ref(0x0045), true),
                 Argument::Str(StringRef::Ref(0x0046), StringRef::Ref(0x0047)),
             ],
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1874,6 +2050,7 @@ mod tests {
                     StringRef::Inline("hello world".to_string()),
                 ),
             ],
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -1944,6 +2121,7 @@ mod tests {
             category: StringRef::Ref(11),
             name: StringRef::Ref(16),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let counter_record = EventRecord::Counter(Counter {
@@ -2007,6 +2185,7 @@ mod tests {
             category: StringRef::Ref(12),
             name: StringRef::Ref(17),
             arguments: begin_args,
+            preserved: Preserved::default(),
         };
 
         let begin_record = EventRecord::DurationBegin(DurationBegin { event: begin_event });
@@ -2066,6 +2245,7 @@ mod tests {
             category: StringRef::Ref(12),
             name: StringRef::Ref(17),
             arguments: end_args,
+            preserved: Preserved::default(),
         };
 
         let end_record = EventRecord::DurationEnd(DurationEnd { event: end_event });
@@ -2131,6 +2311,7 @@ mod tests {
             category: StringRef::Ref(13),
             name: StringRef::Ref(18),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let complete_record = EventRecord::DurationComplete(DurationComplete {
@@ -2213,6 +2394,7 @@ mod tests {
             category: StringRef::Ref(14),
             name: StringRef::Ref(19),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -2365,6 +2547,7 @@ mod tests {
             category: StringRef::Inline("test-category".to_string()),
             name: StringRef::Inline("test-event-name".to_string()),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -2505,6 +2688,7 @@ mod tests {
             category: StringRef::Ref(20),
             name: StringRef::Ref(30),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -2566,6 +2750,7 @@ mod tests {
             category: StringRef::Ref(21),
             name: StringRef::Ref(31),
             arguments: args,
+            preserved: Preserved::default(),
         };
 
         let instant_record = EventRecord::Instant(Instant { event });
@@ -2743,4 +2928,120 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_arg_lookup() {
+        let mut strings = StringTable::new();
+        strings.insert(3, "rows".to_string());
+        let event = Instant::new(
+            0,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(2),
+            vec![
+                ("table", "users").into(),
+                Argument::UInt64(StringRef::Ref(3), 12),
+                ("table", "ignored").into(),
+            ],
+        );
+        assert_eq!(
+            event.arg("table").and_then(|a| a.value().as_str()),
+            Some("users")
+        );
+        assert!(event.arg("rows").is_none());
+        assert_eq!(
+            event
+                .arg_in("rows", &strings)
+                .and_then(|a| a.value().as_u64()),
+            Some(12)
+        );
+        assert!(event.arg_in("missing", &strings).is_none());
+    }
+
+    #[test]
+    fn test_edit_in_place() -> Result<()> {
+        let mut complete = DurationComplete::new(
+            10,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(2),
+            Vec::new(),
+            20,
+        );
+        complete.set_timestamp(100);
+        complete.set_end_ts(250);
+        complete.set_thread(ThreadRef::Inline {
+            process_koid: 5,
+            thread_koid: 6,
+        });
+        complete.set_category(StringRef::Inline("db".to_string()));
+        complete.set_name(StringRef::Ref(7));
+        complete.arguments_mut().push(("rows", 3u32).into());
+
+        let mut buffer = Vec::new();
+        Record::Event(EventRecord::DurationComplete(complete.clone())).write(&mut buffer)?;
+        let parsed = Record::read(&mut Cursor::new(buffer))?;
+        assert_eq!(
+            parsed,
+            Record::Event(EventRecord::DurationComplete(complete.clone()))
+        );
+        assert_eq!(complete.timestamp(), 100);
+        assert_eq!(complete.end_ts(), 250);
+
+        // the header only has room for 15 arguments
+        complete
+            .arguments_mut()
+            .extend((0..15).map(|i| Argument::from((i.to_string(), i))));
+        assert!(matches!(
+            Record::Event(EventRecord::DurationComplete(complete)).write(&mut Vec::new()),
+            Err(FtfError::TooManyArguments(16))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_common_fields() {
+        let events = [
+            EventRecord::create_instant(
+                1,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(2),
+                Vec::new(),
+            ),
+            EventRecord::create_counter(
+                2,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(3),
+                Vec::new(),
+                9,
+            ),
+            EventRecord::create_duration_complete(
+                3,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(4),
+                Vec::new(),
+                5,
+            ),
+        ];
+        let fields: Vec<_> = events
+            .iter()
+            .map(|e| {
+                let event = e.as_event().unwrap();
+                (e.kind(), event.timestamp(), event.name().clone())
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                (EventType::Instant, 1, StringRef::Ref(2)),
+                (EventType::Counter, 2, StringRef::Ref(3)),
+                (EventType::DurationComplete, 3, StringRef::Ref(4)),
+            ]
+        );
+        assert_eq!(EventRecord::FlowStep.kind(), EventType::FlowStep);
+        assert!(EventRecord::AsyncBegin.as_event().is_none());
+    }
 }
@@ -0,0 +1,736 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use crate::event::EventType;
+use crate::framing::{RecordSplitter, TraceState};
+use crate::tables::ProviderTables;
+use crate::{extract_bits, MetadataRecord, Record, RecordType, Result};
+
+/// Fires a snapshot of a `FlightRecorder`.
+/// Firing only sets a flag, so it is safe to do from a signal handler;
+/// the snapshot itself is written by the next call to
+/// `FlightRecorder::snapshot_if_triggered`.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotTrigger {
+    fired: Arc<AtomicBool>,
+}
+
+impl SnapshotTrigger {
+    /// Request a snapshot
+    pub fn fire(&self) {
+        self.fired.store(true, Ordering::Release);
+    }
+
+    /// Whether a snapshot has been requested and not yet taken
+    pub fn is_fired(&self) -> bool {
+        self.fired.load(Ordering::Acquire)
+    }
+
+    fn take(&self) -> bool {
+        self.fired.swap(false, Ordering::AcqRel)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Definition replaced by a String or Thread record in the ring
+#[derive(Debug, Clone)]
+enum Replaced {
+    String(u16, String),
+    Thread(u8, (u64, u64)),
+}
+
+struct Entry {
+    // None until the trace has a ProviderSection
+    provider: Option<u32>,
+    bytes: Vec<u8>,
+    // set for records redefining a string or thread index, with the
+    // definition the records before them saw
+    replaced: Option<Replaced>,
+}
+
+#[derive(Default)]
+struct Inner {
+    splitter: RecordSplitter,
+    capacity: usize,
+    state: TraceState,
+    entries: VecDeque<Entry>,
+    entries_len: usize,
+    duration_threshold: Option<u64>,
+    // begin timestamps of open DurationBegin events still in the ring,
+    // per provider and thread field
+    open_durations: HashMap<(Option<u32>, u64), Vec<u64>>,
+}
+
+/// Type, thread key and timestamp of a duration event
+fn duration_event(header: u64, bytes: &[u8]) -> Option<(EventType, u64, u64)> {
+    if bytes.len() < 16 {
+        return None;
+    }
+    let word = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+    let thread = match extract_bits!(header, 24, 31) {
+        // inline thread: key on the thread koid
+        0 if bytes.len() >= 32 => word(3) | (1 << 63),
+        t => t,
+    };
+    match EventType::try_from(extract_bits!(header, 16, 19) as u8) {
+        Ok(
+            event_type @ (EventType::DurationBegin
+            | EventType::DurationEnd
+            | EventType::DurationComplete),
+        ) => Some((event_type, thread, word(1))),
+        _ => None,
+    }
+}
+
+impl Inner {
+    fn record(&mut self, bytes: &[u8], trigger: &SnapshotTrigger) {
+        let header = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let record_type = extract_bits!(header, 0, 3) as u8;
+
+        // Definitions and preamble live outside the ring, so they
+        // survive for as long as an event might refer to them.
+        // Redefinitions also go in the ring, so a snapshot can give the
+        // records before them the definition they saw.
+        let mut replaced = None;
+        if record_type == RecordType::Event as u8 {
+            self.check_duration(header, bytes, trigger);
+        } else {
+            let previous = self.definition_before(record_type, bytes);
+            let Some(record) = self.state.absorb(bytes) else {
+                self.push(bytes, None);
+                return;
+            };
+            if record == Record::Metadata(MetadataRecord::MagicNumber) {
+                self.reset();
+            }
+            replaced = match (&record, previous) {
+                (Record::String(r), Some(Replaced::String(i, value))) if *r.value() != value => {
+                    Some(Replaced::String(i, value))
+                }
+                (Record::Thread(r), Some(Replaced::Thread(i, koids)))
+                    if (r.process_koid(), r.thread_koid()) != koids =>
+                {
+                    Some(Replaced::Thread(i, koids))
+                }
+                _ => None,
+            };
+            if replaced.is_none() {
+                return;
+            }
+        }
+        self.push(bytes, replaced);
+    }
+
+    /// Current definition of the index a String or Thread record defines
+    fn definition_before(&self, record_type: u8, bytes: &[u8]) -> Option<Replaced> {
+        if record_type != RecordType::String as u8 && record_type != RecordType::Thread as u8 {
+            return None;
+        }
+        let tables = self.state.tables(self.state.current_provider())?;
+        match Record::read(&mut &bytes[..]).ok()? {
+            Record::String(r) => tables
+                .strings
+                .get(r.index())
+                .map(|value| Replaced::String(r.index(), value.to_string())),
+            Record::Thread(r) => tables
+                .threads
+                .get(r.index())
+                .map(|koids| Replaced::Thread(r.index(), koids)),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8], replaced: Option<Replaced>) {
+        self.entries_len += bytes.len();
+        self.entries.push_back(Entry {
+            provider: self.state.current_provider(),
+            bytes: bytes.to_vec(),
+            replaced,
+        });
+        while self.entries_len > self.capacity {
+            match self.entries.pop_front() {
+                Some(e) => {
+                    self.entries_len -= e.bytes.len();
+                    self.forget_duration(e.provider, &e.bytes);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn check_duration(&mut self, header: u64, bytes: &[u8], trigger: &SnapshotTrigger) {
+        let Some(threshold) = self.duration_threshold else {
+            return;
+        };
+        let Some((event_type, thread, timestamp)) = duration_event(header, bytes) else {
+            return;
+        };
+        let thread = (self.state.current_provider(), thread);
+
+        let duration = match event_type {
+            EventType::DurationComplete => {
+                let end_ts = u64::from_le_bytes(bytes[bytes.len() - 8..].try_into().unwrap());
+                Some(end_ts.wrapping_sub(timestamp))
+            }
+            EventType::DurationBegin => {
+                self.open_durations
+                    .entry(thread)
+                    .or_default()
+                    .push(timestamp);
+                None
+            }
+            _ => {
+                let open = self.open_durations.get_mut(&thread);
+                let begin = open.and_then(Vec::pop);
+                if self.open_durations.get(&thread).is_some_and(Vec::is_empty) {
+                    self.open_durations.remove(&thread);
+                }
+                begin.map(|begin| timestamp.wrapping_sub(begin))
+            }
+        };
+
+        if duration.is_some_and(|d| d >= threshold) {
+            trigger.fire();
+        }
+    }
+
+    /// Stop waiting for the end of a DurationBegin event leaving the ring,
+    /// so begins which never end don't pile up. It is the oldest begin of
+    /// its thread, if it is still open.
+    fn forget_duration(&mut self, provider: Option<u32>, bytes: &[u8]) {
+        let header = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        if extract_bits!(header, 0, 3) as u8 != RecordType::Event as u8 {
+            return;
+        }
+        let Some((EventType::DurationBegin, thread, timestamp)) = duration_event(header, bytes)
+        else {
+            return;
+        };
+        let thread = (provider, thread);
+        if let Some(open) = self.open_durations.get_mut(&thread) {
+            if open.first() == Some(&timestamp) {
+                open.remove(0);
+            }
+            if open.is_empty() {
+                self.open_durations.remove(&thread);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        self.entries.clear();
+        self.entries_len = 0;
+        self.open_durations.clear();
+    }
+
+    fn snapshot<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.state.write_header(writer)?;
+
+        // Only re-emit the definitions the surviving records refer to before
+        // any redefinition in the ring, as they were when the oldest
+        // record was written; redefinitions are written in ring order
+        let mut used: HashMap<Option<u32>, (BTreeSet<u16>, BTreeSet<u8>)> = HashMap::new();
+        let mut order: Vec<Option<u32>> = Vec::new();
+        let mut first_strings: HashMap<(Option<u32>, u16), String> = HashMap::new();
+        let mut first_threads: HashMap<(Option<u32>, u8), (u64, u64)> = HashMap::new();
+        for entry in &self.entries {
+            let (strings, threads) = used.entry(entry.provider).or_insert_with(|| {
+                order.push(entry.provider);
+                Default::default()
+            });
+            match &entry.replaced {
+                Some(Replaced::String(index, value)) => {
+                    first_strings
+                        .entry((entry.provider, *index))
+                        .or_insert_with(|| value.clone());
+                }
+                Some(Replaced::Thread(index, koids)) => {
+                    first_threads
+                        .entry((entry.provider, *index))
+                        .or_insert(*koids);
+                }
+                None => {
+                    if let Ok(record) = Record::read(&mut &entry.bytes[..]) {
+                        record.for_each_ref(
+                            |s| {
+                                if !first_strings.contains_key(&(entry.provider, s)) {
+                                    strings.insert(s);
+                                }
+                            },
+                            |t| {
+                                if !first_threads.contains_key(&(entry.provider, t)) {
+                                    threads.insert(t);
+                                }
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        let empty = ProviderTables::default();
+        let write_definitions = |writer: &mut W, provider: Option<u32>| -> Result<()> {
+            let state = self.state.tables(provider).unwrap_or(&empty);
+            let (strings, threads) = &used[&provider];
+            for index in strings {
+                let value = match first_strings.get(&(provider, *index)) {
+                    Some(value) => Some(value.as_str()),
+                    None => state.strings.get(*index),
+                };
+                if let Some(value) = value {
+                    Record::create_string(*index, value).write(writer)?;
+                }
+            }
+            for index in threads {
+                let koids = match first_threads.get(&(provider, *index)) {
+                    Some(koids) => Some(*koids),
+                    None => state.threads.get(*index),
+                };
+                if let Some((process_koid, thread_koid)) = koids {
+                    Record::create_thread(*index, process_koid, thread_koid).write(writer)?;
+                }
+            }
+            Ok(())
+        };
+
+        // Records of no provider only come before the trace's first provider,
+        // so they go before the provider infos, which would claim them
+        let unattributed = self
+            .entries
+            .iter()
+            .position(|e| e.provider.is_some())
+            .unwrap_or(self.entries.len());
+        if unattributed > 0 {
+            write_definitions(writer, None)?;
+        }
+        for entry in self.entries.range(..unattributed) {
+            writer.write_all(&entry.bytes)?;
+        }
+
+        let mut current = self.state.write_providers(writer)?;
+        for provider in order.iter().flatten() {
+            Record::create_provider_section(*provider).write(writer)?;
+            write_definitions(writer, Some(*provider))?;
+            current = Some(*provider);
+        }
+
+        for entry in self.entries.range(unattributed..) {
+            if let Some(id) = entry.provider {
+                if current != Some(id) {
+                    Record::create_provider_section(id).write(writer)?;
+                    current = Some(id);
+                }
+            }
+            writer.write_all(&entry.bytes)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Keeps the most recent records of a trace in a bounded, circular buffer,
+/// overwriting the oldest ones, and dumps them as a standalone trace on demand.
+///
+/// The recorder is a sink: write an encoded trace into it (for example as the
+/// sink of a `TraceWriter`). Events and other records go into the ring buffer,
+/// while the initialization record, provider info and the string and thread
+/// tables are kept aside, so that a snapshot begins with the magic number,
+/// and each `ProviderInfo` with the provider's `InitializationRecord`,
+/// followed by just the string and thread definitions the surviving records
+/// refer to. Records redefining
+/// an index are kept in the ring, so every record keeps the definition it
+/// was written with.
+///
+/// Clones share the same buffer.
+#[derive(Clone)]
+pub struct FlightRecorder {
+    inner: Arc<Mutex<Inner>>,
+    trigger: SnapshotTrigger,
+}
+
+impl FlightRecorder {
+    /// Create a recorder which keeps at most `capacity` bytes of encoded records
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                capacity,
+                ..Default::default()
+            })),
+            trigger: SnapshotTrigger::default(),
+        }
+    }
+
+    /// Fire the trigger whenever a duration (a DurationComplete event, or a
+    /// matching DurationBegin/DurationEnd pair on the same thread) lasts at
+    /// least `ticks`
+    pub fn set_duration_threshold(&self, ticks: Option<u64>) {
+        let mut inner = lock(&self.inner);
+        inner.duration_threshold = ticks;
+        inner.open_durations.clear();
+    }
+
+    /// The trigger for this recorder. Fire it (from an API call, signal
+    /// handler, ...) to have the next `snapshot_if_triggered` dump a snapshot.
+    pub fn trigger(&self) -> SnapshotTrigger {
+        self.trigger.clone()
+    }
+
+    /// Number of bytes of records currently held in the ring buffer
+    pub fn len(&self) -> usize {
+        lock(&self.inner).entries_len
+    }
+
+    /// Whether the ring buffer holds no records
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write the buffered records as a standalone trace
+    pub fn snapshot<W: Write>(&self, mut writer: W) -> Result<()> {
+        // Encode under the lock, write outside it, so a slow
+        // destination doesn't stall the producers
+        let mut buffer = Vec::new();
+        lock(&self.inner).snapshot(&mut buffer)?;
+        writer.write_all(&buffer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Write a snapshot if the trigger has fired since the last one.
+    /// Returns whether a snapshot was written.
+    pub fn snapshot_if_triggered<W: Write>(&self, writer: W) -> Result<bool> {
+        if !self.trigger.take() {
+            return Ok(false);
+        }
+        self.snapshot(writer)?;
+        Ok(true)
+    }
+}
+
+impl Write for FlightRecorder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = lock(&self.inner);
+        inner.splitter.push(buf);
+
+        let mut records = Vec::new();
+        while let Some(record) = inner.splitter.next_record() {
+            records.push(record.to_vec());
+        }
+        for record in records {
+            inner.record(&record, &self.trigger);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Archive, StringRef, ThreadRef};
+    use std::io::Cursor;
+
+    fn event(ts: u64, name: u16) -> Record {
+        Record::create_instant_event(
+            ts,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(name),
+            Vec::new(),
+        )
+    }
+
+    fn write_all(recorder: &mut FlightRecorder, records: &[Record]) {
+        for r in records {
+            r.write(recorder).unwrap();
+        }
+    }
+
+    fn snapshot(recorder: &FlightRecorder) -> Vec<Record> {
+        let mut buffer = Vec::new();
+        recorder.snapshot(&mut buffer).unwrap();
+        Archive::read(Cursor::new(buffer)).unwrap().records
+    }
+
+    #[test]
+    fn test_snapshot_is_standalone() {
+        let mut recorder = FlightRecorder::new(1024);
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_magic_number(),
+                Record::create_initialization(1_000_000_000),
+                Record::create_provider_info(1, "provider"),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "unused"),
+                Record::create_string(3, "name"),
+                Record::create_thread(1, 10, 11),
+                event(100, 3),
+            ],
+        );
+
+        assert_eq!(
+            snapshot(&recorder),
+            vec![
+                Record::create_magic_number(),
+                Record::create_initialization(1_000_000_000),
+                Record::create_provider_info(1, "provider"),
+                Record::create_provider_section(1),
+                Record::create_string(1, "category"),
+                Record::create_string(3, "name"),
+                Record::create_thread(1, 10, 11),
+                event(100, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oldest_records_are_overwritten() {
+        // each event is 16 bytes, keep 3 of them
+        let mut recorder = FlightRecorder::new(48);
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_string(1, "category"),
+                Record::create_string(2, "old"),
+                Record::create_string(3, "new"),
+                Record::create_thread(1, 10, 11),
+            ],
+        );
+        for ts in 0..5 {
+            write_all(&mut recorder, &[event(ts, if ts < 2 { 2 } else { 3 })]);
+        }
+        assert_eq!(recorder.len(), 48);
+
+        let records = snapshot(&recorder);
+        assert_eq!(
+            records,
+            vec![
+                Record::create_magic_number(),
+                Record::create_string(1, "category"),
+                Record::create_string(3, "new"),
+                Record::create_thread(1, 10, 11),
+                event(2, 3),
+                event(3, 3),
+                event(4, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duration_threshold_trigger() -> Result<()> {
+        let mut recorder = FlightRecorder::new(1024);
+        recorder.set_duration_threshold(Some(100));
+        let trigger = recorder.trigger();
+
+        let short = Record::create_duration_complete_event(
+            0,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(1),
+            Vec::new(),
+            50,
+        );
+        write_all(&mut recorder, &[short]);
+        assert!(!trigger.is_fired());
+        assert!(!recorder.snapshot_if_triggered(io::sink())?);
+
+        let begin = Record::create_duration_begin_event(
+            1000,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(1),
+            Vec::new(),
+        );
+        let end = Record::create_duration_end_event(
+            1200,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(1),
+            Vec::new(),
+        );
+        write_all(&mut recorder, &[begin, end]);
+        assert!(trigger.is_fired());
+
+        let mut buffer = Vec::new();
+        assert!(recorder.snapshot_if_triggered(&mut buffer)?);
+        assert!(!trigger.is_fired());
+        assert_eq!(Archive::read(Cursor::new(buffer))?.records.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_redefinitions_keep_what_events_saw() {
+        // each event is 16 bytes, keep 4 records
+        let mut recorder = FlightRecorder::new(64);
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_string(1, "category"),
+                Record::create_string(2, "old"),
+                Record::create_thread(1, 10, 11),
+                event(0, 2),
+                event(1, 2),
+                Record::create_string(2, "new"),
+                // same definition again
+                Record::create_string(2, "new"),
+                event(2, 2),
+            ],
+        );
+
+        assert_eq!(
+            snapshot(&recorder),
+            vec![
+                Record::create_magic_number(),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "old"),
+                Record::create_thread(1, 10, 11),
+                event(0, 2),
+                event(1, 2),
+                Record::create_string(2, "new"),
+                event(2, 2),
+            ]
+        );
+
+        // once the older events are gone, only the new definition is needed
+        write_all(&mut recorder, &[event(3, 2), event(4, 2), event(5, 2)]);
+        assert_eq!(
+            snapshot(&recorder),
+            vec![
+                Record::create_magic_number(),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "new"),
+                Record::create_thread(1, 10, 11),
+                event(2, 2),
+                event(3, 2),
+                event(4, 2),
+                event(5, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unended_durations_leave_with_the_ring() {
+        let mut recorder = FlightRecorder::new(64);
+        recorder.set_duration_threshold(Some(100));
+        let begin = |ts| {
+            Record::create_duration_begin_event(
+                ts,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(1),
+                Vec::new(),
+            )
+        };
+        let records: Vec<_> = (0..100).map(begin).collect();
+        write_all(&mut recorder, &records);
+
+        let inner = lock(&recorder.inner);
+        assert_eq!(inner.entries.len(), 4);
+        assert_eq!(inner.open_durations[&(None, 1)], vec![96, 97, 98, 99]);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_provider_attribution() {
+        let mut recorder = FlightRecorder::new(1024);
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_magic_number(),
+                Record::create_initialization(1000),
+                Record::create_string(1, "category"),
+                Record::create_thread(1, 10, 11),
+                event(1, 1),
+                Record::create_provider_info(1, "one"),
+                Record::create_initialization(10),
+                Record::create_string(1, "one"),
+                event(2, 1),
+                Record::create_provider_info(2, "two"),
+                Record::create_initialization(20),
+                Record::create_string(1, "two"),
+                event(3, 1),
+            ],
+        );
+
+        assert_eq!(
+            snapshot(&recorder),
+            vec![
+                Record::create_magic_number(),
+                Record::create_initialization(1000),
+                // before any provider claims them
+                Record::create_string(1, "category"),
+                Record::create_thread(1, 10, 11),
+                event(1, 1),
+                Record::create_provider_info(1, "one"),
+                Record::create_initialization(10),
+                Record::create_provider_info(2, "two"),
+                Record::create_initialization(20),
+                Record::create_provider_section(1),
+                Record::create_string(1, "one"),
+                Record::create_provider_section(2),
+                Record::create_string(1, "two"),
+                Record::create_provider_section(1),
+                event(2, 1),
+                Record::create_provider_section(2),
+                event(3, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_durations_are_matched_per_provider() {
+        let mut recorder = FlightRecorder::new(1024);
+        recorder.set_duration_threshold(Some(100));
+        let trigger = recorder.trigger();
+        let duration = |ts, begin| {
+            let args = (ts, ThreadRef::Ref(1), StringRef::Ref(1), StringRef::Ref(1));
+            if begin {
+                Record::create_duration_begin_event(args.0, args.1, args.2, args.3, Vec::new())
+            } else {
+                Record::create_duration_end_event(args.0, args.1, args.2, args.3, Vec::new())
+            }
+        };
+
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_provider_section(1),
+                duration(0, true),
+                // same thread field, other provider
+                Record::create_provider_section(2),
+                duration(500, false),
+            ],
+        );
+        assert!(!trigger.is_fired());
+
+        write_all(
+            &mut recorder,
+            &[Record::create_provider_section(1), duration(200, false)],
+        );
+        assert!(trigger.is_fired());
+    }
+
+    #[test]
+    fn test_magic_number_resets() {
+        let mut recorder = FlightRecorder::new(1024);
+        write_all(
+            &mut recorder,
+            &[
+                Record::create_magic_number(),
+                Record::create_string(1, "category"),
+                event(1, 1),
+                Record::create_magic_number(),
+            ],
+        );
+        assert!(recorder.is_empty());
+        assert_eq!(snapshot(&recorder), vec![Record::create_magic_number()]);
+    }
+}
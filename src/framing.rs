@@ -0,0 +1,231 @@
+use std::io::Write;
+
+use crate::tables::ProviderTables;
+use crate::{extract_bits, header::RecordType, MetadataRecord, Record, Result, TableTracker};
+
+/// Size in bytes of the record starting with `header`, including the header.
+/// Large records carry a 32-bit size, everything else a 12-bit one.
+pub(crate) fn record_len(header: u64) -> usize {
+    let words = if extract_bits!(header, 0, 3) as u8 == RecordType::LargeBlob as u8 {
+        extract_bits!(header, 4, 35) as usize
+    } else {
+        extract_bits!(header, 4, 15) as usize
+    };
+    // a zero-sized record is malformed; treat it as just a header so we make progress
+    words.max(1) * 8
+}
+
+/// Accumulates bytes written in arbitrary chunks and
+/// hands them back out one whole record at a time
+#[derive(Debug, Default)]
+pub(crate) struct RecordSplitter {
+    pending: Vec<u8>,
+    start: usize,
+}
+
+impl RecordSplitter {
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        if self.start > 0 && self.start >= self.pending.len() / 2 {
+            self.pending.drain(..self.start);
+            self.start = 0;
+        }
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// The next complete record, if enough bytes have been pushed
+    pub(crate) fn next_record(&mut self) -> Option<&[u8]> {
+        let available = &self.pending[self.start..];
+        if available.len() < 8 {
+            return None;
+        }
+
+        let header = u64::from_le_bytes(available[..8].try_into().unwrap());
+        let len = record_len(header);
+        if available.len() < len {
+            return None;
+        }
+
+        let record = &self.pending[self.start..self.start + len];
+        self.start += len;
+        Some(record)
+    }
+}
+
+/// Everything a sink has to remember about the trace passing through it
+/// to write a later part of that trace out as a standalone trace:
+/// the preamble records, and each provider's string and thread tables.
+#[derive(Debug, Default)]
+pub(crate) struct TraceState {
+    // last Initialization record of each provider, None for the one
+    // written before any provider, in the order they first appeared
+    initializations: Vec<(Option<u32>, Record)>,
+    providers: Vec<Record>,
+    tables: TableTracker,
+}
+
+impl TraceState {
+    /// Update the state from an encoded record. Returns the parsed record if
+    /// it was a preamble, provider section or definition record, which the
+    /// state now accounts for, or None for anything else (events, records
+    /// which can't be parsed, ...). A magic number resets the state.
+    pub(crate) fn absorb(&mut self, bytes: &[u8]) -> Option<Record> {
+        let header = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        if extract_bits!(header, 0, 3) as u8 == RecordType::Event as u8 {
+            return None;
+        }
+
+        let record = Record::read(&mut &bytes[..]).ok()?;
+        match &record {
+            Record::Metadata(MetadataRecord::MagicNumber) => *self = Self::default(),
+            Record::Metadata(MetadataRecord::ProviderInfo(_)) => {
+                self.providers.push(record.clone());
+            }
+            Record::Initialization(_) => {
+                let provider = self.current_provider();
+                match self
+                    .initializations
+                    .iter_mut()
+                    .find(|(p, _)| *p == provider)
+                {
+                    Some((_, init)) => *init = record.clone(),
+                    None => self.initializations.push((provider, record.clone())),
+                }
+            }
+            Record::Metadata(MetadataRecord::ProviderSection(_))
+            | Record::String(_)
+            | Record::Thread(_) => (),
+            _ => return None,
+        }
+        self.tables.observe(&record);
+        Some(record)
+    }
+
+    /// The provider records are currently attributed to
+    pub(crate) fn current_provider(&self) -> Option<u32> {
+        self.tables.current_provider()
+    }
+
+    /// String and thread tables of `provider`, if it has any
+    pub(crate) fn tables(&self, provider: Option<u32>) -> Option<&ProviderTables> {
+        self.tables.provider_tables(provider)
+    }
+
+    /// Write the magic number, every provider info and the initialization
+    /// records. Returns the provider active after them.
+    pub(crate) fn write_preamble<W: Write>(&self, writer: &mut W) -> Result<Option<u32>> {
+        self.write_header(writer)?;
+        self.write_providers(writer)
+    }
+
+    /// Write the magic number and the initialization record written before
+    /// any provider, leaving no provider active
+    pub(crate) fn write_header<W: Write>(&self, writer: &mut W) -> Result<()> {
+        Record::create_magic_number().write(writer)?;
+        if let Some(init) = self.initialization(None) {
+            init.write(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Write every provider info, each followed by the provider's
+    /// initialization record. Returns the provider active after them.
+    pub(crate) fn write_providers<W: Write>(&self, writer: &mut W) -> Result<Option<u32>> {
+        let mut active = None;
+        for provider in &self.providers {
+            provider.write(writer)?;
+            if let Record::Metadata(MetadataRecord::ProviderInfo(info)) = provider {
+                active = Some(info.provider_id());
+                if let Some(init) = self.initialization(active) {
+                    init.write(writer)?;
+                }
+            }
+        }
+        // providers which only had a section
+        for (provider, init) in &self.initializations {
+            let Some(id) = *provider else {
+                continue;
+            };
+            let declared = self.providers.iter().any(|r| {
+                matches!(r, Record::Metadata(MetadataRecord::ProviderInfo(info)) if info.provider_id() == id)
+            });
+            if !declared {
+                Record::create_provider_section(id).write(writer)?;
+                init.write(writer)?;
+                active = Some(id);
+            }
+        }
+        Ok(active)
+    }
+
+    fn initialization(&self, provider: Option<u32>) -> Option<&Record> {
+        self.initializations
+            .iter()
+            .find(|(p, _)| *p == provider)
+            .map(|(_, init)| init)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Record;
+
+    #[test]
+    fn test_preamble_keeps_initialization_per_provider() -> Result<()> {
+        let records = [
+            Record::create_magic_number(),
+            Record::create_initialization(1000),
+            Record::create_provider_info(1, "one"),
+            Record::create_initialization(10),
+            Record::create_provider_info(2, "two"),
+            Record::create_provider_section(3),
+            Record::create_initialization(30),
+            Record::create_provider_section(1),
+            Record::create_initialization(20),
+        ];
+        let mut state = TraceState::default();
+        for record in &records {
+            let mut bytes = Vec::new();
+            record.write(&mut bytes)?;
+            state.absorb(&bytes);
+        }
+
+        let mut bytes = Vec::new();
+        assert_eq!(state.write_preamble(&mut bytes)?, Some(3));
+        assert_eq!(
+            crate::Archive::read(&bytes[..])?.records,
+            vec![
+                Record::create_magic_number(),
+                Record::create_initialization(1000),
+                Record::create_provider_info(1, "one"),
+                Record::create_initialization(20),
+                Record::create_provider_info(2, "two"),
+                Record::create_provider_section(3),
+                Record::create_initialization(30),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_byte_at_a_time() {
+        let mut bytes = Vec::new();
+        Record::create_magic_number().write(&mut bytes).unwrap();
+        Record::create_string(1, "hello world")
+            .write(&mut bytes)
+            .unwrap();
+        Record::create_thread(1, 2, 3).write(&mut bytes).unwrap();
+
+        let mut splitter = RecordSplitter::default();
+        let mut lens = Vec::new();
+        for b in &bytes {
+            splitter.push(std::slice::from_ref(b));
+            while let Some(record) = splitter.next_record() {
+                lens.push(record.len());
+            }
+        }
+
+        assert_eq!(lens, vec![8, 24, 24]);
+        assert!(splitter.next_record().is_none());
+    }
+}
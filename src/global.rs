@@ -0,0 +1,385 @@
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, OnceLock};
+
+use crate::category::{Category, CategoryRegistry};
+use crate::session::TraceSession;
+use crate::writer::{ThreadWriter, TraceWriter};
+use crate::{FtfError, Result};
+
+enum Tracer {
+    Writer(TraceWriter),
+    Session(TraceSession, u32),
+}
+
+impl Tracer {
+    fn thread_writer(&self) -> ThreadWriter {
+        match self {
+            Tracer::Writer(writer) => writer.thread_writer(),
+            Tracer::Session(session, provider_id) => session
+                .provider(*provider_id)
+                .expect("provider was checked on install"),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            Tracer::Writer(writer) => writer.flush(),
+            Tracer::Session(session, _) => session.flush(),
+        }
+    }
+}
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+// mirrors TRACER being set, so the macros' fast path is a single relaxed load
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static CATEGORIES: LazyLock<CategoryRegistry> = LazyLock::new(CategoryRegistry::new);
+
+thread_local! {
+    static THREAD_WRITER: RefCell<Option<ThreadWriter>> = const { RefCell::new(None) };
+}
+
+fn set_tracer(tracer: Tracer) -> Result<()> {
+    TRACER
+        .set(tracer)
+        .map_err(|_| FtfError::InvalidConfiguration("a tracer is already installed".to_string()))?;
+    INSTALLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Install `writer` as the process-global tracer used by the `ftf_*!` macros.
+/// Each thread gets its own `ThreadWriter` the first time it traces something.
+/// Fails if a tracer is already installed; a tracer stays installed for the
+/// rest of the process, so call `flush_tracer` before exiting.
+pub fn install(writer: TraceWriter) -> Result<()> {
+    set_tracer(Tracer::Writer(writer))
+}
+
+/// Install `session` as the process-global tracer, writing the macros'
+/// events as records of `provider_id`.
+/// Fails if a tracer is already installed, or the session has no such provider.
+pub fn install_session(session: TraceSession, provider_id: u32) -> Result<()> {
+    session.provider(provider_id)?;
+    set_tracer(Tracer::Session(session, provider_id))
+}
+
+/// Whether a process-global tracer is installed
+#[inline]
+pub fn is_installed() -> bool {
+    INSTALLED.load(Ordering::Relaxed)
+}
+
+/// Merge every thread's buffered records into the installed tracer's sink.
+/// Does nothing if no tracer is installed.
+pub fn flush_tracer() -> Result<()> {
+    match TRACER.get() {
+        Some(tracer) => tracer.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Categories of events traced by the `ftf_*!` macros.
+/// Independent of the categories of the installed tracer, which
+/// also apply to the macros' events.
+pub fn categories() -> &'static CategoryRegistry {
+    &CATEGORIES
+}
+
+/// Whether `category` is enabled for the macros, caching its
+/// enabled bit in a static of the macro's call site
+#[doc(hidden)]
+#[inline]
+pub fn callsite_enabled(callsite: &'static OnceLock<Category>, category: &str) -> bool {
+    let cached = callsite.get_or_init(|| CATEGORIES.category(category));
+    // the category of a call site is usually constant, but doesn't have to be
+    if cached.name() == category {
+        cached.is_enabled()
+    } else {
+        CATEGORIES.is_enabled(category)
+    }
+}
+
+/// Run `f` with the calling thread's `ThreadWriter` for the installed tracer.
+/// Returns None without running `f` if no tracer is installed, or if the
+/// thread is already inside `f` (e.g. an argument expression which traces
+/// something itself).
+#[doc(hidden)]
+pub fn with_thread_writer<T>(f: impl FnOnce(&mut ThreadWriter) -> T) -> Option<T> {
+    let tracer = TRACER.get()?;
+    THREAD_WRITER
+        .try_with(|cell| {
+            let mut writer = cell.try_borrow_mut().ok()?;
+            Some(f(writer.get_or_insert_with(|| tracer.thread_writer())))
+        })
+        .ok()
+        .flatten()
+}
+
+// Checks the category of a macro call site
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __ftf_enabled {
+    ($category:expr) => {{
+        static CATEGORY: ::std::sync::OnceLock<$crate::Category> = ::std::sync::OnceLock::new();
+        $crate::__private::callsite_enabled(&CATEGORY, $category)
+    }};
+}
+
+/// Write an instant event with the installed tracer.
+/// Does nothing if no tracer is installed or the category is disabled,
+/// in which case the arguments aren't evaluated.
+///
+/// ```rust,no_run
+/// # let (bytes, peer) = (1500u64, "10.0.0.1");
+/// ftfrs::ftf_instant!("net", "packet", "bytes" => bytes, "peer" => peer);
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! ftf_instant {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        if $crate::is_installed() {
+            let category = $category;
+            if $crate::__ftf_enabled!(category) {
+                $crate::__private::with_thread_writer(|w| {
+                    let _ = w
+                        .event(category, $name)
+                        $(.arg_value($key, $value))*
+                        .write();
+                });
+            }
+        }
+    };
+}
+
+/// Write a counter event with the installed tracer.
+/// Does nothing if no tracer is installed or the category is disabled,
+/// in which case the arguments aren't evaluated.
+///
+/// ```rust,no_run
+/// # let depth = 3u64;
+/// ftfrs::ftf_counter!("queue", "depth", 1, "items" => depth);
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! ftf_counter {
+    ($category:expr, $name:expr, $counter_id:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        if $crate::is_installed() {
+            let category = $category;
+            if $crate::__ftf_enabled!(category) {
+                $crate::__private::with_thread_writer(|w| {
+                    let _ = w
+                        .event(category, $name)
+                        .counter($counter_id)
+                        $(.arg_value($key, $value))*
+                        .write();
+                });
+            }
+        }
+    };
+}
+
+/// Start a span with the installed tracer, which is written as a duration
+/// complete event when the returned `Option<SpanGuard>` is dropped.
+/// Returns None if no tracer is installed or the category is disabled,
+/// in which case the arguments aren't evaluated.
+///
+/// ```rust,no_run
+/// let _span = ftfrs::ftf_span!("db", "query", "table" => "users");
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! ftf_span {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        if $crate::is_installed() {
+            let category = $category;
+            if $crate::__ftf_enabled!(category) {
+                $crate::__private::with_thread_writer(|w| {
+                    w.event(category, $name)
+                        $(.arg_value($key, $value))*
+                        .span()
+                })
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    };
+}
+
+/// Evaluate an expression inside a span of the installed tracer,
+/// and return its value.
+///
+/// ```rust,no_run
+/// # fn load() -> u32 { 0 }
+/// let value = ftfrs::ftf_duration!("io", "load", "attempt" => 1; load());
+/// ```
+#[cfg(feature = "macros")]
+#[macro_export]
+macro_rules! ftf_duration {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* ; $body:expr) => {{
+        let _span = $crate::ftf_span!($category, $name $(, $key => $value)*);
+        $body
+    }};
+}
+
+// With the `macros` feature off, the macros type-check their arguments
+// but never evaluate them, and compile to nothing.
+
+/// Disabled: the `macros` feature is off
+#[cfg(not(feature = "macros"))]
+#[macro_export]
+macro_rules! ftf_instant {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        if false {
+            let _ = ($category, $name $(, $key, $value)*);
+        }
+    };
+}
+
+/// Disabled: the `macros` feature is off
+#[cfg(not(feature = "macros"))]
+#[macro_export]
+macro_rules! ftf_counter {
+    ($category:expr, $name:expr, $counter_id:expr $(, $key:expr => $value:expr)* $(,)?) => {
+        if false {
+            let _ = ($category, $name, $counter_id $(, $key, $value)*);
+        }
+    };
+}
+
+/// Disabled: the `macros` feature is off
+#[cfg(not(feature = "macros"))]
+#[macro_export]
+macro_rules! ftf_span {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* $(,)?) => {{
+        if false {
+            let _ = ($category, $name $(, $key, $value)*);
+        }
+        None::<$crate::SpanGuard>
+    }};
+}
+
+/// Disabled: the `macros` feature is off
+#[cfg(not(feature = "macros"))]
+#[macro_export]
+macro_rules! ftf_duration {
+    ($category:expr, $name:expr $(, $key:expr => $value:expr)* ; $body:expr) => {{
+        let _span = $crate::ftf_span!($category, $name $(, $key => $value)*);
+        $body
+    }};
+}
+
+#[cfg(all(test, feature = "macros"))]
+mod tests {
+    use super::*;
+    use crate::{Archive, Event, EventRecord, Interning, Record};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // The tracer is process-global, so everything is checked in one test
+    #[test]
+    fn test_global_tracer() -> Result<()> {
+        let evaluated = std::cell::Cell::new(0);
+        let count = || {
+            evaluated.set(evaluated.get() + 1);
+            evaluated.get() as u64
+        };
+
+        // nothing is evaluated before a tracer is installed
+        crate::ftf_instant!("net", "ignored", "n" => count());
+        assert!(crate::ftf_span!("net", "ignored").is_none());
+        assert_eq!(evaluated.get(), 0);
+
+        let sink = SharedSink::default();
+        install(TraceWriter::new(sink.clone(), Interning::Shared)?)?;
+        assert!(install(TraceWriter::new(SharedSink::default(), Interning::Shared)?).is_err());
+
+        // a disabled category writes nothing, not even its strings
+        categories().set_patterns(["-off"]);
+        flush_tracer()?;
+        let written = sink.0.lock().unwrap().len();
+        crate::ftf_instant!("off", "x", "secret_key" => "secret_value");
+        crate::ftf_counter!("off", "rows", 7, "secret_rows" => 1u64);
+        flush_tracer()?;
+        assert_eq!(sink.0.lock().unwrap().len(), written);
+
+        categories().set_patterns(["net", "db:*", "-db:debug"]);
+        // the category expression is evaluated once
+        let categories_evaluated = std::cell::Cell::new(0);
+        let category = |name| {
+            categories_evaluated.set(categories_evaluated.get() + 1);
+            name
+        };
+        crate::ftf_instant!(category("net"), "packet", "n" => count(), "proto" => "udp");
+        crate::ftf_instant!("gfx", "frame", "n" => count());
+        crate::ftf_counter!("db:pool", "rows", 7, "rows" => 12u64);
+        crate::ftf_counter!("db:debug", "rows", 7, "rows" => count());
+        let value = crate::ftf_duration!("db:sql", "query", "table" => "users"; 5);
+        assert_eq!(value, 5);
+        categories().enable_all();
+        assert!(crate::ftf_span!(category("gfx"), "frame").is_some());
+        assert_eq!(categories_evaluated.get(), 2);
+        assert_eq!(evaluated.get(), 1);
+        flush_tracer()?;
+
+        let names: Vec<(String, usize)> = Archive::read(&sink.0.lock().unwrap()[..])?
+            .records
+            .iter()
+            .filter_map(|r| match r {
+                Record::Event(EventRecord::Instant(e)) => {
+                    Some(("instant".into(), e.arguments().len()))
+                }
+                Record::Event(EventRecord::Counter(e)) => {
+                    Some(("counter".into(), e.arguments().len()))
+                }
+                Record::Event(EventRecord::DurationComplete(e)) => {
+                    Some(("complete".into(), e.arguments().len()))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("instant".to_string(), 2),
+                ("counter".to_string(), 1),
+                ("complete".to_string(), 1),
+                ("complete".to_string(), 0),
+            ]
+        );
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "macros")))]
+mod disabled_tests {
+    #[test]
+    fn test_macros_compile_to_nothing() {
+        let evaluated = std::cell::Cell::new(false);
+        let value = || {
+            evaluated.set(true);
+            1u64
+        };
+
+        crate::ftf_instant!("net", "packet", "n" => value());
+        crate::ftf_counter!("db", "rows", 7, "rows" => value());
+        assert!(crate::ftf_span!("db", "query", "n" => value()).is_none());
+        assert_eq!(crate::ftf_duration!("io", "load", "n" => value(); 5), 5);
+        assert!(!evaluated.get());
+    }
+}
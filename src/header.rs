@@ -1,4 +1,4 @@
-use crate::{event::EventType, extract_bits, mask_length, Result};
+use crate::{event::EventType, extract_bits, mask_length, FtfError, Result};
 use thiserror::Error;
 
 /// Type of a record
@@ -60,6 +60,20 @@ pub(super) struct CustomField {
     pub value: u64,
 }
 
+/// Largest record or argument, in 8-byte words
+pub(crate) const MAX_WORDS: usize = 0xFFF;
+
+/// Fail on a record or argument too large for its 12-bit size field
+pub(crate) fn check_size(words: usize) -> Result<()> {
+    if words > MAX_WORDS {
+        return Err(FtfError::ValueOutOfRange(format!(
+            "{} words, at most {}",
+            words, MAX_WORDS
+        )));
+    }
+    Ok(())
+}
+
 /// Header for a record
 pub struct RecordHeader {
     pub(crate) value: u64,
@@ -67,13 +81,17 @@ pub struct RecordHeader {
 
 impl RecordHeader {
     pub(super) fn build_event_header(
-        record_size: u8,
+        record_size: usize,
         event_type: EventType,
         nargs: usize,
         tid: u8,
         cid: u16,
         nid: u16,
     ) -> Result<Self> {
+        if nargs > 15 {
+            return Err(FtfError::TooManyArguments(nargs));
+        }
+        check_size(record_size)?;
         let mut res: u64 = 0;
 
         res |= RecordType::Event as u64;
@@ -89,9 +107,10 @@ impl RecordHeader {
 
     pub(super) fn build(
         record_type: RecordType,
-        record_size: u8,
+        record_size: usize,
         fields: &[CustomField],
     ) -> Result<Self> {
+        check_size(record_size)?;
         let record_type = record_type as u8;
         let mut res: u64 = 0;
 
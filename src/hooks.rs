@@ -0,0 +1,166 @@
+use std::io::Write;
+use std::panic::{self, PanicHookInfo};
+use std::sync::{Arc, Mutex, Once, PoisonError, TryLockError, Weak};
+use std::time::Duration;
+
+/// A writer flushed by the panic hook and at-exit handler
+pub(crate) trait HookTarget: Send + Sync {
+    /// Buffer an instant event describing a panic
+    fn record_panic(&self, message: &str, location: &str);
+
+    /// Write everything buffered to the sink, without blocking for long
+    fn flush_now(&self);
+}
+
+/// Whether the at-exit handler is available on this platform
+pub(crate) const FLUSHES_AT_EXIT: bool = cfg!(unix);
+
+/// Longest panic message recorded, in bytes
+const MAX_MESSAGE_LEN: usize = 1024;
+
+static TARGETS: Mutex<Vec<Weak<dyn HookTarget>>> = Mutex::new(Vec::new());
+static INSTALL: Once = Once::new();
+
+/// Flush `target` on panic and exit, until it is dropped.
+/// Installs the hooks the first time it is called.
+pub(crate) fn register(target: Weak<dyn HookTarget>) {
+    INSTALL.call_once(install);
+    let mut targets = TARGETS.lock().unwrap_or_else(PoisonError::into_inner);
+    targets.retain(|t| t.strong_count() > 0);
+    targets.push(target);
+}
+
+/// Flush `writer` if the process panics or exits, for sinks such as
+/// `BufWriter` or `RotatingFileSink` which buffer records themselves.
+/// Installs the panic hook and at-exit handler the first time it is called,
+/// and the writer is unregistered once every `Arc` to it is dropped.
+///
+/// Writers are flushed in the reverse order they were registered, so a
+/// sink registered before the `TraceWriter` writing to it is flushed after
+/// it. Returns whether `writer` is also flushed at exit, which is only
+/// available on unix.
+pub fn register_writer<W: Write + Send + 'static>(writer: &Arc<Mutex<W>>) -> bool {
+    let target: Arc<dyn HookTarget> = writer.clone();
+    register(Arc::downgrade(&target));
+    FLUSHES_AT_EXIT
+}
+
+impl<W: Write + Send> HookTarget for Mutex<W> {
+    fn record_panic(&self, _message: &str, _location: &str) {}
+
+    fn flush_now(&self) {
+        // the panic may have happened with the lock held, so only wait a
+        // little while in case another thread is writing
+        for _ in 0..100 {
+            match self.try_lock() {
+                Ok(mut writer) => {
+                    let _ = writer.flush();
+                    return;
+                }
+                Err(TryLockError::Poisoned(e)) => {
+                    let _ = e.into_inner().flush();
+                    return;
+                }
+                Err(TryLockError::WouldBlock) => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+}
+
+/// The longest prefix of `s` of at most `max` bytes, on a char boundary
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+fn install() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        on_panic(info);
+        previous(info);
+    }));
+    #[cfg(unix)]
+    // SAFETY: `on_exit` is a plain function which doesn't unwind
+    unsafe {
+        libc::atexit(on_exit);
+    }
+}
+
+fn on_panic(info: &PanicHookInfo<'_>) {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>");
+    // the message is an inline string argument, whose length is limited
+    let message = truncate(message, MAX_MESSAGE_LEN);
+    let location = info
+        .location()
+        .map(|l| format!("{}:{}:{}", l.file(), l.line(), l.column()))
+        .unwrap_or_default();
+    for target in targets().iter().rev() {
+        target.record_panic(message, &location);
+        target.flush_now();
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_exit() {
+    // unwinding out of an at-exit handler would abort
+    let _ = panic::catch_unwind(|| {
+        for target in targets().iter().rev() {
+            target.flush_now();
+        }
+    });
+}
+
+fn targets() -> Vec<Arc<dyn HookTarget>> {
+    // the panic may have happened while registering, with the lock held
+    let targets = match TARGETS.try_lock() {
+        Ok(targets) => targets,
+        Err(TryLockError::Poisoned(e)) => e.into_inner(),
+        Err(TryLockError::WouldBlock) => return Vec::new(),
+    };
+    targets.iter().filter_map(Weak::upgrade).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("boom", 1024), "boom");
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(truncate("héllo", 3), "hé");
+        assert_eq!(truncate(&"x".repeat(2000), MAX_MESSAGE_LEN).len(), 1024);
+    }
+
+    #[test]
+    fn test_register_writer() {
+        #[derive(Default)]
+        struct Flushes(usize);
+
+        impl Write for Flushes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0 += 1;
+                Ok(())
+            }
+        }
+
+        let writer = Arc::new(Mutex::new(Flushes::default()));
+        assert_eq!(register_writer(&writer), cfg!(unix));
+        let result = std::thread::spawn(|| panic!("register_writer boom")).join();
+        assert!(result.is_err());
+        // other tests may panic too
+        assert!(writer.lock().unwrap().0 >= 1);
+    }
+}
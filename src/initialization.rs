@@ -1,17 +1,24 @@
 #![warn(missing_docs)]
-use crate::{header::RecordHeader, wordutils::read_u64_word, Result};
+use crate::{header::RecordHeader, preserved::PreservedHeader, wordutils::read_u64_word, Result};
 use std::io::{Read, Write};
 
 /// Initialization record
 /// Specifies number of ticks per second in this trace
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InitializationRecord {
     ticks_per_second: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: PreservedHeader,
 }
 
 impl InitializationRecord {
-    pub(super) fn new(ticks_per_second: u64) -> Self {
-        Self { ticks_per_second }
+    /// Initialization record for a trace with `ticks_per_second` ticks in a second
+    pub fn new(ticks_per_second: u64) -> Self {
+        Self {
+            ticks_per_second,
+            preserved: PreservedHeader::default(),
+        }
     }
 
     /// Number of ticks in a second for this trace
@@ -19,14 +26,21 @@ impl InitializationRecord {
         self.ticks_per_second
     }
 
+    /// Set the number of ticks in a second
+    pub fn set_ticks_per_second(&mut self, ticks_per_second: u64) {
+        self.ticks_per_second = ticks_per_second;
+        self.preserved.clear();
+    }
+
     pub(super) fn parse<U: Read>(reader: &mut U, _header: RecordHeader) -> Result<Self> {
-        Ok(InitializationRecord {
-            ticks_per_second: read_u64_word(reader)?,
-        })
+        Ok(InitializationRecord::new(read_u64_word(reader)?))
     }
 
     pub(super) fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let header = RecordHeader::build(crate::header::RecordType::Initialization, 2, &[])?;
+        let header = match self.preserved.get() {
+            Some(value) => RecordHeader::new(value),
+            None => RecordHeader::build(crate::header::RecordType::Initialization, 2, &[])?,
+        };
         writer.write_all(&header.value.to_le_bytes())?;
         writer.write_all(&self.ticks_per_second.to_le_bytes())?;
         Ok(())
@@ -70,6 +84,7 @@ mod tests {
         // Create an initialization record
         let record = InitializationRecord {
             ticks_per_second: 10_000_000, // 10 MHz
+            preserved: PreservedHeader::default(),
         };
 
         // Write it to a buffer
@@ -108,6 +123,7 @@ mod tests {
         // Create an initialization record
         let original_record = InitializationRecord {
             ticks_per_second: 12_345_678,
+            preserved: PreservedHeader::default(),
         };
 
         // Write it to a buffer
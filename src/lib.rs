@@ -4,7 +4,7 @@
 //!
 //! ```rust
 //! use ftfrs::{
-//!     Archive, Record, StringRef, ThreadRef, Result
+//!     Archive, Argument, Record, StringRef, ThreadRef, Result
 //! };
 //! use std::fs::File;
 //! use std::io::BufWriter;
@@ -61,30 +61,81 @@
 
 mod argument;
 mod bitutils;
+mod bounded;
+mod category;
+mod clock;
 mod event;
+mod flight_recorder;
+mod framing;
+mod global;
 mod header;
+mod hooks;
 mod initialization;
 mod metadata;
+mod overflow;
+mod preserved;
+mod rotating;
+mod sampling;
+#[cfg(feature = "serde")]
+mod ser;
+mod session;
+mod span;
 mod string_rec;
+mod tables;
+mod text;
 mod thread_rec;
+mod time;
+mod trace_str;
 mod wordutils;
+mod writer;
 
-pub use crate::argument::Argument;
+pub use crate::argument::{ArgValue, Argument};
 
 use argument::ArgumentTypeParseError;
 use bitutils::{extract_bits, mask_length};
+pub use bounded::{BoundedWriter, OverflowPolicy};
+pub use category::{Category, CategoryRegistry};
+#[cfg(target_arch = "x86_64")]
+pub use clock::TscClock;
+pub use clock::{ManualClock, MonotonicClock, MonotonicSource, TraceClock};
 use event::EventTypeParseError;
 pub use event::{
-    Counter, DurationBegin, DurationComplete, DurationEnd, Event, EventRecord, Instant,
+    Counter, DurationBegin, DurationComplete, DurationEnd, Event, EventRecord, EventType, Instant,
 };
+pub use flight_recorder::{FlightRecorder, SnapshotTrigger};
+#[cfg(feature = "derive")]
+pub use ftfrs_derive::FtfArgs;
+pub use global::{categories, flush_tracer, install, install_session, is_installed};
 use header::RecordTypeParseError;
 pub use header::{RecordHeader, RecordType};
+pub use hooks::register_writer;
 pub use initialization::InitializationRecord;
 use metadata::MetadataTypeParseError;
-pub use metadata::{MetadataRecord, ProviderEvent, ProviderInfo, ProviderSection, TraceInfo};
+pub use metadata::{
+    MetadataRecord, ProviderEvent, ProviderEventKind, ProviderInfo, ProviderSection, TraceInfo,
+    TraceInfoKind,
+};
+pub use overflow::{Overflow, OverflowSummary};
+use preserved::{Preserved, PreservedHeader, Recording};
+pub use rotating::{RotatingFileSink, Rotation};
+pub use sampling::{Sampler, SamplingPolicy, DROP_REPORT_COUNTER_IDS};
+#[cfg(feature = "serde")]
+pub use ser::to_arguments;
+pub use session::{TraceSession, TraceSessionBuilder};
+pub use span::{EventBuilder, FtfArgs, IntoArgument, SpanGuard};
 pub use string_rec::StringRecord;
+pub use tables::{StringTable, TableTracker, ThreadTable};
 pub use thread_rec::ThreadRecord;
+pub use time::{Timebase, TimebaseTracker};
+pub use trace_str::TraceStr;
 use wordutils::read_u64_word;
+pub use writer::{Checkpoint, Interning, ThreadWriter, TraceWriter};
+
+// Used by the ftf_* macros
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::global::{callsite_enabled, with_thread_writer};
+}
 
 use std::io::{ErrorKind, Read, Write};
 use std::string::FromUtf8Error;
@@ -132,6 +183,28 @@ pub enum FtfError {
     /// Parse error
     #[error("Parse error: {0}")]
     ParseError(String),
+
+    /// Record written where the trace format doesn't allow it
+    /// (eg: a second magic number in the middle of a trace)
+    #[error("Invalid record order: {0}")]
+    InvalidRecordOrder(String),
+
+    /// Invalid configuration of a writer or session
+    #[error("Invalid configuration: {0}")]
+    InvalidConfiguration(String),
+
+    /// Value which can't be written as an argument
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// More arguments than an event can carry
+    #[error("Too many arguments: {0}, an event carries at most 15")]
+    TooManyArguments(usize),
+
+    /// Field of a record too wide to be written
+    /// (eg: a string index above 0x7FFF)
+    #[error("Value out of range: {0}")]
+    ValueOutOfRange(String),
 }
 
 /// Specialized Result type for FtfError
@@ -142,6 +215,8 @@ pub type Result<T> = std::result::Result<T, FtfError>;
 /// to a previously interned string (using a string record)
 /// as the String record's index
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "StringRefRepr"))]
 pub enum StringRef {
     /// Inline string
     Inline(String),
@@ -152,6 +227,14 @@ pub enum StringRef {
 }
 
 impl StringRef {
+    /// The string, looking up a ref in `strings`
+    pub fn resolve<'a>(&'a self, strings: &'a StringTable) -> Option<&'a str> {
+        match self {
+            StringRef::Inline(s) => Some(s),
+            StringRef::Ref(index) => strings.get(*index),
+        }
+    }
+
     fn to_field(&self) -> u16 {
         match self {
             StringRef::Ref(r) => *r & 0x7FFF,
@@ -163,10 +246,36 @@ impl StringRef {
         (field & 0x8000) == 0
     }
 
-    fn encoding_num_words(&self) -> u8 {
+    fn encoding_num_words(&self) -> usize {
         match self {
             StringRef::Ref(_) => 0,
-            StringRef::Inline(s) => s.len().div_ceil(8) as u8,
+            StringRef::Inline(s) => s.len().div_ceil(8),
+        }
+    }
+}
+
+// Deserialized before its lengths are checked
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+enum StringRefRepr {
+    Inline(String),
+    Ref(u16),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<StringRefRepr> for StringRef {
+    type Error = String;
+
+    fn try_from(repr: StringRefRepr) -> std::result::Result<Self, String> {
+        match repr {
+            StringRefRepr::Inline(s) if s.len() > 0x7FFF => {
+                Err(format!("inline string of {} bytes, at most 32767", s.len()))
+            }
+            StringRefRepr::Ref(index) if index > 0x7FFF => {
+                Err(format!("string ref {}, at most 32767", index))
+            }
+            StringRefRepr::Inline(s) => Ok(StringRef::Inline(s)),
+            StringRefRepr::Ref(index) => Ok(StringRef::Ref(index)),
         }
     }
 }
@@ -175,7 +284,8 @@ impl StringRef {
 /// which is written with the record, or a reference
 /// to a previously interned thread (using a Thread record)
 /// as the Thread record's index
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ThreadRef {
     /// Inline thread with specified process ID and thread ID
     Inline {
@@ -189,10 +299,10 @@ pub enum ThreadRef {
 }
 
 impl ThreadRef {
-    fn to_field(&self) -> u8 {
+    fn to_field(self) -> u8 {
         match self {
             Self::Inline { .. } => 0,
-            Self::Ref(r) => *r,
+            Self::Ref(r) => r,
         }
     }
 }
@@ -200,6 +310,7 @@ impl ThreadRef {
 /// Represents a single Fuchsia Trace Format record
 /// https://fuchsia.dev/fuchsia-src/reference/tracing/trace-format#record_types
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Record {
     /// specifies providers, and start of trace
     Metadata(MetadataRecord),
@@ -238,6 +349,23 @@ pub enum Record {
 
 /// A sequence of records
 /// Must begin with a Magic record
+///
+/// Archives (and single records) also print as and parse from a text form,
+/// one record per line, which converts back to the same bytes:
+///
+/// ```rust
+/// use ftfrs::Archive;
+///
+/// let archive: Archive = r#"
+///     magic
+///     string index=1 value="db"
+///     event instant ts=100 thread=inline:1/2 cat=ref:1 name="started" args{int32 status_code=200}
+/// "#.parse()?;
+/// assert_eq!(archive.records.len(), 3);
+/// assert!(archive.to_string().starts_with("magic\nstring index=1"));
+/// # Ok::<(), ftfrs::FtfError>(())
+/// ```
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Archive {
     /// the records in the archive
     pub records: Vec<Record>,
@@ -246,10 +374,22 @@ pub struct Archive {
 impl Archive {
     /// Read a trace from a file, or other readable object.
     /// Reads the object till EOF.
-    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+    pub fn read<R: Read>(reader: R) -> Result<Self> {
+        Self::read_with(reader, Record::read)
+    }
+
+    /// Read a trace like `read`, keeping the bits the records don't model
+    /// (reserved header bits and the padding after strings). Writing the
+    /// archive gives back the same bytes for every record which wasn't
+    /// modified, so a read and write pass can be checked to be lossless.
+    pub fn read_preserving<R: Read>(reader: R) -> Result<Self> {
+        Self::read_with(reader, Record::read_preserving)
+    }
+
+    fn read_with<R: Read>(mut reader: R, read: fn(&mut R) -> Result<Record>) -> Result<Self> {
         let mut res = Vec::new();
         loop {
-            match Record::read(&mut reader) {
+            match read(&mut reader) {
                 Ok(r) => res.push(r),
                 Err(FtfError::Io(e)) => match e.kind() {
                     ErrorKind::UnexpectedEof => break,
@@ -269,6 +409,64 @@ impl Archive {
         }
         Ok(())
     }
+
+    /// The traces of an archive made of concatenated traces, each starting
+    /// at a magic number record. Records before the first magic number, if
+    /// any, are a session of their own.
+    pub fn sessions(&self) -> Vec<ArchiveSession<'_>> {
+        let mut starts: Vec<usize> = self
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| *i == 0 || matches!(r, Record::Metadata(MetadataRecord::MagicNumber)))
+            .map(|(i, _)| i)
+            .collect();
+        starts.push(self.records.len());
+        starts
+            .windows(2)
+            .map(|w| ArchiveSession {
+                records: &self.records[w[0]..w[1]],
+                start: w[0],
+            })
+            .collect()
+    }
+}
+
+/// One trace of an archive holding several concatenated traces: the
+/// records from a magic number record up to the next one.
+/// Providers, strings and threads of one session don't carry over to the next.
+#[derive(Debug, Clone, Copy)]
+pub struct ArchiveSession<'a> {
+    records: &'a [Record],
+    start: usize,
+}
+
+impl<'a> ArchiveSession<'a> {
+    /// Records of the trace, starting with its magic number record
+    pub fn records(&self) -> &'a [Record] {
+        self.records
+    }
+
+    /// Position of the trace's first record in the archive
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// String and thread tables of the trace, as of its last record
+    pub fn tables(&self) -> TableTracker {
+        let mut tracker = TableTracker::new();
+        for record in self.records {
+            tracker.observe(record);
+        }
+        tracker
+    }
+
+    /// The trace as an archive of its own
+    pub fn to_archive(&self) -> Archive {
+        Archive {
+            records: self.records.to_vec(),
+        }
+    }
 }
 
 impl Record {
@@ -314,7 +512,7 @@ impl Record {
     /// This metadata provides running notification of events that the provider wants to report.
     /// This record may appear anywhere in the output, and does not delimit what came before it or what comes after it.
     /// * provider_id: ID of the assosciated provider
-    /// * event_id: ID for the type of event. The following events are defined:
+    /// * event_id: ID for the type of event, see `ProviderEventKind`. The following events are defined:
     /// - 0: a buffer filled up, records were likely dropped
     pub fn create_provider_event(provider_id: u32, event_id: u8) -> Self {
         Self::Metadata(MetadataRecord::ProviderEvent(ProviderEvent::new(
@@ -339,6 +537,8 @@ impl Record {
 
     /// Create TraceInfo record
     /// Provides information about the trace as a whole
+    /// * trace_info_type: type of the trace info (4 bits)
+    /// * data: type-specific data, in the order it's written (see `TraceInfo::new`)
     pub fn create_trace_info(trace_info_type: u8, data: [u8; 5]) -> Self {
         Self::Metadata(MetadataRecord::TraceInfo(TraceInfo::new(
             trace_info_type,
@@ -472,8 +672,86 @@ impl Record {
         }
     }
 
+    /// Read a single record like `read`, keeping its bytes so that writing
+    /// it back unmodified reproduces them, reserved bits and padding included.
+    /// Any setter called on the record drops the bytes.
+    pub fn read_preserving<U: Read>(reader: &mut U) -> Result<Record> {
+        let mut recording = Recording::new(reader);
+        let mut record = Self::read(&mut recording)?;
+        let bytes = recording.into_bytes();
+        let header = || u64::from_le_bytes(bytes[..8].try_into().expect("header was read"));
+        match &mut record {
+            Self::Metadata(MetadataRecord::ProviderInfo(r)) => r.preserved = Preserved::new(bytes),
+            Self::Metadata(MetadataRecord::ProviderSection(r)) => {
+                r.preserved = PreservedHeader::new(header())
+            }
+            Self::Metadata(MetadataRecord::ProviderEvent(r)) => {
+                r.preserved = PreservedHeader::new(header())
+            }
+            Self::Initialization(r) => r.preserved = PreservedHeader::new(header()),
+            Self::Thread(r) => r.preserved = PreservedHeader::new(header()),
+            Self::String(r) => r.preserved = Preserved::new(bytes),
+            Self::Event(r) => {
+                if let Some(preserved) = r.preserved_mut() {
+                    *preserved = Preserved::new(bytes);
+                }
+            }
+            // every bit of these is modelled
+            _ => {}
+        }
+        Ok(record)
+    }
+
+    /// Bytes a variable-size record was read from, while it is unmodified
+    fn preserved(&self) -> Option<&[u8]> {
+        match self {
+            Self::Metadata(MetadataRecord::ProviderInfo(r)) => r.preserved.bytes(),
+            Self::String(r) => r.preserved.bytes(),
+            Self::Event(r) => r.preserved().and_then(Preserved::bytes),
+            _ => None,
+        }
+    }
+
+    /// The event, for the event types which are implemented
+    pub(crate) fn event(&self) -> Option<&dyn Event> {
+        match self {
+            Self::Event(event) => event.as_event(),
+            _ => None,
+        }
+    }
+
+    /// Calls `strings` for every string table index and `threads` for
+    /// every thread table index this record refers to
+    pub(crate) fn for_each_ref(&self, mut strings: impl FnMut(u16), mut threads: impl FnMut(u8)) {
+        let Some(event) = self.event() else {
+            return;
+        };
+
+        let mut string = |s: &StringRef| {
+            if let StringRef::Ref(i) = s {
+                strings(*i);
+            }
+        };
+        string(event.category());
+        string(event.name());
+        for arg in event.arguments() {
+            string(arg.name());
+            if let Argument::Str(_, value) = arg {
+                string(value);
+            }
+        }
+
+        if let ThreadRef::Ref(i) = event.thread() {
+            threads(*i);
+        }
+    }
+
     /// Write a single record to a file, or other writeable object
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if let Some(bytes) = self.preserved() {
+            writer.write_all(bytes)?;
+            return Ok(());
+        }
         match self {
             Self::Metadata(r) => Ok(r.write(writer)?),
             Self::Initialization(r) => Ok(r.write(writer)?),
@@ -734,6 +1012,52 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_concatenated_sessions() -> Result<()> {
+        let trace = |name: &str, koid: u64| Archive {
+            records: vec![
+                Record::create_magic_number(),
+                Record::create_provider_info(1, "provider"),
+                Record::create_string(1, name),
+                Record::create_thread(1, koid, koid + 1),
+                Record::create_instant_event(
+                    koid,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    StringRef::Ref(1),
+                    Vec::new(),
+                ),
+            ],
+        };
+        let mut buffer = Vec::new();
+        trace("first", 10).write(&mut buffer)?;
+        trace("second", 20).write(&mut buffer)?;
+        let archive = Archive::read(Cursor::new(&buffer))?;
+
+        let sessions = archive.sessions();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[1].start(), 5);
+        assert_eq!(sessions[1].records(), &archive.records[5..]);
+        assert_eq!(sessions[0].to_archive().records, trace("first", 10).records);
+        for (session, (name, koid)) in sessions.iter().zip([("first", 10), ("second", 20)]) {
+            let tables = session.tables();
+            assert_eq!(tables.current_provider(), Some(1));
+            assert_eq!(tables.strings().get(1), Some(name));
+            assert_eq!(tables.strings().len(), 1);
+            assert_eq!(tables.threads().get(1), Some((koid, koid + 1)));
+        }
+
+        // records before the first magic number are a session of their own
+        let headless = Archive {
+            records: archive.records[1..].to_vec(),
+        };
+        let sessions = headless.sessions();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].records().len(), 4);
+        assert!(Archive { records: vec![] }.sessions().is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_archive_appending() -> Result<()> {
         // Create two separate archives
@@ -790,4 +1114,121 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_read_preserving() -> Result<()> {
+        let encode = |record: Record| -> Result<Vec<u8>> {
+            let mut buffer = Vec::new();
+            record.write(&mut buffer)?;
+            Ok(buffer)
+        };
+        let canonical = [
+            encode(Record::create_string(1, "db"))?,
+            encode(Record::create_thread(1, 10, 11))?,
+            encode(Record::create_initialization(1000))?,
+            encode(Record::create_provider_section(1))?,
+            encode(Record::create_provider_event(1, 0))?,
+            encode(Record::create_provider_info(1, "p"))?,
+            encode(Record::create_instant_event(
+                100,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Inline("n".to_string()),
+                vec![Argument::Null(StringRef::Ref(2))],
+            ))?,
+        ];
+
+        // set reserved bits and fill the padding after strings
+        let mut odd = canonical.clone();
+        odd[0][3] |= 0x80;
+        odd[0][10..16].fill(0xAA);
+        odd[1][4] = 0x5A;
+        odd[2][2] = 0x11;
+        odd[3][7] = 0xF0;
+        odd[4][7] = 0xA0;
+        odd[5][9..16].fill(b'x');
+        odd[6][17..24].fill(0xBB);
+        odd[6][28] = 0x77;
+        let input = odd.concat();
+
+        let mut preserved = Archive::read_preserving(Cursor::new(&input))?;
+        let mut buffer = Vec::new();
+        preserved.write(&mut buffer)?;
+        assert_eq!(buffer, input);
+
+        // a plain read writes the bits it models only
+        let plain = Archive::read(Cursor::new(&input))?;
+        assert_eq!(plain.records, preserved.records);
+        let mut buffer = Vec::new();
+        plain.write(&mut buffer)?;
+        assert_eq!(buffer, canonical.concat());
+
+        // modified records are encoded again
+        if let Record::String(r) = &mut preserved.records[0] {
+            r.set_index(2);
+        }
+        if let Record::Thread(r) = &mut preserved.records[1] {
+            r.set_thread_koid(12);
+        }
+        if let Record::Event(EventRecord::Instant(e)) = &mut preserved.records[6] {
+            e.arguments_mut();
+        }
+        let mut buffer = Vec::new();
+        preserved.write(&mut buffer)?;
+        let mut expected = odd;
+        expected[0] = encode(Record::create_string(2, "db"))?;
+        expected[1] = encode(Record::create_thread(1, 10, 12))?;
+        expected[6] = canonical[6].clone();
+        assert_eq!(buffer, expected.concat());
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() -> Result<()> {
+        let archive = Archive {
+            records: vec![
+                Record::create_magic_number(),
+                Record::create_provider_info(1, "provider"),
+                Record::create_string(1, "db"),
+                Record::create_thread(1, 10, 11),
+                Record::create_counter_event(
+                    5,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    StringRef::Inline("rows".to_string()),
+                    vec![("count", 3u32).into(), ("ok", true).into()],
+                    7,
+                ),
+                Record::Metadata(MetadataRecord::TraceInfo(TraceInfo::new(
+                    1,
+                    &[1, 2, 3, 4, 5],
+                ))),
+            ],
+        };
+        let json = serde_json::to_string(&archive).unwrap();
+        let parsed: Archive = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.records, archive.records);
+
+        // event fields are inlined into each event type
+        let counter = serde_json::to_value(&archive.records[4]).unwrap();
+        assert_eq!(counter["Event"]["Counter"]["timestamp"], 5);
+        assert_eq!(counter["Event"]["Counter"]["counter_id"], 7);
+
+        // values which don't fit their fields are rejected
+        for invalid in [
+            r#"{"Metadata":{"TraceInfo":{"trace_info_type":1,"data":1099511627776}}}"#,
+            r#"{"Metadata":{"TraceInfo":{"trace_info_type":16,"data":0}}}"#,
+            r#"{"Metadata":{"ProviderEvent":{"provider_id":1,"event_id":16}}}"#,
+            r#"{"String":{"index":32768,"value":"db"}}"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Record>(invalid).is_err(),
+                "{}",
+                invalid
+            );
+        }
+        assert!(serde_json::from_str::<StringRef>(r#"{"Ref":32768}"#).is_err());
+        Ok(())
+    }
 }
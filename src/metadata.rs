@@ -4,33 +4,139 @@ use thiserror::Error;
 use crate::{
     extract_bits,
     header::CustomField,
+    preserved::{Preserved, PreservedHeader},
     wordutils::{self, pad_and_write_string},
-    RecordHeader, Result,
+    FtfError, RecordHeader, Result,
 };
 
 /// Represents information about the entire trace
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "TraceInfoRepr"))]
 pub struct TraceInfo {
     trace_info_type: u8,
     // only 40 bits, but no point in encoding as [u8; 5]
     data: u64,
 }
 
+/// Decoded contents of a trace info record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TraceInfoKind {
+    /// Magic number record (type 0), demarcating the start of a trace.
+    /// `magic` is `TraceInfoKind::MAGIC_NUMBER` in a valid trace.
+    MagicNumber {
+        /// the 32-bit magic value
+        magic: u32,
+    },
+    /// Trace info type without a decoding yet, or a magic number record
+    /// with its reserved bits set
+    Unknown {
+        /// type of the trace info (4 bits)
+        trace_info_type: u8,
+        /// type-specific data (40 bits)
+        data: u64,
+    },
+}
+
+impl TraceInfoKind {
+    /// Magic value of the magic number record
+    pub const MAGIC_NUMBER: u32 = 0x16547846;
+
+    const MAGIC_NUMBER_TYPE: u8 = 0;
+}
+
 impl TraceInfo {
-    pub(crate) fn new(trace_info_type: u8, data: &[u8; 5]) -> Self {
+    /// Trace info record of type `trace_info_type` (4 bits) carrying
+    /// 40 bits of type-specific data, in the order they're written in the
+    /// trace: `data[0]` holds the low byte of `data()`.
+    /// A wider type panics in debug builds, and fails to be written.
+    pub fn new(trace_info_type: u8, data: &[u8; 5]) -> Self {
+        debug_assert!(
+            trace_info_type <= 0xF,
+            "trace info type {}",
+            trace_info_type
+        );
         let mut tmp = [0_u8; 8];
-        tmp[3] = data[0];
-        tmp[4] = data[1];
-        tmp[5] = data[2];
-        tmp[6] = data[3];
-        tmp[7] = data[4];
+        tmp[..5].copy_from_slice(data);
 
         Self {
             trace_info_type,
-            data: u64::from_be_bytes(tmp),
+            data: u64::from_le_bytes(tmp),
+        }
+    }
+
+    /// Trace info record holding `kind`
+    pub fn from_kind(kind: TraceInfoKind) -> Self {
+        match kind {
+            TraceInfoKind::MagicNumber { magic } => Self {
+                trace_info_type: TraceInfoKind::MAGIC_NUMBER_TYPE,
+                data: magic as u64,
+            },
+            TraceInfoKind::Unknown {
+                trace_info_type,
+                data,
+            } => Self {
+                trace_info_type,
+                data,
+            },
         }
     }
+
+    /// Decoded type and data of the trace info
+    pub fn kind(&self) -> TraceInfoKind {
+        match self.trace_info_type {
+            TraceInfoKind::MAGIC_NUMBER_TYPE if self.data >> 32 == 0 => {
+                TraceInfoKind::MagicNumber {
+                    magic: self.data as u32,
+                }
+            }
+            trace_info_type => TraceInfoKind::Unknown {
+                trace_info_type,
+                data: self.data,
+            },
+        }
+    }
+
+    /// Type of the trace info
+    pub fn trace_info_type(&self) -> u8 {
+        self.trace_info_type
+    }
+
+    /// Type-specific data, in the low 40 bits
+    pub fn data(&self) -> u64 {
+        self.data
+    }
+
+    /// Type-specific data as the 5 bytes written in the trace, the
+    /// inverse of `new`
+    pub fn data_bytes(&self) -> [u8; 5] {
+        let bytes = self.data.to_le_bytes();
+        [bytes[0], bytes[1], bytes[2], bytes[3], bytes[4]]
+    }
+
+    /// Set the type of the trace info, at most 15
+    pub fn set_trace_info_type(&mut self, trace_info_type: u8) {
+        debug_assert!(
+            trace_info_type <= 0xF,
+            "trace info type {}",
+            trace_info_type
+        );
+        self.trace_info_type = trace_info_type;
+    }
+
+    /// Set the type-specific data, at most 40 bits wide
+    pub fn set_data(&mut self, data: u64) {
+        debug_assert!(data >> 40 == 0, "trace info data {:#x}", data);
+        self.data = data;
+    }
     fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.trace_info_type > 0xF || self.data >> 40 != 0 {
+            return Err(FtfError::ValueOutOfRange(format!(
+                "trace info type {} or data {:#x}, at most 4 and 40 bits wide",
+                self.trace_info_type, self.data
+            )));
+        }
         let header = RecordHeader::build(
             crate::header::RecordType::Metadata,
             1,
@@ -61,16 +167,22 @@ impl TraceInfo {
 /// A single Provider can have many threads and processes.
 /// Each Provider has its own String and Thread tables.
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ProviderInfoRepr"))]
 pub struct ProviderInfo {
     provider_id: u32,
     provider_name: String,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: Preserved,
 }
 
 impl ProviderInfo {
-    pub(crate) fn new(provider_id: u32, provider_name: String) -> Self {
+    /// Provider info registering `provider_name` (at most 255 bytes) as `provider_id`
+    pub fn new(provider_id: u32, provider_name: String) -> Self {
         Self {
             provider_id,
             provider_name,
+            preserved: Preserved::default(),
         }
     }
 
@@ -84,13 +196,25 @@ impl ProviderInfo {
         &self.provider_name
     }
 
+    /// Set the ID of the provider
+    pub fn set_provider_id(&mut self, provider_id: u32) {
+        self.provider_id = provider_id;
+        self.preserved.clear();
+    }
+
+    /// Set the name of the provider
+    pub fn set_provider_name(&mut self, provider_name: String) {
+        self.provider_name = provider_name;
+        self.preserved.clear();
+    }
+
     fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
         let str_bytes = self.provider_name.as_bytes();
         let size = 1 + str_bytes.len().div_ceil(8);
 
         let header = RecordHeader::build(
             crate::header::RecordType::Metadata,
-            size as u8,
+            size,
             &[
                 CustomField {
                     width: 4,
@@ -118,12 +242,19 @@ impl ProviderInfo {
 /// All records processed after a ProviderSection are
 /// considered to be produced by the assosciated Provider.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ProviderSection {
     provider_id: u32,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: PreservedHeader,
 }
 impl ProviderSection {
-    pub(crate) fn new(provider_id: u32) -> Self {
-        Self { provider_id }
+    /// Section of the records of `provider_id`
+    pub fn new(provider_id: u32) -> Self {
+        Self {
+            provider_id,
+            preserved: PreservedHeader::default(),
+        }
     }
 
     /// ID of the provider
@@ -131,40 +262,85 @@ impl ProviderSection {
         self.provider_id
     }
 
+    /// Set the ID of the provider
+    pub fn set_provider_id(&mut self, provider_id: u32) {
+        self.provider_id = provider_id;
+        self.preserved.clear();
+    }
+
     fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let header = RecordHeader::build(
-            crate::header::RecordType::Metadata,
-            1,
-            &[
-                CustomField {
-                    width: 4,
-                    value: MetadataType::ProviderSection as u64,
-                },
-                CustomField {
-                    width: 32,
-                    value: self.provider_id as u64,
-                },
-            ],
-        )?;
+        let header = match self.preserved.get() {
+            Some(value) => RecordHeader::new(value),
+            None => RecordHeader::build(
+                crate::header::RecordType::Metadata,
+                1,
+                &[
+                    CustomField {
+                        width: 4,
+                        value: MetadataType::ProviderSection as u64,
+                    },
+                    CustomField {
+                        width: 32,
+                        value: self.provider_id as u64,
+                    },
+                ],
+            )?,
+        };
 
         writer.write_all(&header.value.to_le_bytes())?;
         Ok(())
     }
 }
 
+/// Kind of a ProviderEvent, decoded from its event ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ProviderEventKind {
+    /// A buffer filled up, so records were likely dropped (event ID 0)
+    BufferOverflow,
+    /// Event ID without a meaning yet
+    Unknown(u8),
+}
+
+impl From<u8> for ProviderEventKind {
+    fn from(event_id: u8) -> Self {
+        match event_id {
+            0 => Self::BufferOverflow,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+impl From<ProviderEventKind> for u8 {
+    fn from(kind: ProviderEventKind) -> Self {
+        match kind {
+            ProviderEventKind::BufferOverflow => 0,
+            ProviderEventKind::Unknown(id) => id,
+        }
+    }
+}
+
 /// Event assosciated with a particular Provider
 /// eg: missing records due to a buffer overflow
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "ProviderEventRepr"))]
 pub struct ProviderEvent {
     provider_id: u32,
     event_id: u8,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: PreservedHeader,
 }
 
 impl ProviderEvent {
-    pub(crate) fn new(provider_id: u32, event_id: u8) -> Self {
+    /// Event `event_id` (at most 15) of `provider_id`.
+    /// A wider ID panics in debug builds, and fails to be written.
+    pub fn new(provider_id: u32, event_id: u8) -> Self {
+        debug_assert!(event_id <= 0xF, "provider event id {}", event_id);
         Self {
             provider_id,
             event_id,
+            preserved: PreservedHeader::default(),
         }
     }
 
@@ -179,25 +355,52 @@ impl ProviderEvent {
         self.event_id
     }
 
+    /// Kind of the event
+    pub fn kind(&self) -> ProviderEventKind {
+        self.event_id.into()
+    }
+
+    /// Set the ID of the provider
+    pub fn set_provider_id(&mut self, provider_id: u32) {
+        self.provider_id = provider_id;
+        self.preserved.clear();
+    }
+
+    /// Set the ID of the event, at most 15
+    pub fn set_event_id(&mut self, event_id: u8) {
+        debug_assert!(event_id <= 0xF, "provider event id {}", event_id);
+        self.event_id = event_id;
+        self.preserved.clear();
+    }
+
     fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let header = RecordHeader::build(
-            crate::header::RecordType::Metadata,
-            1,
-            &[
-                CustomField {
-                    width: 4,
-                    value: MetadataType::ProviderEvent as u64,
-                },
-                CustomField {
-                    width: 32,
-                    value: self.provider_id as u64,
-                },
-                CustomField {
-                    width: 4,
-                    value: self.event_id as u64,
-                },
-            ],
-        )?;
+        if self.event_id > 0xF {
+            return Err(FtfError::ValueOutOfRange(format!(
+                "provider event id {}, at most 15",
+                self.event_id
+            )));
+        }
+        let header = match self.preserved.get() {
+            Some(value) => RecordHeader::new(value),
+            None => RecordHeader::build(
+                crate::header::RecordType::Metadata,
+                1,
+                &[
+                    CustomField {
+                        width: 4,
+                        value: MetadataType::ProviderEvent as u64,
+                    },
+                    CustomField {
+                        width: 32,
+                        value: self.provider_id as u64,
+                    },
+                    CustomField {
+                        width: 4,
+                        value: self.event_id as u64,
+                    },
+                ],
+            )?,
+        };
 
         writer.write_all(&header.value.to_le_bytes())?;
         Ok(())
@@ -235,6 +438,7 @@ impl TryFrom<u8> for MetadataType {
 /// trace providers, and mark the beginning of a
 /// trace
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MetadataRecord {
     /// Register a Provider
     ProviderInfo(ProviderInfo),
@@ -275,22 +479,22 @@ impl MetadataRecord {
 
                 let provider_name = wordutils::read_aligned_str(reader, namelen)?;
 
-                Ok(Self::ProviderInfo(ProviderInfo {
+                Ok(Self::ProviderInfo(ProviderInfo::new(
                     provider_id,
                     provider_name,
-                }))
+                )))
             }
             MetadataType::ProviderSection => {
                 let provider_id = Self::provider_id(&header);
-                Ok(Self::ProviderSection(ProviderSection { provider_id }))
+                Ok(Self::ProviderSection(ProviderSection::new(provider_id)))
             }
             MetadataType::ProviderEvent => {
                 let provider_id = Self::provider_id(&header);
                 let event_id = extract_bits!(header.value, 52, 55) as u8;
-                Ok(Self::ProviderEvent(ProviderEvent {
+                Ok(Self::ProviderEvent(ProviderEvent::new(
                     provider_id,
                     event_id,
-                }))
+                )))
             }
             MetadataType::TraceInfo => {
                 let trace_info_type = extract_bits!(header.value, 20, 23) as u8;
@@ -316,6 +520,79 @@ impl MetadataRecord {
     }
 }
 
+// Metadata records as deserialized, before the widths of their fields are checked
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct TraceInfoRepr {
+    trace_info_type: u8,
+    data: u64,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ProviderInfoRepr {
+    provider_id: u32,
+    provider_name: String,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct ProviderEventRepr {
+    provider_id: u32,
+    event_id: u8,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<TraceInfoRepr> for TraceInfo {
+    type Error = String;
+
+    fn try_from(repr: TraceInfoRepr) -> std::result::Result<Self, String> {
+        if repr.trace_info_type > 0xF {
+            return Err(format!(
+                "trace info type {}, at most 15",
+                repr.trace_info_type
+            ));
+        }
+        if repr.data >> 40 != 0 {
+            return Err(format!(
+                "trace info data {:#x} is wider than 40 bits",
+                repr.data
+            ));
+        }
+        Ok(Self {
+            trace_info_type: repr.trace_info_type,
+            data: repr.data,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ProviderInfoRepr> for ProviderInfo {
+    type Error = String;
+
+    fn try_from(repr: ProviderInfoRepr) -> std::result::Result<Self, String> {
+        if repr.provider_name.len() > 0xFF {
+            return Err(format!(
+                "provider name of {} bytes, at most 255",
+                repr.provider_name.len()
+            ));
+        }
+        Ok(Self::new(repr.provider_id, repr.provider_name))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<ProviderEventRepr> for ProviderEvent {
+    type Error = String;
+
+    fn try_from(repr: ProviderEventRepr) -> std::result::Result<Self, String> {
+        if repr.event_id > 0xF {
+            return Err(format!("provider event {}, at most 15", repr.event_id));
+        }
+        Ok(Self::new(repr.provider_id, repr.event_id))
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::identity_op)]
 mod tests {
@@ -494,6 +771,7 @@ mod tests {
         let provider_info = ProviderInfo {
             provider_id: 42,
             provider_name: "test_lib".to_string(),
+            preserved: Preserved::default(),
         };
 
         let record = MetadataRecord::ProviderInfo(provider_info.clone());
@@ -526,7 +804,7 @@ mod tests {
     #[test]
     fn test_provider_section_write() -> Result<()> {
         // Create a provider section record
-        let provider_section = ProviderSection { provider_id: 123 };
+        let provider_section = ProviderSection::new(123);
 
         let record = MetadataRecord::ProviderSection(provider_section);
 
@@ -559,6 +837,7 @@ mod tests {
         let provider_event = ProviderEvent {
             provider_id: 456,
             event_id: 7,
+            preserved: PreservedHeader::default(),
         };
 
         let record = MetadataRecord::ProviderEvent(provider_event);
@@ -627,6 +906,65 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_trace_info_kind() -> Result<()> {
+        // bytes passed to new are the bytes written, and parsed back
+        let info = TraceInfo::new(5, &[1, 2, 3, 4, 5]);
+        assert_eq!(info.data(), 0x05_0403_0201);
+        let mut buffer = Vec::new();
+        MetadataRecord::TraceInfo(info).write(&mut buffer)?;
+        assert_eq!(buffer[3..8], [1, 2, 3, 4, 5]);
+        match Record::read(&mut Cursor::new(&buffer))? {
+            Record::Metadata(MetadataRecord::TraceInfo(parsed)) => {
+                assert_eq!(parsed, info);
+                assert_eq!(parsed.data_bytes(), [1, 2, 3, 4, 5]);
+                assert_eq!(
+                    parsed.kind(),
+                    TraceInfoKind::Unknown {
+                        trace_info_type: 5,
+                        data: 0x05_0403_0201
+                    }
+                );
+            }
+            other => panic!("Expected TraceInfo record, got {:?}", other),
+        }
+
+        // the magic number record is a type 0 trace info
+        let magic = TraceInfo::from_kind(TraceInfoKind::MagicNumber {
+            magic: TraceInfoKind::MAGIC_NUMBER,
+        });
+        let mut buffer = Vec::new();
+        MetadataRecord::TraceInfo(magic).write(&mut buffer)?;
+        assert_eq!(
+            buffer,
+            MetadataRecord::MAGIC_NUMBER_RECORD.to_le_bytes().to_vec()
+        );
+        assert_eq!(
+            Record::read(&mut Cursor::new(&buffer))?,
+            Record::create_magic_number()
+        );
+
+        // a wrong magic value is still a magic number record
+        let header = MetadataRecord::MAGIC_NUMBER_RECORD ^ (1 << 24);
+        match MetadataRecord::parse(&mut Cursor::new(Vec::new()), RecordHeader::new(header))? {
+            MetadataRecord::TraceInfo(info) => assert_eq!(
+                info.kind(),
+                TraceInfoKind::MagicNumber {
+                    magic: TraceInfoKind::MAGIC_NUMBER ^ 1
+                }
+            ),
+            other => panic!("Expected TraceInfo record, got {:?}", other),
+        }
+        // but not with its reserved bits set
+        let info = TraceInfo::from_kind(TraceInfoKind::Unknown {
+            trace_info_type: 0,
+            data: 1 << 32,
+        });
+        assert!(matches!(info.kind(), TraceInfoKind::Unknown { .. }));
+        assert_eq!(TraceInfo::from_kind(info.kind()), info);
+        Ok(())
+    }
+
     #[test]
     fn test_metadata_record_roundtrip() -> Result<()> {
         // Test all types of metadata records for roundtrip
@@ -646,6 +984,7 @@ mod tests {
         let provider_info = ProviderInfo {
             provider_id: 42,
             provider_name: "test_lib".to_string(),
+            preserved: Preserved::default(),
         };
 
         buffer.clear();
@@ -662,7 +1001,7 @@ mod tests {
         }
 
         // 3. Provider Section
-        let provider_section = ProviderSection { provider_id: 123 };
+        let provider_section = ProviderSection::new(123);
 
         buffer.clear();
         MetadataRecord::ProviderSection(provider_section).write(&mut buffer)?;
@@ -680,6 +1019,7 @@ mod tests {
         let provider_event = ProviderEvent {
             provider_id: 456,
             event_id: 7,
+            preserved: PreservedHeader::default(),
         };
 
         buffer.clear();
@@ -716,4 +1056,57 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_edit_in_place() -> Result<()> {
+        let mut info = ProviderInfo::new(1, "first".to_string());
+        info.set_provider_id(9);
+        info.set_provider_name("renamed".to_string());
+        let mut event = ProviderEvent::new(1, 0);
+        event.set_provider_id(9);
+        event.set_event_id(1);
+        let mut section = ProviderSection::new(1);
+        section.set_provider_id(9);
+
+        for record in [
+            MetadataRecord::ProviderInfo(info),
+            MetadataRecord::ProviderEvent(event),
+            MetadataRecord::ProviderSection(section),
+        ] {
+            let mut buffer = Vec::new();
+            record.write(&mut buffer)?;
+            let parsed = Record::read(&mut Cursor::new(buffer))?;
+            assert_eq!(parsed, Record::Metadata(record));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_out_of_range_is_not_written() {
+        let event = ProviderEvent {
+            provider_id: 1,
+            event_id: 0x10,
+            preserved: PreservedHeader::default(),
+        };
+        let info = TraceInfo {
+            trace_info_type: 1,
+            data: 1 << 40,
+        };
+        for record in [
+            MetadataRecord::ProviderEvent(event),
+            MetadataRecord::TraceInfo(info),
+        ] {
+            assert!(matches!(
+                record.write(&mut Vec::new()),
+                Err(FtfError::ValueOutOfRange(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "trace info data")]
+    fn test_wide_data_asserts() {
+        TraceInfo::new(1, &[0; 5]).set_data(1 << 40);
+    }
 }
@@ -0,0 +1,285 @@
+use std::collections::BTreeMap;
+
+use crate::{Archive, MetadataRecord, ProviderEventKind, Record};
+
+/// A buffer overflow marker in a trace: records of the provider around it
+/// are likely missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overflow {
+    /// Position of the ProviderEvent record in the trace's records
+    pub record_index: usize,
+    /// Timestamp of the provider's last event before the marker, None if
+    /// it had none. Events are missing from here on.
+    pub last_before: Option<u64>,
+    /// Timestamp of the provider's first event after the marker, None if
+    /// it had none. Events are missing up to here.
+    pub first_after: Option<u64>,
+}
+
+/// Buffer overflows reported in a trace, by provider, to tell whether
+/// results computed from the trace may be missing data.
+/// Records before any ProviderInfo or ProviderSection belong to no provider
+/// (`None`), as do markers for provider 0 among them, which is how a
+/// `BoundedWriter` reports their drops. Timestamps around an overflow are
+/// only taken from its own trace when traces are concatenated.
+///
+/// ```rust
+/// use ftfrs::{Archive, OverflowSummary, Record, StringRef, ThreadRef};
+///
+/// let event = |ts| Record::create_instant_event(
+///     ts, ThreadRef::Ref(1), StringRef::Ref(1), StringRef::Ref(2), Vec::new(),
+/// );
+/// let archive = Archive {
+///     records: vec![
+///         Record::create_provider_info(1, "gfx"),
+///         event(10),
+///         Record::create_provider_event(1, 0),
+///         event(50),
+///     ],
+/// };
+/// let summary = archive.overflow_summary();
+/// assert!(!summary.is_complete());
+/// let overflow = summary.overflows(Some(1))[0];
+/// assert_eq!((overflow.last_before, overflow.first_after), (Some(10), Some(50)));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OverflowSummary {
+    providers: BTreeMap<Option<u32>, Vec<Overflow>>,
+}
+
+impl OverflowSummary {
+    /// Find the overflow markers in `records`
+    pub fn from_records(records: &[Record]) -> Self {
+        let mut providers: BTreeMap<Option<u32>, Vec<Overflow>> = BTreeMap::new();
+        // last event timestamp of each provider, and its overflows waiting
+        // for the next one
+        let mut last_ts: BTreeMap<Option<u32>, u64> = BTreeMap::new();
+        let mut pending: BTreeMap<Option<u32>, usize> = BTreeMap::new();
+        let mut current = None;
+
+        for (record_index, record) in records.iter().enumerate() {
+            match record {
+                // a new trace: its events say nothing about the previous
+                // trace's overflows
+                Record::Metadata(MetadataRecord::MagicNumber) => {
+                    current = None;
+                    last_ts.clear();
+                    pending.clear();
+                }
+                Record::Metadata(MetadataRecord::ProviderInfo(info)) => {
+                    current = Some(info.provider_id())
+                }
+                Record::Metadata(MetadataRecord::ProviderSection(section)) => {
+                    current = Some(section.provider_id())
+                }
+                Record::Metadata(MetadataRecord::ProviderEvent(event))
+                    if event.kind() == ProviderEventKind::BufferOverflow =>
+                {
+                    let provider = match (current, event.provider_id()) {
+                        (None, 0) => None,
+                        (_, id) => Some(id),
+                    };
+                    let overflows = providers.entry(provider).or_default();
+                    overflows.push(Overflow {
+                        record_index,
+                        last_before: last_ts.get(&provider).copied(),
+                        first_after: None,
+                    });
+                    pending.entry(provider).or_insert(overflows.len() - 1);
+                }
+                _ => {
+                    let Some(event) = record.event() else {
+                        continue;
+                    };
+                    let ts = event.timestamp();
+                    last_ts.insert(current, ts);
+                    if let Some(first) = pending.remove(&current) {
+                        for overflow in &mut providers.get_mut(&current).unwrap()[first..] {
+                            overflow.first_after = Some(ts);
+                        }
+                    }
+                }
+            }
+        }
+
+        Self { providers }
+    }
+
+    /// Whether no provider reported an overflow
+    pub fn is_complete(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Overflows of `provider_id`, or of records outside of any provider
+    /// for `None`, in trace order
+    pub fn overflows(&self, provider_id: Option<u32>) -> &[Overflow] {
+        self.providers
+            .get(&provider_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Providers which reported overflows, with their overflows, starting
+    /// with records outside of any provider
+    pub fn iter(&self) -> impl Iterator<Item = (Option<u32>, &[Overflow])> {
+        self.providers.iter().map(|(id, o)| (*id, o.as_slice()))
+    }
+}
+
+impl Archive {
+    /// Buffer overflows reported in the archive, see `OverflowSummary`
+    pub fn overflow_summary(&self) -> OverflowSummary {
+        OverflowSummary::from_records(&self.records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{StringRef, ThreadRef};
+
+    fn event(ts: u64) -> Record {
+        Record::create_instant_event(
+            ts,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(2),
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn test_no_overflow() {
+        let records = vec![
+            Record::create_magic_number(),
+            Record::create_provider_info(1, "one"),
+            event(1),
+            // not an overflow
+            Record::create_provider_event(1, 3),
+            event(2),
+        ];
+        let summary = OverflowSummary::from_records(&records);
+        assert!(summary.is_complete());
+        assert!(summary.overflows(Some(1)).is_empty());
+        assert_eq!(summary.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_overflows_by_provider() {
+        let records = vec![
+            Record::create_magic_number(),
+            // before any provider, reported as provider 0
+            Record::create_provider_event(0, 0),
+            event(5),
+            Record::create_provider_info(1, "one"),
+            Record::create_provider_info(2, "two"),
+            Record::create_provider_section(1),
+            event(10),
+            Record::create_provider_event(1, 0),
+            Record::create_provider_section(2),
+            event(20),
+            // provider 1 reporting from provider 2's section
+            Record::create_provider_event(1, 0),
+            Record::create_provider_event(2, 0),
+            event(30),
+            Record::create_provider_section(1),
+            event(40),
+            event(50),
+            // never followed by an event of provider 2
+            Record::create_provider_event(2, 0),
+        ];
+        let summary = OverflowSummary::from_records(&records);
+        assert!(!summary.is_complete());
+        assert_eq!(
+            summary.overflows(None),
+            [Overflow {
+                record_index: 1,
+                last_before: None,
+                first_after: Some(5),
+            }]
+        );
+        assert_eq!(
+            summary.overflows(Some(1)),
+            [
+                Overflow {
+                    record_index: 7,
+                    last_before: Some(10),
+                    first_after: Some(40),
+                },
+                Overflow {
+                    record_index: 10,
+                    last_before: Some(10),
+                    first_after: Some(40),
+                },
+            ]
+        );
+        assert_eq!(
+            summary.overflows(Some(2)),
+            [
+                Overflow {
+                    record_index: 11,
+                    last_before: Some(20),
+                    first_after: Some(30),
+                },
+                Overflow {
+                    record_index: 16,
+                    last_before: Some(30),
+                    first_after: None,
+                },
+            ]
+        );
+        assert_eq!(
+            summary
+                .iter()
+                .map(|(id, o)| (id, o.len()))
+                .collect::<Vec<_>>(),
+            [(None, 1), (Some(1), 2), (Some(2), 2)]
+        );
+        assert!(summary.overflows(Some(3)).is_empty());
+    }
+
+    #[test]
+    fn test_concatenated_traces() {
+        let records = vec![
+            Record::create_magic_number(),
+            Record::create_provider_info(1, "one"),
+            event(10),
+            // never followed by an event in this trace
+            Record::create_provider_event(1, 0),
+            Record::create_magic_number(),
+            Record::create_provider_info(1, "one"),
+            // before any event of this trace
+            Record::create_provider_event(1, 0),
+            event(20),
+        ];
+        let summary = OverflowSummary::from_records(&records);
+        assert_eq!(
+            summary.overflows(Some(1)),
+            [
+                Overflow {
+                    record_index: 3,
+                    last_before: Some(10),
+                    first_after: None,
+                },
+                Overflow {
+                    record_index: 6,
+                    last_before: None,
+                    first_after: Some(20),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_provider_zero() {
+        let records = vec![
+            Record::create_magic_number(),
+            Record::create_provider_info(0, "zero"),
+            event(10),
+            Record::create_provider_event(0, 0),
+        ];
+        let summary = OverflowSummary::from_records(&records);
+        assert_eq!(summary.overflows(Some(0)).len(), 1);
+        assert!(summary.overflows(None).is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::io::Read;
+
+/// Bytes of a record as it was read, including reserved bits and padding,
+/// written back instead of encoding the record again. Setters clear it, so
+/// only unmodified records are written as read. Ignored when comparing
+/// records.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Preserved(Option<Box<[u8]>>);
+
+impl Preserved {
+    pub(crate) fn new(bytes: Vec<u8>) -> Self {
+        Self(Some(bytes.into_boxed_slice()))
+    }
+
+    pub(crate) fn bytes(&self) -> Option<&[u8]> {
+        self.0.as_deref()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl PartialEq for Preserved {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Preserved {}
+
+/// Header word of a fixed-size record as it was read, for records whose
+/// only unmodelled bits are reserved header bits. Cleared by setters like
+/// `Preserved`, and ignored when comparing records.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct PreservedHeader(Option<u64>);
+
+impl PreservedHeader {
+    pub(crate) fn new(header: u64) -> Self {
+        Self(Some(header))
+    }
+
+    pub(crate) fn get(&self) -> Option<u64> {
+        self.0
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl PartialEq for PreservedHeader {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for PreservedHeader {}
+
+/// Reader keeping a copy of everything read through it
+pub(crate) struct Recording<R> {
+    inner: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read> Recording<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            bytes: Vec::new(),
+        }
+    }
+
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
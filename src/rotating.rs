@@ -0,0 +1,513 @@
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::framing::{RecordSplitter, TraceState};
+use crate::{FtfError, MetadataRecord, Record, Result};
+
+/// When a `RotatingFileSink` rolls over to a new file,
+/// and how many files it keeps around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    /// Roll over once the current file holds at least this many bytes
+    pub max_bytes: Option<u64>,
+    /// Roll over once the current file has been open for this long
+    pub max_age: Option<Duration>,
+    /// Number of files to keep, including the one being written.
+    /// Older files are deleted.
+    pub keep: usize,
+}
+
+struct Segment {
+    file: BufWriter<File>,
+    bytes: u64,
+    opened: Instant,
+    // provider the segment's records are currently attributed to
+    provider: Option<u32>,
+    // whether anything past the preamble has been written
+    has_records: bool,
+    // definitions written to this segment, per provider
+    strings: HashSet<(Option<u32>, u16)>,
+    threads: HashSet<(Option<u32>, u8)>,
+}
+
+impl Segment {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        self.file.write_all(bytes)?;
+        self.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Record) -> Result<()> {
+        let mut buffer = Vec::new();
+        record.write(&mut buffer)?;
+        self.write_all(&buffer)
+    }
+}
+
+/// A file sink for long-running producers which rolls over to a new file
+/// once the current one is large or old enough, and keeps only the most
+/// recent files.
+///
+/// Every file is a standalone trace: it starts with the magic number,
+/// initialization record and provider info records, and String and Thread
+/// records are re-emitted in each file, but only once one of the file's
+/// records refers to them.
+///
+/// Files are named after `path` with a sequence number before the extension,
+/// e.g. `trace.ftf` is written as `trace.0.ftf`, `trace.1.ftf`, ...
+/// Files left by a previous run count towards `keep`, and numbering
+/// continues after the highest of them, so they are never overwritten.
+pub struct RotatingFileSink {
+    path: PathBuf,
+    rotation: Rotation,
+    splitter: RecordSplitter,
+    state: TraceState,
+    segment: Option<Segment>,
+    sequence: u64,
+    files: VecDeque<PathBuf>,
+}
+
+fn to_io(e: FtfError) -> io::Error {
+    match e {
+        FtfError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl RotatingFileSink {
+    /// Create a sink writing files named after `path`.
+    /// The first file is created immediately, after the files of previous
+    /// runs, the oldest of which are deleted to keep `rotation.keep` files.
+    pub fn new<P: AsRef<Path>>(path: P, rotation: Rotation) -> Result<Self> {
+        let mut sink = Self {
+            path: path.as_ref().to_path_buf(),
+            rotation,
+            splitter: RecordSplitter::default(),
+            state: TraceState::default(),
+            segment: None,
+            sequence: 0,
+            files: VecDeque::new(),
+        };
+        let existing = sink.existing_segments()?;
+        if let Some(last) = existing.last() {
+            sink.sequence = last + 1;
+        }
+        sink.files = existing.into_iter().map(|n| sink.segment_path(n)).collect();
+        sink.roll_over()?;
+        Ok(sink)
+    }
+
+    /// Sequence numbers of the segment files already next to `path`, in order
+    fn existing_segments(&self) -> Result<Vec<u64>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // let creating the first file report it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let stem = self.path.file_stem().map(|s| s.to_string_lossy());
+        let ext = self.path.extension().map(|s| s.to_string_lossy());
+        let mut sequences = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            let Some(rest) = stem
+                .as_deref()
+                .and_then(|stem| name.strip_prefix(stem))
+                .and_then(|rest| rest.strip_prefix('.'))
+            else {
+                continue;
+            };
+            let number = match &ext {
+                Some(ext) => rest
+                    .strip_suffix(ext.as_ref())
+                    .and_then(|n| n.strip_suffix('.')),
+                None => Some(rest),
+            };
+            if let Some(n) = number
+                .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .and_then(|n| n.parse().ok())
+            {
+                sequences.push(n);
+            }
+        }
+        sequences.sort_unstable();
+        Ok(sequences)
+    }
+
+    /// Path of the file currently being written
+    pub fn current_path(&self) -> Option<&Path> {
+        self.files.back().map(PathBuf::as_path)
+    }
+
+    /// Paths of the files kept so far, oldest first
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().map(PathBuf::as_path)
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, sequence, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, sequence),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn roll_over(&mut self) -> Result<()> {
+        if let Some(mut segment) = self.segment.take() {
+            segment.file.flush()?;
+        }
+
+        let path = self.segment_path(self.sequence);
+        self.sequence += 1;
+
+        let mut file = BufWriter::new(File::create(&path)?);
+        let mut preamble = Vec::new();
+        let provider = self.state.write_preamble(&mut preamble)?;
+        file.write_all(&preamble)?;
+
+        self.segment = Some(Segment {
+            file,
+            bytes: preamble.len() as u64,
+            opened: Instant::now(),
+            provider,
+            has_records: false,
+            strings: HashSet::new(),
+            threads: HashSet::new(),
+        });
+
+        self.files.push_back(path);
+        while self.files.len() > self.rotation.keep.max(1) {
+            if let Some(old) = self.files.pop_front() {
+                match std::fs::remove_file(&old) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn should_roll_over(&self) -> bool {
+        let Some(segment) = &self.segment else {
+            return true;
+        };
+        if !segment.has_records {
+            return false;
+        }
+
+        self.rotation.max_bytes.is_some_and(|n| segment.bytes >= n)
+            || self
+                .rotation
+                .max_age
+                .is_some_and(|age| segment.opened.elapsed() >= age)
+    }
+
+    fn record(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Some(record) = self.state.absorb(bytes) {
+            let segment = self.segment.as_mut().unwrap();
+            match record {
+                // a new trace in the stream starts a new file,
+                // unless nothing has been written to this one yet
+                Record::Metadata(MetadataRecord::MagicNumber) if segment.has_records => {
+                    self.roll_over()?;
+                }
+                // written lazily, once something refers to them
+                Record::String(s) => {
+                    segment
+                        .strings
+                        .remove(&(self.state.current_provider(), s.index()));
+                }
+                Record::Thread(t) => {
+                    segment
+                        .threads
+                        .remove(&(self.state.current_provider(), t.index()));
+                }
+                // written as is, and again at the start of every later file
+                Record::Initialization(_) | Record::Metadata(MetadataRecord::ProviderInfo(_)) => {
+                    segment.write_all(bytes)?;
+                    segment.provider = self.state.current_provider();
+                }
+                // provider sections are written lazily, once a record of the provider is
+                _ => (),
+            }
+            return Ok(());
+        }
+
+        if self.should_roll_over() {
+            self.roll_over()?;
+        }
+
+        let provider = self.state.current_provider();
+        let segment = self.segment.as_mut().unwrap();
+        if segment.provider != provider {
+            if let Some(id) = provider {
+                segment.write_record(&Record::create_provider_section(id))?;
+            }
+            segment.provider = provider;
+        }
+
+        if let (Ok(record), Some(tables)) =
+            (Record::read(&mut &bytes[..]), self.state.tables(provider))
+        {
+            let mut strings = Vec::new();
+            let mut threads = Vec::new();
+            record.for_each_ref(
+                |s| {
+                    if segment.strings.insert((provider, s)) {
+                        strings.push(s);
+                    }
+                },
+                |t| {
+                    if segment.threads.insert((provider, t)) {
+                        threads.push(t);
+                    }
+                },
+            );
+
+            for index in strings {
+                if let Some(value) = tables.strings.get(index) {
+                    segment.write_record(&Record::create_string(index, value))?;
+                }
+            }
+            for index in threads {
+                if let Some((process_koid, thread_koid)) = tables.threads.get(index) {
+                    segment.write_record(&Record::create_thread(
+                        index,
+                        process_koid,
+                        thread_koid,
+                    ))?;
+                }
+            }
+        }
+
+        segment.write_all(bytes)?;
+        segment.has_records = true;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFileSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.splitter.push(buf);
+
+        let mut records = Vec::new();
+        while let Some(record) = self.splitter.next_record() {
+            records.push(record.to_vec());
+        }
+        for record in records {
+            self.record(&record).map_err(to_io)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.segment {
+            Some(segment) => segment.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Archive, StringRef, ThreadRef};
+    use std::fs;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ftfrs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn event(ts: u64, name: u16) -> Record {
+        Record::create_instant_event(
+            ts,
+            ThreadRef::Ref(1),
+            StringRef::Ref(1),
+            StringRef::Ref(name),
+            Vec::new(),
+        )
+    }
+
+    fn read(path: &Path) -> Vec<Record> {
+        Archive::read(File::open(path).unwrap()).unwrap().records
+    }
+
+    #[test]
+    fn test_segment_names() -> Result<()> {
+        let dir = temp_dir("names");
+        let sink = RotatingFileSink::new(
+            dir.join("trace.ftf"),
+            Rotation {
+                max_bytes: None,
+                max_age: None,
+                keep: 1,
+            },
+        )?;
+        assert_eq!(sink.current_path(), Some(dir.join("trace.0.ftf").as_path()));
+        assert_eq!(sink.segment_path(12), dir.join("trace.12.ftf"),);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_segments_are_standalone() -> Result<()> {
+        let dir = temp_dir("standalone");
+        let mut sink = RotatingFileSink::new(
+            dir.join("trace.ftf"),
+            Rotation {
+                // preamble (40 bytes) + definitions (56 bytes) + 2 events
+                max_bytes: Some(128),
+                max_age: None,
+                keep: 10,
+            },
+        )?;
+
+        for record in [
+            Record::create_magic_number(),
+            Record::create_initialization(1000),
+            Record::create_provider_info(7, "prov"),
+            Record::create_string(1, "category"),
+            Record::create_string(2, "first"),
+            Record::create_string(3, "second"),
+            Record::create_thread(1, 10, 11),
+            event(1, 2),
+            event(2, 2),
+            event(3, 3),
+            event(4, 3),
+        ] {
+            record.write(&mut sink)?;
+        }
+        sink.flush()?;
+
+        let files: Vec<PathBuf> = sink.files().map(Path::to_path_buf).collect();
+        assert_eq!(files.len(), 2);
+
+        let preamble = vec![
+            Record::create_magic_number(),
+            Record::create_initialization(1000),
+            Record::create_provider_info(7, "prov"),
+        ];
+
+        let mut first = preamble.clone();
+        first.extend([
+            Record::create_string(1, "category"),
+            Record::create_string(2, "first"),
+            Record::create_thread(1, 10, 11),
+            event(1, 2),
+            event(2, 2),
+        ]);
+        assert_eq!(read(&files[0]), first);
+
+        // "first" is not used in the second file, so it isn't re-emitted
+        let mut second = preamble;
+        second.extend([
+            Record::create_string(1, "category"),
+            Record::create_string(3, "second"),
+            Record::create_thread(1, 10, 11),
+            event(3, 3),
+            event(4, 3),
+        ]);
+        assert_eq!(read(&files[1]), second);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_continues_numbering() -> Result<()> {
+        let dir = temp_dir("restart");
+        let rotation = Rotation {
+            max_bytes: Some(1),
+            max_age: None,
+            keep: 3,
+        };
+        fs::write(dir.join("trace.other.ftf"), b"not a segment")?;
+        fs::write(dir.join("trace.ftf"), b"not a segment")?;
+
+        let mut sink = RotatingFileSink::new(dir.join("trace.ftf"), rotation)?;
+        Record::create_string(1, "category").write(&mut sink)?;
+        Record::create_thread(1, 10, 11).write(&mut sink)?;
+        event(0, 1).write(&mut sink)?;
+        event(1, 1).write(&mut sink)?;
+        sink.flush()?;
+        drop(sink);
+        let first_run = read(&dir.join("trace.1.ftf"));
+
+        let sink = RotatingFileSink::new(dir.join("trace.ftf"), rotation)?;
+        let files: Vec<PathBuf> = sink.files().map(Path::to_path_buf).collect();
+        assert_eq!(
+            files,
+            vec![
+                dir.join("trace.0.ftf"),
+                dir.join("trace.1.ftf"),
+                dir.join("trace.2.ftf"),
+            ]
+        );
+        // the previous run's files are left as they were
+        assert_eq!(read(&dir.join("trace.1.ftf")), first_run);
+        drop(sink);
+
+        // and count towards the files kept
+        let sink = RotatingFileSink::new(dir.join("trace.ftf"), rotation)?;
+        assert_eq!(sink.current_path(), Some(dir.join("trace.3.ftf").as_path()));
+        assert!(!dir.join("trace.0.ftf").exists());
+        assert!(dir.join("trace.other.ftf").exists());
+        assert!(dir.join("trace.ftf").exists());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_keeps_last_files() -> Result<()> {
+        let dir = temp_dir("keep");
+        let mut sink = RotatingFileSink::new(
+            dir.join("trace"),
+            Rotation {
+                max_bytes: Some(1),
+                max_age: None,
+                keep: 2,
+            },
+        )?;
+
+        Record::create_string(1, "category").write(&mut sink)?;
+        Record::create_thread(1, 10, 11).write(&mut sink)?;
+        for ts in 0..5 {
+            event(ts, 1).write(&mut sink)?;
+        }
+        sink.flush()?;
+
+        let files: Vec<PathBuf> = sink.files().map(Path::to_path_buf).collect();
+        assert_eq!(files, vec![dir.join("trace.3"), dir.join("trace.4")]);
+        assert_eq!(fs::read_dir(&dir)?.count(), 2);
+        assert_eq!(
+            read(&files[1]),
+            vec![
+                Record::create_magic_number(),
+                Record::create_string(1, "category"),
+                Record::create_thread(1, 10, 11),
+                event(4, 1),
+            ]
+        );
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
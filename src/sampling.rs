@@ -0,0 +1,491 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::Instant;
+
+use crate::category::glob_match;
+use crate::{EventRecord, StringRef, ThreadRef};
+
+/// How many events of a category or name to keep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplingPolicy {
+    /// Keep the first of every N events
+    OneInN(u64),
+    /// Keep each event with this probability, between 0 and 1
+    Probability(f64),
+    /// Keep at most this many events per second, allowing bursts of as
+    /// many events after a quiet period (a token bucket)
+    RateLimit(u64),
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    category: String,
+    name: Option<String>,
+    policy: SamplingPolicy,
+}
+
+/// Sampling state of one (provider, category, name)
+#[derive(Debug)]
+struct KeyState {
+    counter_id: u64,
+    seen: u64,
+    tokens: f64,
+    last_refill: Instant,
+    // drops not yet reported, and where the last one happened
+    dropped: u64,
+    category: StringRef,
+    name: StringRef,
+    thread: ThreadRef,
+}
+
+impl KeyState {
+    fn new(counter_id: u64, policy: Option<SamplingPolicy>, event: &dyn crate::Event) -> Self {
+        Self {
+            counter_id,
+            seen: 0,
+            tokens: match policy {
+                Some(SamplingPolicy::RateLimit(n)) => n as f64,
+                _ => 0.0,
+            },
+            last_refill: Instant::now(),
+            dropped: 0,
+            category: event.category().clone(),
+            name: event.name().clone(),
+            thread: *event.thread(),
+        }
+    }
+
+    /// Whether `policy` keeps the next event
+    #[allow(clippy::manual_is_multiple_of)]
+    fn decide(&mut self, policy: SamplingPolicy) -> bool {
+        match policy {
+            SamplingPolicy::OneInN(n) => {
+                self.seen += 1;
+                (self.seen - 1) % n.max(1) == 0
+            }
+            SamplingPolicy::Probability(p) => random() < p,
+            SamplingPolicy::RateLimit(n) => {
+                let now = Instant::now();
+                let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+                self.tokens = (self.tokens + elapsed * n as f64).min(n as f64);
+                self.last_refill = now;
+                if self.tokens >= 1.0 {
+                    self.tokens -= 1.0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// Events dropped since the last report, for one (provider, category, name)
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DropReport {
+    pub(crate) provider: Option<u32>,
+    pub(crate) counter_id: u64,
+    pub(crate) dropped: u64,
+    pub(crate) category: StringRef,
+    pub(crate) name: StringRef,
+    pub(crate) thread: ThreadRef,
+}
+
+/// Counter ids from this one up are reserved for drop reports, so they
+/// don't collide with the writer's own counters
+pub const DROP_REPORT_COUNTER_IDS: u64 = 1 << 63;
+
+// number of locks the sampling state is split over
+const SHARDS: usize = 16;
+
+/// Sampling state by provider, category and name, nested so it can be
+/// looked up with borrowed strings
+type Keys = HashMap<Option<u32>, HashMap<String, HashMap<String, KeyState>>>;
+
+#[derive(Debug, Default)]
+struct Shard {
+    keys: Keys,
+    // whether each open DurationBegin event was kept, innermost last, per
+    // provider and thread, for its DurationEnd event to follow
+    open_durations: HashMap<(Option<u32>, ThreadRef), Vec<bool>>,
+}
+
+impl Shard {
+    fn key_state(
+        &mut self,
+        provider: Option<u32>,
+        category: &str,
+        name: &str,
+        new: impl FnOnce() -> KeyState,
+    ) -> &mut KeyState {
+        let categories = self.keys.entry(provider).or_default();
+        if !categories.contains_key(category) {
+            categories.insert(category.to_string(), HashMap::new());
+        }
+        let names = categories.get_mut(category).unwrap();
+        if !names.contains_key(name) {
+            names.insert(name.to_string(), new());
+        }
+        names.get_mut(name).unwrap()
+    }
+
+    /// Take the drops not reported yet
+    fn take_drops(&mut self, reports: &mut Vec<DropReport>) {
+        for (provider, categories) in &mut self.keys {
+            for key in categories.values_mut().flat_map(HashMap::values_mut) {
+                if key.dropped > 0 {
+                    reports.push(DropReport {
+                        provider: *provider,
+                        counter_id: key.counter_id,
+                        dropped: std::mem::take(&mut key.dropped),
+                        category: key.category.clone(),
+                        name: key.name.clone(),
+                        thread: key.thread,
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn shard_index(key: impl Hash) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
+
+/// Sampling and rate-limiting rules of a writer, matched against the
+/// category and name of every event written. The first matching rule
+/// applies, separately to each distinct category and name it matches,
+/// and events matching no rule are all kept. A DurationEnd event is kept
+/// exactly when its DurationBegin event was.
+///
+/// Dropped events are reported when the writer flushes, as a counter event
+/// with the dropped event's category and name, and a `dropped` argument
+/// with the number of events dropped since the last report. Its counter
+/// id is unique to the category and name, from `DROP_REPORT_COUNTER_IDS` up.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    rules: RwLock<Vec<Rule>>,
+    // split by the hash of what they're looked up by, so threads sampling
+    // different events rarely wait for each other
+    shards: [Mutex<Shard>; SHARDS],
+    // drops of keys forgotten when the rules changed, not reported yet
+    pending: Mutex<Vec<DropReport>>,
+    // counter ids given out so far, from DROP_REPORT_COUNTER_IDS
+    counter_ids: AtomicU64,
+    // whether there are any rules, and the number of open durations,
+    // checked without taking a lock
+    has_rules: AtomicBool,
+    open_durations: AtomicUsize,
+}
+
+/// Uniform random number in [0, 1), from a per-thread splitmix64 generator
+fn random() -> f64 {
+    thread_local! {
+        static SEED: Cell<u64> = Cell::new({
+            let local = 0u8;
+            let time = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or_default();
+            time ^ (&local as *const u8 as u64)
+        });
+    }
+    let mut z = SEED.with(|seed| {
+        let next = seed.get().wrapping_add(0x9E37_79B9_7F4A_7C15);
+        seed.set(next);
+        next
+    });
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Sampler {
+    /// Apply `policy` to events whose category matches the `category` glob,
+    /// and whose name matches the `name` glob, if any.
+    /// Rules are tried in the order they were added.
+    pub fn add_rule(&self, category: &str, name: Option<&str>, policy: SamplingPolicy) {
+        let mut rules = self.rules.write().unwrap_or_else(PoisonError::into_inner);
+        rules.push(Rule {
+            category: category.to_string(),
+            name: name.map(str::to_string),
+            policy,
+        });
+        // restart every policy from scratch
+        self.reset_keys();
+        self.has_rules.store(true, Ordering::Relaxed);
+    }
+
+    /// Remove every rule, keeping every event
+    pub fn clear(&self) {
+        let mut rules = self.rules.write().unwrap_or_else(PoisonError::into_inner);
+        rules.clear();
+        self.reset_keys();
+        self.has_rules.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether there are no rules
+    pub fn is_empty(&self) -> bool {
+        !self.has_rules.load(Ordering::Relaxed)
+    }
+
+    /// Whether events have to go through `sample`: there are rules, or
+    /// durations whose end has to follow the decision taken for their begin
+    pub(crate) fn is_active(&self) -> bool {
+        !self.is_empty() || self.open_durations.load(Ordering::Relaxed) > 0
+    }
+
+    /// Forget the sampling state of every key, keeping the drops not
+    /// reported yet for the next report
+    fn reset_keys(&self) {
+        let mut reports = Vec::new();
+        for shard in &self.shards {
+            let mut shard = lock(shard);
+            shard.take_drops(&mut reports);
+            shard.keys.clear();
+        }
+        lock(&self.pending).extend(reports);
+    }
+
+    /// Decide whether to keep `record`, whose category and name are
+    /// `category` and `name`, counting it as dropped if not
+    pub(crate) fn sample(
+        &self,
+        provider: Option<u32>,
+        category: &str,
+        name: &str,
+        record: &EventRecord,
+    ) -> bool {
+        let Some(event) = record.as_event() else {
+            return true;
+        };
+        let thread = (provider, *event.thread());
+
+        // an end goes with its begin, whatever the rules are now
+        let decided = match record {
+            EventRecord::DurationEnd(_) => self.end_duration(thread),
+            _ => None,
+        };
+        let rules = self.rules.read().unwrap_or_else(PoisonError::into_inner);
+        let policy = rules
+            .iter()
+            .find(|r| {
+                glob_match(&r.category, category)
+                    && r.name.as_ref().is_none_or(|n| glob_match(n, name))
+            })
+            .map(|r| r.policy);
+
+        let keep = match (decided, policy) {
+            (Some(true), _) | (None, None) => true,
+            (decided, policy) => {
+                let mut shard = lock(&self.shards[shard_index((provider, category, name))]);
+                let key = shard.key_state(provider, category, name, || {
+                    let id = self.counter_ids.fetch_add(1, Ordering::Relaxed);
+                    KeyState::new(DROP_REPORT_COUNTER_IDS + id, policy, event)
+                });
+                let keep = match (decided, policy) {
+                    (Some(keep), _) => keep,
+                    (None, Some(policy)) => key.decide(policy),
+                    (None, None) => true,
+                };
+                if !keep {
+                    key.dropped += 1;
+                    key.category = event.category().clone();
+                    key.name = event.name().clone();
+                    key.thread = *event.thread();
+                }
+                keep
+            }
+        };
+
+        if let EventRecord::DurationBegin(_) = record {
+            self.begin_duration(thread, keep);
+        }
+        keep
+    }
+
+    fn begin_duration(&self, thread: (Option<u32>, ThreadRef), keep: bool) {
+        let mut shard = lock(&self.shards[shard_index(thread)]);
+        shard.open_durations.entry(thread).or_default().push(keep);
+        self.open_durations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether the innermost open DurationBegin event of `thread` was kept,
+    /// None if it has none, e.g. it began before there were rules
+    fn end_duration(&self, thread: (Option<u32>, ThreadRef)) -> Option<bool> {
+        let mut shard = lock(&self.shards[shard_index(thread)]);
+        let open = shard.open_durations.get_mut(&thread)?;
+        let keep = open.pop();
+        if open.is_empty() {
+            shard.open_durations.remove(&thread);
+        }
+        if keep.is_some() {
+            self.open_durations.fetch_sub(1, Ordering::Relaxed);
+        }
+        keep
+    }
+
+    /// Take the drops not reported yet
+    pub(crate) fn take_drops(&self) -> Vec<DropReport> {
+        let mut reports = std::mem::take(&mut *lock(&self.pending));
+        for shard in &self.shards {
+            lock(shard).take_drops(&mut reports);
+        }
+        reports.sort_by_key(|r| r.counter_id);
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EventRecord, Record};
+    use std::time::Duration;
+
+    fn event(name: &str) -> EventRecord {
+        match Record::create_instant_event(
+            0,
+            ThreadRef::Ref(1),
+            StringRef::Inline("cat".to_string()),
+            StringRef::Inline(name.to_string()),
+            Vec::new(),
+        ) {
+            Record::Event(e) => e,
+            _ => unreachable!(),
+        }
+    }
+
+    fn sample(sampler: &Sampler, name: &str) -> bool {
+        sampler.sample(None, "cat", name, &event(name))
+    }
+
+    fn duration(begin: bool) -> EventRecord {
+        let record = if begin {
+            Record::create_duration_begin_event
+        } else {
+            Record::create_duration_end_event
+        };
+        match record(
+            0,
+            ThreadRef::Ref(1),
+            StringRef::Inline("cat".to_string()),
+            StringRef::Inline("span".to_string()),
+            Vec::new(),
+        ) {
+            Record::Event(e) => e,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_one_in_n_per_name() {
+        let sampler = Sampler::default();
+        sampler.add_rule("c*", Some("hot*"), SamplingPolicy::OneInN(3));
+
+        let kept: Vec<bool> = (0..6).map(|_| sample(&sampler, "hot")).collect();
+        assert_eq!(kept, vec![true, false, false, true, false, false]);
+        // counted separately for every name
+        assert!(sample(&sampler, "hotter"));
+        // no rule matches
+        assert!(sample(&sampler, "cold"));
+
+        let drops = sampler.take_drops();
+        assert_eq!(drops.len(), 1);
+        assert_eq!(drops[0].dropped, 4);
+        assert_eq!(drops[0].name, StringRef::Inline("hot".to_string()));
+        assert!(sampler.take_drops().is_empty());
+    }
+
+    #[test]
+    fn test_probability() {
+        let sampler = Sampler::default();
+        sampler.add_rule("*", None, SamplingPolicy::Probability(0.25));
+        let kept = (0..10_000).filter(|_| sample(&sampler, "e")).count();
+        assert!((2000..3000).contains(&kept), "{}", kept);
+
+        sampler.clear();
+        assert!(sampler.is_empty());
+        assert!((0..100).all(|_| sample(&sampler, "e")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let sampler = Sampler::default();
+        sampler.add_rule("cat", None, SamplingPolicy::RateLimit(5));
+        let kept = (0..20).filter(|_| sample(&sampler, "e")).count();
+        assert_eq!(kept, 5);
+
+        std::thread::sleep(Duration::from_millis(250));
+        assert!(sample(&sampler, "e"));
+        assert_eq!(sampler.take_drops()[0].dropped, 15);
+    }
+
+    #[test]
+    fn test_rule_changes_keep_drops() {
+        let sampler = Sampler::default();
+        sampler.add_rule("cat", Some("a"), SamplingPolicy::OneInN(2));
+        for _ in 0..4 {
+            sample(&sampler, "a");
+        }
+        sampler.add_rule("cat", Some("b"), SamplingPolicy::OneInN(2));
+        for _ in 0..2 {
+            sample(&sampler, "a");
+        }
+        for _ in 0..2 {
+            sample(&sampler, "b");
+        }
+        sampler.clear();
+
+        let drops: Vec<_> = sampler
+            .take_drops()
+            .into_iter()
+            .map(|r| (r.name, r.counter_id, r.dropped))
+            .collect();
+        let name = |n: &str| StringRef::Inline(n.to_string());
+        assert_eq!(
+            drops,
+            vec![
+                (name("a"), DROP_REPORT_COUNTER_IDS, 2),
+                (name("a"), DROP_REPORT_COUNTER_IDS + 1, 1),
+                (name("b"), DROP_REPORT_COUNTER_IDS + 2, 1),
+            ]
+        );
+        assert!(sampler.take_drops().is_empty());
+    }
+
+    #[test]
+    fn test_duration_end_follows_begin() {
+        let sampler = Sampler::default();
+        sampler.add_rule("cat", None, SamplingPolicy::OneInN(2));
+        let begin = |s: &Sampler| s.sample(None, "cat", "span", &duration(true));
+        let end = |s: &Sampler| s.sample(None, "cat", "span", &duration(false));
+
+        // kept, dropped and kept, nested
+        assert!(begin(&sampler));
+        assert!(!begin(&sampler));
+        assert!(begin(&sampler));
+        assert!(end(&sampler));
+        assert!(!end(&sampler));
+        // even once the rules change
+        sampler.clear();
+        assert!(sampler.is_active());
+        assert!(end(&sampler));
+        assert!(!sampler.is_active());
+        // an end whose begin wasn't sampled is kept
+        assert!(end(&sampler));
+
+        // the dropped begin and end
+        assert_eq!(sampler.take_drops()[0].dropped, 2);
+    }
+}
@@ -0,0 +1,633 @@
+use serde::ser::{self, Serialize};
+
+use crate::{Argument, FtfError, Result, StringRef};
+
+/// Most arguments an event can carry (4 bits of its header)
+const MAX_ARGUMENTS: usize = 15;
+
+/// Flatten a struct or map into event arguments, with inline names.
+/// Nested fields get dotted names (`request.id`), and sequence elements
+/// their position (`tags.0`). Integers and floats map to the matching
+/// argument type, strings and unit enum variants to `Str`, and `None`
+/// and unit values to `Null`.
+///
+/// Fails on bytes, on values which aren't a struct or map at the top level,
+/// on map keys which aren't strings or integers, and when the value would
+/// take more than the 15 arguments an event can carry.
+///
+/// ```rust
+/// # use ftfrs::{to_arguments, Argument, StringRef};
+/// #[derive(serde::Serialize)]
+/// struct Query { table: &'static str, limit: Option<u32> }
+///
+/// let args = to_arguments(&Query { table: "users", limit: None }).unwrap();
+/// assert_eq!(args[1], Argument::Null(StringRef::Inline("limit".to_string())));
+/// ```
+pub fn to_arguments<T: Serialize + ?Sized>(value: &T) -> Result<Vec<Argument>> {
+    let mut arguments = Vec::new();
+    value.serialize(Serializer {
+        arguments: &mut arguments,
+        name: String::new(),
+    })?;
+    Ok(arguments)
+}
+
+impl ser::Error for FtfError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        FtfError::InvalidArgument(msg.to_string())
+    }
+}
+
+// Serializes a value as the argument(s) called `name`; the top-level
+// value has no name, so it has to be a struct or map
+struct Serializer<'a> {
+    arguments: &'a mut Vec<Argument>,
+    name: String,
+}
+
+impl<'a> Serializer<'a> {
+    fn push(self, argument: impl FnOnce(StringRef) -> Argument) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(FtfError::InvalidArgument(
+                "only structs and maps can be serialized as arguments".to_string(),
+            ));
+        }
+        if self.arguments.len() == MAX_ARGUMENTS {
+            return Err(FtfError::TooManyArguments(MAX_ARGUMENTS + 1));
+        }
+        self.arguments.push(argument(StringRef::Inline(self.name)));
+        Ok(())
+    }
+
+    fn compound(self) -> Compound<'a> {
+        Compound {
+            arguments: self.arguments,
+            prefix: self.name,
+            index: 0,
+            key: None,
+        }
+    }
+}
+
+fn child(prefix: &str, field: &str) -> String {
+    if prefix.is_empty() {
+        field.to_string()
+    } else {
+        format!("{}.{}", prefix, field)
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = ();
+    type Error = FtfError;
+    type SerializeSeq = Compound<'a>;
+    type SerializeTuple = Compound<'a>;
+    type SerializeTupleStruct = Compound<'a>;
+    type SerializeTupleVariant = Compound<'a>;
+    type SerializeMap = Compound<'a>;
+    type SerializeStruct = Compound<'a>;
+    type SerializeStructVariant = Compound<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.push(|name| Argument::Boolean(name, v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i32(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i32(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.push(|name| Argument::Int32(name, v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.push(|name| Argument::Int64(name, v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u32(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u32(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.push(|name| Argument::UInt32(name, v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.push(|name| Argument::UInt64(name, v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.push(|name| Argument::Float(name, v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.push(|name| Argument::Str(name, StringRef::Inline(v.to_string())))
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<()> {
+        Err(FtfError::InvalidArgument(format!(
+            "{}: bytes have no argument type",
+            self.name
+        )))
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.push(Argument::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.push(Argument::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(Serializer {
+            name: child(&self.name, variant),
+            arguments: self.arguments,
+        })
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(self.compound())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.compound())
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.compound())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>> {
+        let name = child(&self.name, variant);
+        Ok(Serializer { name, ..self }.compound())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'a>> {
+        Ok(self.compound())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'a>> {
+        Ok(self.compound())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'a>> {
+        let name = child(&self.name, variant);
+        Ok(Serializer { name, ..self }.compound())
+    }
+}
+
+// Fields and elements of a struct, map or sequence called `prefix`
+struct Compound<'a> {
+    arguments: &'a mut Vec<Argument>,
+    prefix: String,
+    // position of the next sequence element
+    index: usize,
+    // map key waiting for its value
+    key: Option<String>,
+}
+
+impl Compound<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, field: &str, value: &T) -> Result<()> {
+        value.serialize(Serializer {
+            arguments: self.arguments,
+            name: child(&self.prefix, field),
+        })
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let index = self.index.to_string();
+        self.index += 1;
+        self.field(&index, value)
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take().unwrap_or_default();
+        self.field(&key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = FtfError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Turns a map key into a name segment
+struct KeySerializer;
+
+fn invalid_key() -> FtfError {
+    FtfError::InvalidArgument("map keys must be strings or integers".to_string())
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = FtfError;
+    type SerializeSeq = ser::Impossible<String, FtfError>;
+    type SerializeTuple = ser::Impossible<String, FtfError>;
+    type SerializeTupleStruct = ser::Impossible<String, FtfError>;
+    type SerializeTupleVariant = ser::Impossible<String, FtfError>;
+    type SerializeMap = ser::Impossible<String, FtfError>;
+    type SerializeStruct = ser::Impossible<String, FtfError>;
+    type SerializeStructVariant = ser::Impossible<String, FtfError>;
+
+    fn serialize_str(self, v: &str) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_char(self, v: char) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _v: bool) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_none(self) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, _value: &T) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_unit(self) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String> {
+        Err(invalid_key())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Err(invalid_key())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple> {
+        Err(invalid_key())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        Err(invalid_key())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Err(invalid_key())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Err(invalid_key())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct> {
+        Err(invalid_key())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Err(invalid_key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    fn name(s: &str) -> StringRef {
+        StringRef::Inline(s.to_string())
+    }
+
+    #[derive(Serialize)]
+    enum Mode {
+        Read,
+        Retry(u8),
+        Range { from: u64, to: u64 },
+    }
+
+    #[derive(Serialize)]
+    struct Peer {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Serialize)]
+    struct Context {
+        id: i64,
+        ratio: f32,
+        cached: bool,
+        peer: Peer,
+        deadline: Option<u32>,
+        tags: Vec<&'static str>,
+        mode: Mode,
+    }
+
+    #[test]
+    fn test_flattening() -> Result<()> {
+        let context = Context {
+            id: -4,
+            ratio: 0.5,
+            cached: false,
+            peer: Peer {
+                host: "db1".to_string(),
+                port: 5432,
+            },
+            deadline: None,
+            tags: vec!["a", "b"],
+            mode: Mode::Read,
+        };
+        assert_eq!(
+            to_arguments(&context)?,
+            vec![
+                Argument::Int64(name("id"), -4),
+                Argument::Float(name("ratio"), 0.5),
+                Argument::Boolean(name("cached"), false),
+                Argument::Str(name("peer.host"), name("db1")),
+                Argument::UInt32(name("peer.port"), 5432),
+                Argument::Null(name("deadline")),
+                Argument::Str(name("tags.0"), name("a")),
+                Argument::Str(name("tags.1"), name("b")),
+                Argument::Str(name("mode"), name("Read")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_variants_and_maps() -> Result<()> {
+        let mut map = BTreeMap::new();
+        map.insert(1u8, Mode::Retry(3));
+        map.insert(2u8, Mode::Range { from: 1, to: 9 });
+        assert_eq!(
+            to_arguments(&map)?,
+            vec![
+                Argument::UInt32(name("1.Retry"), 3),
+                Argument::UInt64(name("2.Range.from"), 1),
+                Argument::UInt64(name("2.Range.to"), 9),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        let wide: BTreeMap<String, u32> = (0..16).map(|i| (i.to_string(), i)).collect();
+        assert!(matches!(
+            to_arguments(&wide),
+            Err(FtfError::TooManyArguments(16))
+        ));
+        let full: BTreeMap<String, u32> = (0..15).map(|i| (i.to_string(), i)).collect();
+        assert_eq!(to_arguments(&full).unwrap().len(), 15);
+
+        assert!(matches!(
+            to_arguments(&5u32),
+            Err(FtfError::InvalidArgument(_))
+        ));
+        let mut option_keys = BTreeMap::new();
+        option_keys.insert(Some(1), 1);
+        assert!(matches!(
+            to_arguments(&option_keys),
+            Err(FtfError::InvalidArgument(_))
+        ));
+    }
+}
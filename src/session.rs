@@ -0,0 +1,380 @@
+use std::io::Write;
+use std::time::Duration;
+
+use std::sync::Arc;
+
+use crate::category::CategoryRegistry;
+use crate::clock::{MonotonicClock, TraceClock};
+use crate::writer::{Checkpoint, Interning, ThreadWriter, TraceWriter};
+use crate::{FtfError, Record, Result};
+
+/// Configuration of a `TraceSession`
+#[derive(Clone)]
+pub struct TraceSessionBuilder {
+    clock: Arc<dyn TraceClock>,
+    providers: Vec<(u32, String)>,
+    interning: Interning,
+}
+
+impl Default for TraceSessionBuilder {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(MonotonicClock::new()),
+            providers: Vec::new(),
+            interning: Interning::default(),
+        }
+    }
+}
+
+impl TraceSessionBuilder {
+    /// Clock timestamping the session's events. Its rate is written in the
+    /// session's Initialization record. Defaults to the monotonic clock.
+    pub fn clock<C: TraceClock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Add a provider to the session
+    pub fn provider<S: Into<String>>(mut self, provider_id: u32, name: S) -> Self {
+        self.providers.push((provider_id, name.into()));
+        self
+    }
+
+    /// How the session's `ThreadWriter`s look up interned strings and threads
+    pub fn interning(mut self, interning: Interning) -> Self {
+        self.interning = interning;
+        self
+    }
+
+    /// Start the session, writing the magic number, initialization
+    /// record and provider info records to `sink`.
+    /// Fails if no providers were added, or a provider ID was added twice.
+    pub fn build<W: Write + Send + 'static>(self, sink: W) -> Result<TraceSession> {
+        if self.clock.ticks_per_second() == 0 {
+            return Err(FtfError::InvalidConfiguration(
+                "ticks per second must be non-zero".to_string(),
+            ));
+        }
+        if self.providers.is_empty() {
+            return Err(FtfError::InvalidConfiguration(
+                "a session needs at least one provider".to_string(),
+            ));
+        }
+        for (i, (id, _)) in self.providers.iter().enumerate() {
+            if self.providers[..i].iter().any(|(other, _)| other == id) {
+                return Err(FtfError::InvalidConfiguration(format!(
+                    "provider {} added more than once",
+                    id
+                )));
+            }
+        }
+
+        let mut preamble = vec![Record::create_magic_number(), self.clock.initialization()];
+        preamble.extend(
+            self.providers
+                .iter()
+                .map(|(id, name)| Record::create_provider_info(*id, name.as_str())),
+        );
+        let ids: Vec<_> = self.providers.iter().map(|(id, _)| Some(*id)).collect();
+
+        Ok(TraceSession {
+            writer: TraceWriter::with_preamble(
+                sink,
+                self.interning,
+                self.clock.clone(),
+                &preamble,
+                &ids,
+            )?,
+            clock: self.clock,
+            providers: self.providers,
+        })
+    }
+}
+
+/// A trace with a well-formed preamble.
+/// The session writes the magic number, initialization record and provider
+/// info records itself, and hands out per-provider `ThreadWriter`s. When
+/// records of different providers are merged into the sink, a
+/// `ProviderSection` record is inserted whenever the active provider changes.
+pub struct TraceSession {
+    writer: TraceWriter,
+    clock: Arc<dyn TraceClock>,
+    providers: Vec<(u32, String)>,
+}
+
+impl TraceSession {
+    /// Start configuring a session
+    pub fn builder() -> TraceSessionBuilder {
+        TraceSessionBuilder::default()
+    }
+
+    /// Clock timestamping the session's events
+    pub fn clock(&self) -> &dyn TraceClock {
+        &*self.clock
+    }
+
+    /// Current time of the session's clock, as ticks
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// IDs and names of the session's providers, in the order they were added
+    pub fn providers(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.providers.iter().map(|(id, name)| (*id, name.as_str()))
+    }
+
+    /// Create a handle for writing records of `provider_id` from the current thread.
+    /// The handle refuses magic number, initialization and provider metadata
+    /// records, since the session writes those itself.
+    pub fn provider(&self, provider_id: u32) -> Result<ThreadWriter> {
+        let index = self
+            .providers
+            .iter()
+            .position(|(id, _)| *id == provider_id)
+            .ok_or_else(|| {
+                FtfError::InvalidConfiguration(format!("unknown provider {}", provider_id))
+            })?;
+        Ok(self.writer.provider_writer(index, true))
+    }
+
+    /// Categories of events the session traces
+    pub fn categories(&self) -> &CategoryRegistry {
+        self.writer.categories()
+    }
+
+    /// Flush the session if the process panics or exits, and write an
+    /// instant event describing the panic as a record of the first provider.
+    /// Returns whether the session is also flushed at exit, which is only
+    /// available on unix, see `TraceWriter::register_hooks`.
+    pub fn register_hooks(&self) -> bool {
+        self.writer.register_hooks()
+    }
+
+    /// Periodically re-emit the string and thread tables,
+    /// or stop doing so with `None`
+    pub fn set_checkpoint(&self, checkpoint: Option<Checkpoint>) {
+        self.writer.set_checkpoint(checkpoint);
+    }
+
+    /// Start a background thread which flushes the buffers every `interval`
+    pub fn spawn_flusher(&mut self, interval: Duration) {
+        self.writer.spawn_flusher(interval);
+    }
+
+    /// Merge every provider's buffered records into the sink
+    pub fn flush(&self) -> Result<()> {
+        self.writer.flush()
+    }
+
+    /// Stop the background flusher, if any, and flush everything
+    /// buffered so far
+    pub fn finish(self) -> Result<()> {
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Archive, Event, EventRecord, ManualClock, StringRef, ThreadRef};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedSink {
+        fn records(&self) -> Vec<Record> {
+            Archive::read(&self.0.lock().unwrap()[..]).unwrap().records
+        }
+    }
+
+    fn instant(writer: &mut ThreadWriter, ts: u64, name: &str) -> Record {
+        let thread = writer.thread(1, 2);
+        let category = writer.string("category");
+        let name = writer.string(name);
+        Record::create_instant_event(ts, thread, category, name, Vec::new())
+    }
+
+    #[test]
+    fn test_preamble() -> Result<()> {
+        let sink = SharedSink::default();
+        let session = TraceSession::builder()
+            .clock(ManualClock::new(1000))
+            .provider(1, "first")
+            .provider(2, "second")
+            .build(sink.clone())?;
+        session.finish()?;
+
+        assert_eq!(
+            sink.records(),
+            vec![
+                Record::create_magic_number(),
+                Record::create_initialization(1000),
+                Record::create_provider_info(1, "first"),
+                Record::create_provider_info(2, "second"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_provider_sections() -> Result<()> {
+        let sink = SharedSink::default();
+        let session = TraceSession::builder()
+            .provider(1, "first")
+            .provider(2, "second")
+            .build(sink.clone())?;
+
+        let mut first = session.provider(1)?;
+        let mut second = session.provider(2)?;
+        let record = instant(&mut first, 1, "a");
+        first.write(&record)?;
+        session.flush()?;
+        let record = instant(&mut second, 2, "b");
+        second.write(&record)?;
+        session.flush()?;
+        let record = instant(&mut first, 3, "a");
+        first.write(&record)?;
+        session.finish()?;
+
+        let event = |ts| {
+            Record::create_instant_event(
+                ts,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(2),
+                Vec::new(),
+            )
+        };
+        // the last provider info leaves provider 2 active, so the first
+        // records of provider 1 need a section too. Each provider has its
+        // own tables, so both start at index 1.
+        assert_eq!(
+            sink.records()[4..],
+            [
+                Record::create_provider_section(1),
+                Record::create_thread(1, 1, 2),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "a"),
+                event(1),
+                Record::create_provider_section(2),
+                Record::create_thread(1, 1, 2),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "b"),
+                event(2),
+                Record::create_provider_section(1),
+                event(3),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_repeats_section_and_initialization() -> Result<()> {
+        let sink = SharedSink::default();
+        let session = TraceSession::builder()
+            .clock(ManualClock::new(1000))
+            .provider(1, "first")
+            .build(sink.clone())?;
+        session.set_checkpoint(Some(Checkpoint::Bytes(1)));
+
+        let mut first = session.provider(1)?;
+        let record = instant(&mut first, 1, "a");
+        first.write(&record)?;
+        session.flush()?;
+        let start = sink.records().len();
+        let record = instant(&mut first, 2, "a");
+        first.write(&record)?;
+        session.finish()?;
+
+        // provider 1 is still active, but the checkpoint says so again
+        assert_eq!(
+            sink.records()[start..start + 5],
+            [
+                Record::create_provider_section(1),
+                Record::create_initialization(1000),
+                Record::create_string(1, "category"),
+                Record::create_string(2, "a"),
+                Record::create_thread(1, 1, 2),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_preamble_records() -> Result<()> {
+        let session = TraceSession::builder()
+            .provider(1, "first")
+            .build(SharedSink::default())?;
+        let mut writer = session.provider(1)?;
+
+        assert!(matches!(
+            writer.write(&Record::create_magic_number()),
+            Err(FtfError::InvalidRecordOrder(_))
+        ));
+        assert!(matches!(
+            writer.write(&Record::create_provider_section(2)),
+            Err(FtfError::InvalidRecordOrder(_))
+        ));
+        assert!(matches!(
+            session.provider(2),
+            Err(FtfError::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            writer.write(&Record::create_initialization(1000)),
+            Err(FtfError::InvalidRecordOrder(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock_timestamps_events() -> Result<()> {
+        let sink = SharedSink::default();
+        let clock = ManualClock::new(1000);
+        let session = TraceSession::builder()
+            .clock(clock.clone())
+            .provider(1, "first")
+            .build(sink.clone())?;
+
+        let mut writer = session.provider(1)?;
+        clock.set(42);
+        writer.event("category", "tick").write()?;
+        clock.advance(8);
+        assert_eq!(session.now(), 50);
+        session.finish()?;
+
+        let records = sink.records();
+        assert_eq!(records[1], Record::create_initialization(1000));
+        match records.last() {
+            Some(Record::Event(EventRecord::Instant(e))) => assert_eq!(e.timestamp(), 42),
+            r => panic!("expected an instant event, got {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        assert!(matches!(
+            TraceSession::builder().build(SharedSink::default()),
+            Err(FtfError::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            TraceSession::builder()
+                .provider(1, "a")
+                .provider(1, "b")
+                .build(SharedSink::default()),
+            Err(FtfError::InvalidConfiguration(_))
+        ));
+    }
+}
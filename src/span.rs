@@ -0,0 +1,661 @@
+use crate::trace_str::TraceStr;
+use crate::writer::ThreadWriter;
+use crate::{Argument, Record, Result, StringRef, ThreadRef};
+
+/// Values which can be written as an event argument.
+/// String values are interned through the `ThreadWriter`.
+pub trait IntoArgument {
+    /// Build an argument named `name` holding this value
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument;
+}
+
+macro_rules! impl_into_argument {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl IntoArgument for $ty {
+                fn into_argument(self, name: StringRef, _writer: &mut ThreadWriter) -> Argument {
+                    Argument::$variant(name, self.into())
+                }
+            }
+
+            impl IntoArgument for &$ty {
+                fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+                    (*self).into_argument(name, writer)
+                }
+            }
+        )*
+    };
+}
+
+impl_into_argument!(
+    i8 => Int32,
+    i16 => Int32,
+    i32 => Int32,
+    u8 => UInt32,
+    u16 => UInt32,
+    u32 => UInt32,
+    i64 => Int64,
+    u64 => UInt64,
+    f32 => Float,
+    f64 => Float,
+    bool => Boolean,
+);
+
+impl IntoArgument for &str {
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+        Argument::Str(name, writer.string(self))
+    }
+}
+
+impl IntoArgument for String {
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+        self.as_str().into_argument(name, writer)
+    }
+}
+
+impl IntoArgument for &String {
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+        self.as_str().into_argument(name, writer)
+    }
+}
+
+impl IntoArgument for &&str {
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+        (*self).into_argument(name, writer)
+    }
+}
+
+impl IntoArgument for () {
+    fn into_argument(self, name: StringRef, _writer: &mut ThreadWriter) -> Argument {
+        Argument::Null(name)
+    }
+}
+
+/// `None` is written as a null argument
+impl<'a, T> IntoArgument for &'a Option<T>
+where
+    &'a T: IntoArgument,
+{
+    fn into_argument(self, name: StringRef, writer: &mut ThreadWriter) -> Argument {
+        match self {
+            Some(value) => value.into_argument(name, writer),
+            None => Argument::Null(name),
+        }
+    }
+}
+
+/// Structs written as a set of event arguments, one per field.
+/// Usually derived with `#[derive(FtfArgs)]`, from the `derive` feature.
+pub trait FtfArgs {
+    /// Number of arguments written, at most the 15 an event can carry
+    const ARGUMENT_COUNT: usize;
+
+    /// Append the arguments to `arguments`, interning strings through `writer`
+    fn write_arguments(&self, writer: &mut ThreadWriter, arguments: &mut Vec<Argument>);
+}
+
+// Methods adding typed arguments, shared by `EventBuilder` and `SpanGuard`.
+// Argument names and string values are interned through `self.writer`,
+// unless the event is disabled.
+macro_rules! argument_methods {
+    () => {
+        /// Add an argument as is
+        pub fn arg(mut self, argument: Argument) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            self.arguments.push(argument);
+            self
+        }
+
+        /// Add the arguments of a struct implementing `FtfArgs`
+        pub fn args<A: FtfArgs + ?Sized>(mut self, args: &A) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            args.write_arguments(&mut self.writer, &mut self.arguments);
+            self
+        }
+
+        /// Add an argument of any type which can be written as one
+        pub fn arg_value<V: IntoArgument>(mut self, name: &str, value: V) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            let argument = value.into_argument(name, &mut self.writer);
+            self.arguments.push(argument);
+            self
+        }
+
+        /// Add a null argument
+        pub fn arg_null(mut self, name: &str) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Null(name));
+            self
+        }
+
+        /// Add a signed 32-bit integer argument
+        pub fn arg_i32(mut self, name: &str, value: i32) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Int32(name, value));
+            self
+        }
+
+        /// Add an unsigned 32-bit integer argument
+        pub fn arg_u32(mut self, name: &str, value: u32) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::UInt32(name, value));
+            self
+        }
+
+        /// Add a signed 64-bit integer argument
+        pub fn arg_i64(mut self, name: &str, value: i64) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Int64(name, value));
+            self
+        }
+
+        /// Add an unsigned 64-bit integer argument
+        pub fn arg_u64(mut self, name: &str, value: u64) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::UInt64(name, value));
+            self
+        }
+
+        /// Add a double-precision floating point argument
+        pub fn arg_f64(mut self, name: &str, value: f64) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Float(name, value));
+            self
+        }
+
+        /// Add a string argument. The value is interned like the name.
+        pub fn arg_str(mut self, name: &str, value: &str) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            let value = self.writer.string(value);
+            self.arguments.push(Argument::Str(name, value));
+            self
+        }
+
+        /// Add a boolean argument
+        pub fn arg_bool(mut self, name: &str, value: bool) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Boolean(name, value));
+            self
+        }
+
+        /// Add a pointer argument
+        pub fn arg_pointer(mut self, name: &str, value: u64) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::Pointer(name, value));
+            self
+        }
+
+        /// Add a kernel object ID argument
+        pub fn arg_koid(mut self, name: &str, value: u64) -> Self {
+            if !self.is_enabled() {
+                return self;
+            }
+            let name = self.writer.string(name);
+            self.arguments.push(Argument::KernelObjectId(name, value));
+            self
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EventKind {
+    Instant,
+    Counter(u64),
+    DurationBegin,
+    DurationEnd,
+    DurationComplete(u64),
+}
+
+/// Fluent builder for a single event, created with `ThreadWriter::event`.
+/// Strings are interned and the thread resolved through the `ThreadWriter`.
+/// Builds an instant event unless told otherwise, timestamped when it is
+/// written. A builder for a disabled category does nothing, not even
+/// interning its strings.
+pub struct EventBuilder<'w> {
+    writer: &'w mut ThreadWriter,
+    enabled: bool,
+    kind: EventKind,
+    timestamp: Option<u64>,
+    thread: Option<ThreadRef>,
+    category: StringRef,
+    name: StringRef,
+    // category and name, when known without a string-table lookup
+    names: Option<(&'static str, &'static str)>,
+    arguments: Vec<Argument>,
+}
+
+impl<'w> EventBuilder<'w> {
+    pub(crate) fn new(writer: &'w mut ThreadWriter, category: &str, name: &str) -> Self {
+        let enabled = writer.category_enabled(category);
+        let (category, name) = if enabled {
+            (writer.string(category), writer.string(name))
+        } else {
+            (StringRef::Ref(0), StringRef::Ref(0))
+        };
+        Self::with_strings(writer, enabled, category, name)
+    }
+
+    pub(crate) fn new_static(
+        writer: &'w mut ThreadWriter,
+        category: &TraceStr,
+        name: &TraceStr,
+    ) -> Self {
+        let enabled = writer.static_category_enabled(category);
+        let names = Some((category.as_str(), name.as_str()));
+        let (category, name) = if enabled {
+            (writer.static_string(category), writer.static_string(name))
+        } else {
+            (StringRef::Ref(0), StringRef::Ref(0))
+        };
+        let mut builder = Self::with_strings(writer, enabled, category, name);
+        builder.names = names;
+        builder
+    }
+
+    fn with_strings(
+        writer: &'w mut ThreadWriter,
+        enabled: bool,
+        category: StringRef,
+        name: StringRef,
+    ) -> Self {
+        Self {
+            writer,
+            enabled,
+            kind: EventKind::Instant,
+            timestamp: None,
+            thread: None,
+            category,
+            name,
+            names: None,
+            arguments: Vec::new(),
+        }
+    }
+
+    /// Build a counter event instead
+    /// * counter_id: unique ID for this counter
+    pub fn counter(mut self, counter_id: u64) -> Self {
+        self.kind = EventKind::Counter(counter_id);
+        self
+    }
+
+    /// Build a duration begin event instead
+    pub fn begin(mut self) -> Self {
+        self.kind = EventKind::DurationBegin;
+        self
+    }
+
+    /// Build a duration end event instead
+    pub fn end(mut self) -> Self {
+        self.kind = EventKind::DurationEnd;
+        self
+    }
+
+    /// Build a duration complete event ending at `end_ts` instead
+    pub fn complete(mut self, end_ts: u64) -> Self {
+        self.kind = EventKind::DurationComplete(end_ts);
+        self
+    }
+
+    /// Use this timestamp (as ticks) instead of the time the event is written
+    pub fn timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Attribute the event to this thread instead of the default one
+    pub fn thread(mut self, process_koid: u64, thread_koid: u64) -> Self {
+        if self.enabled {
+            self.thread = Some(self.writer.thread(process_koid, thread_koid));
+        }
+        self
+    }
+
+    /// Whether the event's category is enabled, so that it will be written.
+    /// Useful to skip computing expensive arguments.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    argument_methods!();
+
+    /// Write the event to the `ThreadWriter`
+    pub fn write(self) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let timestamp = self.timestamp.unwrap_or_else(|| self.writer.now());
+        let thread = match self.thread {
+            Some(thread) => thread,
+            None => self.writer.default_thread(),
+        };
+        let (category, name, arguments) = (self.category, self.name, self.arguments);
+
+        let record = match self.kind {
+            EventKind::Instant => {
+                Record::create_instant_event(timestamp, thread, category, name, arguments)
+            }
+            EventKind::Counter(id) => {
+                Record::create_counter_event(timestamp, thread, category, name, arguments, id)
+            }
+            EventKind::DurationBegin => {
+                Record::create_duration_begin_event(timestamp, thread, category, name, arguments)
+            }
+            EventKind::DurationEnd => {
+                Record::create_duration_end_event(timestamp, thread, category, name, arguments)
+            }
+            EventKind::DurationComplete(end_ts) => Record::create_duration_complete_event(
+                timestamp, thread, category, name, arguments, end_ts,
+            ),
+        };
+        self.writer.write_checked(&record, self.names)
+    }
+
+    /// Start a span which writes a single DurationComplete event when it ends.
+    /// The builder's arguments are written with that event.
+    pub fn span(self) -> SpanGuard {
+        self.start_span(SpanMode::Complete)
+    }
+
+    /// Start a span which writes a DurationBegin event now, with the builder's
+    /// arguments, and a DurationEnd event when it ends. Unlike `span`, the
+    /// beginning of the span is in the trace even if it never ends.
+    pub fn span_begin_end(self) -> Result<SpanGuard> {
+        let mut guard = self.start_span(SpanMode::BeginEnd);
+        if !guard.is_enabled() {
+            return Ok(guard);
+        }
+        let record = Record::create_duration_begin_event(
+            guard.start,
+            guard.thread,
+            guard.category.clone(),
+            guard.name.clone(),
+            std::mem::take(&mut guard.arguments),
+        );
+        guard.writer.write_checked(&record, guard.names)?;
+        Ok(guard)
+    }
+
+    fn start_span(self, mode: SpanMode) -> SpanGuard {
+        let start = self.timestamp.unwrap_or_else(|| self.writer.now());
+        let thread = match self.thread {
+            Some(thread) => thread,
+            None if self.enabled => self.writer.default_thread(),
+            None => ThreadRef::Ref(0),
+        };
+        SpanGuard {
+            writer: self.writer.detach(),
+            mode,
+            start,
+            thread,
+            category: self.category,
+            name: self.name,
+            names: self.names,
+            arguments: self.arguments,
+            // a disabled span has nothing to write
+            ended: !self.enabled,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpanMode {
+    Complete,
+    BeginEnd,
+}
+
+/// A span which is written to the trace when it ends:
+/// explicitly with `end`, or when the guard is dropped.
+/// The guard doesn't borrow the `ThreadWriter` it was started from, so spans
+/// can be nested, and writes to the same buffer so the events stay in order.
+pub struct SpanGuard {
+    writer: ThreadWriter,
+    mode: SpanMode,
+    start: u64,
+    thread: ThreadRef,
+    category: StringRef,
+    name: StringRef,
+    names: Option<(&'static str, &'static str)>,
+    arguments: Vec<Argument>,
+    ended: bool,
+}
+
+impl SpanGuard {
+    /// Timestamp the span started at (as ticks)
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Whether the span's category was enabled when it started and
+    /// it hasn't ended yet, so that ending it writes an event
+    pub fn is_enabled(&self) -> bool {
+        !self.ended
+    }
+
+    argument_methods!();
+
+    /// End the span now, reporting any error writing it.
+    /// Dropping the guard does the same but ignores errors.
+    pub fn end(mut self) -> Result<()> {
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.ended {
+            return Ok(());
+        }
+        self.ended = true;
+
+        let end = self.writer.now();
+        let arguments = std::mem::take(&mut self.arguments);
+        let (thread, category, name) = (self.thread, self.category.clone(), self.name.clone());
+        let record = match self.mode {
+            SpanMode::Complete => Record::create_duration_complete_event(
+                self.start, thread, category, name, arguments, end,
+            ),
+            SpanMode::BeginEnd => {
+                Record::create_duration_end_event(end, thread, category, name, arguments)
+            }
+        };
+        // the category was checked when the span started, so its end is
+        // written even if the category was disabled since
+        self.writer.write_checked(&record, self.names)
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Archive, Event, EventRecord, Interning, Record, StringRef, ThreadRef, TraceWriter,
+    };
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn events(sink: &SharedSink) -> Vec<EventRecord> {
+        Archive::read(&sink.0.lock().unwrap()[..])
+            .unwrap()
+            .records
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Event(e) => Some(e),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_event_builder() -> crate::Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        let mut w = writer.thread_writer();
+
+        w.event("cat", "tick")
+            .timestamp(10)
+            .thread(1, 2)
+            .arg_i64("count", -3)
+            .arg_str("state", "ok")
+            .write()?;
+        w.event("cat", "queue")
+            .counter(7)
+            .arg_u64("len", 4)
+            .write()?;
+        writer.finish()?;
+
+        let records = Archive::read(&sink.0.lock().unwrap()[..])?.records;
+        assert_eq!(
+            records[1..6],
+            [
+                Record::create_string(1, "cat"),
+                Record::create_string(2, "tick"),
+                Record::create_thread(1, 1, 2),
+                Record::create_string(3, "count"),
+                Record::create_string(4, "state"),
+            ]
+        );
+        assert_eq!(
+            records[records.len() - 2],
+            Record::create_instant_event(
+                10,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(2),
+                vec![
+                    crate::Argument::Int64(StringRef::Ref(3), -3),
+                    crate::Argument::Str(StringRef::Ref(4), StringRef::Ref(5)),
+                ],
+            )
+        );
+        match &records[records.len() - 1] {
+            Record::Event(EventRecord::Counter(c)) => assert_eq!(c.counter_id(), 7),
+            r => panic!("expected a counter, got {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_disabled_event_writes_nothing() -> crate::Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        writer.categories().set_patterns(["-off"]);
+        let mut w = writer.thread_writer();
+        writer.flush()?;
+        let written = sink.0.lock().unwrap().len();
+
+        w.event("off", "x")
+            .arg_value("secret_key", "secret_value")
+            .arg_str("other_key", "other_value")
+            .write()?;
+        drop(w);
+        writer.finish()?;
+        assert_eq!(sink.0.lock().unwrap().len(), written);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_spans() -> crate::Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::PerThread)?;
+        let mut w = writer.thread_writer();
+        w.set_default_thread(1, 2);
+
+        {
+            let outer = w.event("cat", "outer").arg_bool("root", true).span();
+            let inner = w.event("cat", "inner").span_begin_end()?;
+            inner.arg_u32("items", 3).end()?;
+            assert!(outer.start() <= w.now());
+        }
+        writer.finish()?;
+
+        let events = events(&sink);
+        assert_eq!(events.len(), 3);
+        match &events[..] {
+            [EventRecord::DurationBegin(begin), EventRecord::DurationEnd(end), EventRecord::DurationComplete(complete)] =>
+            {
+                assert_eq!(begin.name(), &StringRef::Ref(4));
+                assert!(begin.arguments().is_empty());
+                assert_eq!(end.name(), &StringRef::Ref(4));
+                assert_eq!(end.arguments().len(), 1);
+                assert_eq!(complete.name(), &StringRef::Ref(2));
+                assert_eq!(complete.arguments().len(), 1);
+                assert!(complete.timestamp() <= begin.timestamp());
+                assert!(complete.end_ts() >= end.timestamp());
+            }
+            e => panic!("unexpected events {:?}", e),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_span_end_follows_begin() -> crate::Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        let mut w = writer.thread_writer();
+
+        let span = w.event("cat", "span").span_begin_end()?;
+        // disabled while the span is open, which still ends
+        writer.categories().disable("cat");
+        span.end()?;
+        drop(w);
+        writer.finish()?;
+
+        let events = events(&sink);
+        assert!(matches!(
+            &events[..],
+            [EventRecord::DurationBegin(_), EventRecord::DurationEnd(_)]
+        ));
+        Ok(())
+    }
+}
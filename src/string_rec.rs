@@ -1,20 +1,48 @@
 use crate::header::CustomField;
+use crate::preserved::Preserved;
 use crate::wordutils::{self, pad_and_write_string};
-use crate::{extract_bits, RecordHeader, Result};
+use crate::{extract_bits, FtfError, RecordHeader, Result};
 use std::io::{Read, Write};
 
 /// String record. Represents a String interned
 /// in the provider's string table with the assosciated
 /// index
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "StringRecordRepr"))]
 pub struct StringRecord {
     index: u16,
     value: String,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: Preserved,
 }
 
 impl StringRecord {
-    pub(crate) fn new(index: u16, value: String) -> Self {
-        Self { index, value }
+    /// String record registering `value` (at most 32767 bytes) at `index`
+    /// (1 to 0x7FFF, index 0 is always the empty string).
+    /// Out-of-range values panic in debug builds, and fail to be written.
+    pub fn new(index: u16, value: String) -> Self {
+        debug_assert!((1..=0x7FFF).contains(&index), "string index {}", index);
+        debug_assert!(value.len() <= 0x7FFF, "string of {} bytes", value.len());
+        Self {
+            index,
+            value,
+            preserved: Preserved::default(),
+        }
+    }
+
+    /// Set the index into the provider's string table, from 1 to 0x7FFF
+    pub fn set_index(&mut self, index: u16) {
+        debug_assert!((1..=0x7FFF).contains(&index), "string index {}", index);
+        self.index = index;
+        self.preserved.clear();
+    }
+
+    /// Replace the string, of at most 32767 bytes
+    pub fn set_value(&mut self, value: String) {
+        debug_assert!(value.len() <= 0x7FFF, "string of {} bytes", value.len());
+        self.value = value;
+        self.preserved.clear();
     }
 
     /// Index into the provider's string table
@@ -37,16 +65,33 @@ impl StringRecord {
         let length = extract_bits!(header.value, 32, 46) as u32;
 
         let value = wordutils::read_aligned_str(reader, length as usize)?;
-        Ok(StringRecord { index, value })
+        // a malformed trace may define index 0, which is kept as read
+        Ok(StringRecord {
+            index,
+            value,
+            preserved: Preserved::default(),
+        })
     }
 
     pub(super) fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        if self.index > 0x7FFF {
+            return Err(FtfError::ValueOutOfRange(format!(
+                "string index {}, at most 32767",
+                self.index
+            )));
+        }
         let str_bytes = self.value.as_bytes();
+        if str_bytes.len() > 0x7FFF {
+            return Err(FtfError::ValueOutOfRange(format!(
+                "string of {} bytes, at most 32767",
+                str_bytes.len()
+            )));
+        }
         // header + num words for string
         let num_words = 1 + str_bytes.len().div_ceil(8);
         let header = RecordHeader::build(
             crate::header::RecordType::String,
-            num_words as u8,
+            num_words,
             &[
                 CustomField {
                     width: 15,
@@ -68,6 +113,32 @@ impl StringRecord {
     }
 }
 
+// Deserialized before its index and length are checked
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct StringRecordRepr {
+    index: u16,
+    value: String,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<StringRecordRepr> for StringRecord {
+    type Error = String;
+
+    fn try_from(repr: StringRecordRepr) -> std::result::Result<Self, String> {
+        if repr.index == 0 || repr.index > 0x7FFF {
+            return Err(format!("string index {}, from 1 to 32767", repr.index));
+        }
+        if repr.value.len() > 0x7FFF {
+            return Err(format!(
+                "string of {} bytes, at most 32767",
+                repr.value.len()
+            ));
+        }
+        Ok(Self::new(repr.index, repr.value))
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::identity_op)]
 mod tests {
@@ -112,6 +183,7 @@ mod tests {
         let record = StringRecord {
             index: 42,
             value: "Hello World".to_string(),
+            preserved: Preserved::default(),
         };
 
         // Write it to a buffer
@@ -156,6 +228,7 @@ mod tests {
         let record = StringRecord {
             index: 100,
             value: "ABCDEFGH".to_string(),
+            preserved: Preserved::default(),
         };
 
         // Write it to a buffer
@@ -179,6 +252,7 @@ mod tests {
         let original_record = StringRecord {
             index: 123,
             value: "Test String!!".to_string(),
+            preserved: Preserved::default(),
         };
 
         // Write it to a buffer
@@ -201,4 +275,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_out_of_range_is_not_written() {
+        let too_long = StringRecord {
+            index: 1,
+            value: "x".repeat(0x8000),
+            preserved: Preserved::default(),
+        };
+        let index = StringRecord {
+            index: 0x8000,
+            value: "x".to_string(),
+            preserved: Preserved::default(),
+        };
+        for record in [too_long, index] {
+            assert!(matches!(
+                record.write(&mut Vec::new()),
+                Err(FtfError::ValueOutOfRange(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "string index 0")]
+    fn test_index_zero_asserts() {
+        StringRecord::new(0, "x".to_string());
+    }
 }
@@ -0,0 +1,385 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{MetadataRecord, Record, StringRecord, ThreadRecord};
+
+/// A provider's string table. Maps interned strings
+/// to the index they were registered with in a String record,
+/// and back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StringTable {
+    values: BTreeMap<u16, String>,
+    indices: HashMap<String, u16>,
+    next: u16,
+}
+
+impl StringTable {
+    /// Largest index a String record can carry (15 bits)
+    pub const MAX_INDEX: u16 = 0x7FFF;
+
+    /// Create an empty string table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The string registered at `index`, if any
+    pub fn get(&self, index: u16) -> Option<&str> {
+        self.values.get(&index).map(String::as_str)
+    }
+
+    /// The index `value` was registered with, if any
+    pub fn index_of(&self, value: &str) -> Option<u16> {
+        self.indices.get(value).copied()
+    }
+
+    /// Intern a string, allocating the next free index if it has not been seen before.
+    /// Returns the index and whether it was newly allocated (in which case a String record
+    /// has to be written before the index is referenced), or None if the table is full
+    /// or the string is too long for a String record (32767 bytes).
+    pub fn intern(&mut self, value: &str) -> Option<(u16, bool)> {
+        if let Some(index) = self.index_of(value) {
+            return Some((index, false));
+        }
+        if value.len() > 0x7FFF {
+            return None;
+        }
+
+        // index 0 is reserved for the empty string
+        let mut index = self.next.max(1);
+        while self.values.contains_key(&index) {
+            if index == Self::MAX_INDEX {
+                return None;
+            }
+            index += 1;
+        }
+        if index > Self::MAX_INDEX {
+            return None;
+        }
+
+        self.next = index + 1;
+        self.insert(index, value.to_string());
+        Some((index, true))
+    }
+
+    /// Register `value` at `index`, replacing whatever was there before.
+    /// Mirrors the effect of reading a String record.
+    pub fn insert(&mut self, index: u16, value: String) {
+        if let Some(old) = self.values.insert(index, value.clone()) {
+            if self.indices.get(&old) == Some(&index) {
+                self.indices.remove(&old);
+            }
+        }
+        self.indices.insert(value, index);
+    }
+
+    /// Number of strings in the table
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the table has no strings
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Remove every string from the table
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.indices.clear();
+        self.next = 0;
+    }
+
+    /// Iterate over (index, string) pairs in index order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.values.iter().map(|(i, s)| (*i, s.as_str()))
+    }
+
+    /// String records which recreate this table when written
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        self.iter()
+            .map(|(i, s)| Record::String(StringRecord::new(i, s.to_string())))
+    }
+}
+
+/// A provider's thread table. Maps (process, thread) KOID pairs
+/// to the index they were registered with in a Thread record,
+/// and back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadTable {
+    values: BTreeMap<u8, (u64, u64)>,
+    indices: HashMap<(u64, u64), u8>,
+}
+
+impl ThreadTable {
+    /// Create an empty thread table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The (process_koid, thread_koid) registered at `index`, if any
+    pub fn get(&self, index: u8) -> Option<(u64, u64)> {
+        self.values.get(&index).copied()
+    }
+
+    /// The index the thread was registered with, if any
+    pub fn index_of(&self, process_koid: u64, thread_koid: u64) -> Option<u8> {
+        self.indices.get(&(process_koid, thread_koid)).copied()
+    }
+
+    /// Intern a thread, allocating the next free index if it has not been seen before.
+    /// Returns the index and whether it was newly allocated, or None if all 255 indices
+    /// are in use.
+    pub fn intern(&mut self, process_koid: u64, thread_koid: u64) -> Option<(u8, bool)> {
+        if let Some(index) = self.index_of(process_koid, thread_koid) {
+            return Some((index, false));
+        }
+
+        // index 0 denotes an inline thread
+        let index = (1..=u8::MAX).find(|i| !self.values.contains_key(i))?;
+        self.insert(index, process_koid, thread_koid);
+        Some((index, true))
+    }
+
+    /// Register the thread at `index`, replacing whatever was there before.
+    /// Mirrors the effect of reading a Thread record.
+    pub fn insert(&mut self, index: u8, process_koid: u64, thread_koid: u64) {
+        if let Some(old) = self.values.insert(index, (process_koid, thread_koid)) {
+            if self.indices.get(&old) == Some(&index) {
+                self.indices.remove(&old);
+            }
+        }
+        self.indices.insert((process_koid, thread_koid), index);
+    }
+
+    /// Number of threads in the table
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Whether the table has no threads
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Remove every thread from the table
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.indices.clear();
+    }
+
+    /// Iterate over (index, process_koid, thread_koid) in index order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+        self.values.iter().map(|(i, (p, t))| (*i, *p, *t))
+    }
+
+    /// Thread records which recreate this table when written
+    pub fn records(&self) -> impl Iterator<Item = Record> + '_ {
+        self.iter()
+            .map(|(i, p, t)| Record::Thread(ThreadRecord::new(i, p, t)))
+    }
+}
+
+/// String and thread tables of a single provider
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ProviderTables {
+    pub(crate) strings: StringTable,
+    pub(crate) threads: ThreadTable,
+}
+
+/// Follows the String and Thread records of a trace, per provider, so
+/// refs can be resolved with the tables in effect where they appear.
+/// Feed it every record in order; a magic number record starts a new
+/// trace, forgetting every provider and table of the previous one.
+#[derive(Debug, Clone)]
+pub struct TableTracker {
+    tables: HashMap<Option<u32>, ProviderTables>,
+    // None until the trace has a ProviderInfo or ProviderSection
+    current_provider: Option<u32>,
+}
+
+impl Default for TableTracker {
+    fn default() -> Self {
+        Self {
+            tables: HashMap::from([(None, Default::default())]),
+            current_provider: None,
+        }
+    }
+}
+
+impl TableTracker {
+    /// Create a tracker for the start of a trace
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tables with the next record of the trace
+    pub fn observe(&mut self, record: &Record) {
+        match record {
+            Record::Metadata(MetadataRecord::MagicNumber) => *self = Self::default(),
+            Record::Metadata(MetadataRecord::ProviderInfo(info)) => {
+                self.switch_provider(info.provider_id())
+            }
+            Record::Metadata(MetadataRecord::ProviderSection(section)) => {
+                self.switch_provider(section.provider_id())
+            }
+            Record::String(s) => self
+                .current_mut()
+                .strings
+                .insert(s.index(), s.value().clone()),
+            Record::Thread(t) => {
+                self.current_mut()
+                    .threads
+                    .insert(t.index(), t.process_koid(), t.thread_koid())
+            }
+            _ => (),
+        }
+    }
+
+    /// Provider records are currently attributed to
+    pub fn current_provider(&self) -> Option<u32> {
+        self.current_provider
+    }
+
+    /// String table of the current provider
+    pub fn strings(&self) -> &StringTable {
+        &self.tables[&self.current_provider].strings
+    }
+
+    /// Thread table of the current provider
+    pub fn threads(&self) -> &ThreadTable {
+        &self.tables[&self.current_provider].threads
+    }
+
+    /// String and thread tables of `provider`, if it appeared in the trace
+    pub(crate) fn provider_tables(&self, provider: Option<u32>) -> Option<&ProviderTables> {
+        self.tables.get(&provider)
+    }
+
+    fn switch_provider(&mut self, provider_id: u32) {
+        self.current_provider = Some(provider_id);
+        self.tables.entry(self.current_provider).or_default();
+    }
+
+    fn current_mut(&mut self) -> &mut ProviderTables {
+        self.tables
+            .get_mut(&self.current_provider)
+            .expect("the current provider has tables")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_string_table_intern() {
+        let mut table = StringTable::new();
+
+        assert_eq!(table.intern("foo"), Some((1, true)));
+        assert_eq!(table.intern("bar"), Some((2, true)));
+        assert_eq!(table.intern("foo"), Some((1, false)));
+
+        assert_eq!(table.get(2), Some("bar"));
+        assert_eq!(table.index_of("foo"), Some(1));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn test_string_table_skips_taken_indices() {
+        let mut table = StringTable::new();
+        table.insert(1, "taken".to_string());
+
+        assert_eq!(table.intern("foo"), Some((2, true)));
+    }
+
+    #[test]
+    fn test_string_table_full() {
+        let mut table = StringTable::new();
+        for i in 1..=StringTable::MAX_INDEX {
+            assert_eq!(table.intern(&i.to_string()), Some((i, true)));
+        }
+
+        assert_eq!(table.intern("one too many"), None);
+        assert_eq!(table.intern("1"), Some((1, false)));
+    }
+
+    #[test]
+    fn test_string_table_too_long() {
+        let mut table = StringTable::new();
+        assert_eq!(table.intern(&"x".repeat(0x8000)), None);
+        assert_eq!(table.intern(&"x".repeat(0x7FFF)), Some((1, true)));
+    }
+
+    #[test]
+    fn test_string_table_insert_replaces() {
+        let mut table = StringTable::new();
+        table.insert(3, "old".to_string());
+        table.insert(3, "new".to_string());
+
+        assert_eq!(table.get(3), Some("new"));
+        assert_eq!(table.index_of("old"), None);
+        assert_eq!(table.index_of("new"), Some(3));
+    }
+
+    #[test]
+    fn test_thread_table_intern() {
+        let mut table = ThreadTable::new();
+
+        assert_eq!(table.intern(1, 2), Some((1, true)));
+        assert_eq!(table.intern(1, 3), Some((2, true)));
+        assert_eq!(table.intern(1, 2), Some((1, false)));
+        assert_eq!(table.get(2), Some((1, 3)));
+
+        for t in 4..=256 {
+            table.intern(1, t);
+        }
+        assert_eq!(table.len(), 255);
+        assert_eq!(table.intern(1, 1000), None);
+    }
+
+    #[test]
+    fn test_table_records() {
+        let mut strings = StringTable::new();
+        strings.intern("foo");
+        let mut threads = ThreadTable::new();
+        threads.intern(0x12, 0x34);
+
+        assert_eq!(
+            strings.records().collect::<Vec<_>>(),
+            vec![Record::create_string(1, "foo")]
+        );
+        assert_eq!(
+            threads.records().collect::<Vec<_>>(),
+            vec![Record::create_thread(1, 0x12, 0x34)]
+        );
+    }
+
+    #[test]
+    fn test_table_tracker() {
+        let mut tracker = TableTracker::new();
+        let records = [
+            Record::create_string(1, "before providers"),
+            Record::create_provider_info(1, "one"),
+            Record::create_string(1, "one"),
+            Record::create_thread(1, 10, 11),
+            Record::create_provider_section(2),
+            Record::create_string(1, "two"),
+        ];
+        for record in &records {
+            tracker.observe(record);
+        }
+        assert_eq!(tracker.current_provider(), Some(2));
+        assert_eq!(tracker.strings().get(1), Some("two"));
+        assert!(tracker.threads().is_empty());
+
+        tracker.observe(&Record::create_provider_section(1));
+        assert_eq!(tracker.strings().get(1), Some("one"));
+        assert_eq!(tracker.threads().get(1), Some((10, 11)));
+
+        // a new trace starts from scratch
+        tracker.observe(&Record::create_magic_number());
+        assert_eq!(tracker.current_provider(), None);
+        assert!(tracker.strings().is_empty());
+        tracker.observe(&Record::create_provider_section(1));
+        assert_eq!(tracker.strings().get(1), None);
+    }
+}
@@ -0,0 +1,933 @@
+//! Line-oriented text form of a trace, for hand-written test traces and
+//! readable diffs. One record per line, eg:
+//!
+//! ```text
+//! string index=1 value="db"
+//! event instant ts=100 thread=ref:1 cat=ref:1 name="started" args{int32 status_code=200}
+//! ```
+//!
+//! Strings are either `ref:<index>` or inline, written `"..."` or
+//! `inline:"..."`; argument names may also be bare identifiers. Threads are
+//! `ref:<index>` or `inline:<process koid>/<thread koid>`. Fields must be in
+//! the order they're printed in.
+//!
+//! Printing and parsing keep every modelled field as is, so for metadata,
+//! initialization, string, thread and instant, counter and duration event
+//! records, binary to text to binary gives back the same bytes, except for
+//! reserved bits and padding kept by `read_preserving`. Other records are
+//! read without their payload and have no text form: `Record::to_text` and
+//! `Archive::to_text` report them as errors, `Display` prints them as a
+//! `#` comment, and parsing refuses their keywords.
+//!
+//! Parsing refuses lines which couldn't be written back, such as events
+//! with more than 15 arguments or records too large for their size field.
+
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::str::FromStr;
+
+use crate::{
+    Archive, Argument, Counter, DurationBegin, DurationComplete, DurationEnd, EventRecord,
+    FtfError, InitializationRecord, Instant, MetadataRecord, ProviderEvent, ProviderInfo,
+    ProviderSection, Record, Result, StringRecord, StringRef, ThreadRecord, ThreadRef, TraceInfo,
+};
+
+/// Text keyword of records read without their payload, which can't be
+/// printed without losing it
+fn payloadless(record: &Record) -> Option<&'static str> {
+    Some(match record {
+        Record::Blob => "blob",
+        Record::Userspace => "userspace",
+        Record::Kernel => "kernel",
+        Record::Scheduling => "scheduling",
+        Record::Log => "log",
+        Record::LargeBlob => "large_blob",
+        Record::Event(EventRecord::AsyncBegin) => "event async_begin",
+        Record::Event(EventRecord::AsyncEnd) => "event async_end",
+        Record::Event(EventRecord::AsyncInstant) => "event async_instant",
+        Record::Event(EventRecord::FlowBegin) => "event flow_begin",
+        Record::Event(EventRecord::FlowEnd) => "event flow_end",
+        Record::Event(EventRecord::FlowStep) => "event flow_step",
+        _ => return None,
+    })
+}
+
+impl Record {
+    /// Text form of the record, or an error for records without a payload
+    pub fn to_text(&self) -> Result<String> {
+        match payloadless(self) {
+            Some(keyword) => Err(FtfError::Unimplemented(format!(
+                "text form of `{}` records",
+                keyword
+            ))),
+            None => Ok(self.to_string()),
+        }
+    }
+}
+
+impl Archive {
+    /// Text form of the archive, or an error for the first record without
+    /// a payload
+    pub fn to_text(&self) -> Result<String> {
+        for (number, record) in self.records.iter().enumerate() {
+            if let Err(FtfError::Unimplemented(message)) = record.to_text() {
+                return Err(FtfError::Unimplemented(format!(
+                    "record {}: {}",
+                    number + 1,
+                    message
+                )));
+            }
+        }
+        Ok(self.to_string())
+    }
+}
+
+/// Prints records without a payload as comments, see `Archive::to_text`
+impl Display for Archive {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for record in &self.records {
+            writeln!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses one record per line, skipping blank lines and `#` comments
+impl FromStr for Archive {
+    type Err = FtfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut records = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = line.parse().map_err(|e| match e {
+                FtfError::ParseError(message) => {
+                    FtfError::ParseError(format!("line {}: {}", number + 1, message))
+                }
+                e => e,
+            })?;
+            records.push(record);
+        }
+        Ok(Archive { records })
+    }
+}
+
+/// Prints records without a payload as a comment, see `Record::to_text`
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(keyword) = payloadless(self) {
+            return write!(f, "# {} record without its payload", keyword);
+        }
+        match self {
+            Record::Metadata(MetadataRecord::MagicNumber) => f.write_str("magic"),
+            Record::Metadata(MetadataRecord::ProviderInfo(r)) => {
+                write!(f, "provider_info id={} name=", r.provider_id())?;
+                write_quoted(f, r.provider_name())
+            }
+            Record::Metadata(MetadataRecord::ProviderSection(r)) => {
+                write!(f, "provider_section id={}", r.provider_id())
+            }
+            Record::Metadata(MetadataRecord::ProviderEvent(r)) => {
+                write!(
+                    f,
+                    "provider_event id={} event={}",
+                    r.provider_id(),
+                    r.event_id()
+                )
+            }
+            Record::Metadata(MetadataRecord::TraceInfo(r)) => {
+                write!(
+                    f,
+                    "trace_info type={} data={:#x}",
+                    r.trace_info_type(),
+                    r.data()
+                )
+            }
+            Record::Initialization(r) => {
+                write!(
+                    f,
+                    "initialization ticks_per_second={}",
+                    r.ticks_per_second()
+                )
+            }
+            Record::String(r) => {
+                write!(f, "string index={} value=", r.index())?;
+                write_quoted(f, r.value())
+            }
+            Record::Thread(r) => write!(
+                f,
+                "thread index={} process_koid={} thread_koid={}",
+                r.index(),
+                r.process_koid(),
+                r.thread_koid()
+            ),
+            Record::Event(r) => write_event(f, r),
+            _ => unreachable!("records without a payload are printed above"),
+        }
+    }
+}
+
+impl FromStr for Record {
+    type Err = FtfError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut p = Parser { rest: s };
+        let record = match p.word()? {
+            "magic" => Record::create_magic_number(),
+            "provider_info" => {
+                let id = p.field("id", Parser::unsigned)?;
+                let name = p.field("name", Parser::quoted)?;
+                if name.len() > 0xFF {
+                    return Err(p.error("provider name longer than 255 bytes"));
+                }
+                Record::Metadata(MetadataRecord::ProviderInfo(ProviderInfo::new(id, name)))
+            }
+            "provider_section" => {
+                let id = p.field("id", Parser::unsigned)?;
+                Record::Metadata(MetadataRecord::ProviderSection(ProviderSection::new(id)))
+            }
+            "provider_event" => {
+                let id = p.field("id", Parser::unsigned)?;
+                let event = p.field("event", Parser::unsigned)?;
+                if event > 0xF {
+                    return Err(p.error("event id wider than 4 bits"));
+                }
+                Record::Metadata(MetadataRecord::ProviderEvent(ProviderEvent::new(id, event)))
+            }
+            "trace_info" => {
+                let ty: u8 = p.field("type", Parser::unsigned)?;
+                let data: u64 = p.field("data", Parser::unsigned)?;
+                if ty > 0xF || data >> 40 != 0 {
+                    return Err(p.error("trace info type or data too wide"));
+                }
+                let mut info = TraceInfo::new(ty, &[0; 5]);
+                info.set_data(data);
+                Record::Metadata(MetadataRecord::TraceInfo(info))
+            }
+            "initialization" => Record::Initialization(InitializationRecord::new(
+                p.field("ticks_per_second", Parser::unsigned)?,
+            )),
+            "string" => {
+                let index = p.field("index", Parser::index)?;
+                if index == 0 {
+                    return Err(p.error("string index 0 is the empty string"));
+                }
+                let value = p.field("value", Parser::quoted)?;
+                if value.len() > 0x7FFF {
+                    return Err(p.error("string longer than 32767 bytes"));
+                }
+                Record::String(StringRecord::new(index, value))
+            }
+            "thread" => {
+                let index = p.field("index", Parser::unsigned)?;
+                let process_koid = p.field("process_koid", Parser::unsigned)?;
+                let thread_koid = p.field("thread_koid", Parser::unsigned)?;
+                Record::Thread(ThreadRecord::new(index, process_koid, thread_koid))
+            }
+            "event" => Record::Event(p.event()?),
+            keyword @ ("blob" | "userspace" | "kernel" | "scheduling" | "log" | "large_blob") => {
+                return Err(p.error(&format!("`{}` records have no text form", keyword)))
+            }
+            other => return Err(p.error(&format!("unknown record `{}`", other))),
+        };
+        p.finish()?;
+        // eg: inline strings too long for the record's size field
+        match record.write(&mut Vec::new()) {
+            Err(FtfError::ValueOutOfRange(message)) => Err(FtfError::ParseError(format!(
+                "record too large: {}",
+                message
+            ))),
+            _ => Ok(record),
+        }
+    }
+}
+
+fn write_event(f: &mut Formatter<'_>, record: &EventRecord) -> fmt::Result {
+    let kind = match record {
+        EventRecord::Instant(_) => "instant",
+        EventRecord::Counter(_) => "counter",
+        EventRecord::DurationBegin(_) => "duration_begin",
+        EventRecord::DurationEnd(_) => "duration_end",
+        EventRecord::DurationComplete(_) => "duration_complete",
+        _ => unreachable!("events without a payload are printed as comments"),
+    };
+    let event = record
+        .as_event()
+        .expect("events with a text form have a payload");
+    write!(f, "event {}", kind)?;
+
+    write!(f, " ts={} thread=", event.timestamp())?;
+    match event.thread() {
+        ThreadRef::Ref(index) => write!(f, "ref:{}", index)?,
+        ThreadRef::Inline {
+            process_koid,
+            thread_koid,
+        } => write!(f, "inline:{}/{}", process_koid, thread_koid)?,
+    }
+    f.write_str(" cat=")?;
+    write_string_ref(f, event.category())?;
+    f.write_str(" name=")?;
+    write_string_ref(f, event.name())?;
+    match record {
+        EventRecord::Counter(e) => write!(f, " id={}", e.counter_id())?,
+        EventRecord::DurationComplete(e) => write!(f, " end_ts={}", e.end_ts())?,
+        _ => {}
+    }
+
+    if event.arguments().is_empty() {
+        return Ok(());
+    }
+    f.write_str(" args{")?;
+    for (i, arg) in event.arguments().iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write_argument(f, arg)?;
+    }
+    f.write_char('}')
+}
+
+fn write_argument(f: &mut Formatter<'_>, arg: &Argument) -> fmt::Result {
+    let ty = match arg {
+        Argument::Null(_) => "null",
+        Argument::Int32(..) => "int32",
+        Argument::UInt32(..) => "uint32",
+        Argument::Int64(..) => "int64",
+        Argument::UInt64(..) => "uint64",
+        Argument::Float(..) => "float",
+        Argument::Str(..) => "str",
+        Argument::Pointer(..) => "pointer",
+        Argument::KernelObjectId(..) => "koid",
+        Argument::Boolean(..) => "bool",
+    };
+    write!(f, "{} ", ty)?;
+    match arg.name() {
+        StringRef::Inline(name) if is_identifier(name) => f.write_str(name)?,
+        name => write_string_ref(f, name)?,
+    }
+    match arg {
+        Argument::Null(_) => Ok(()),
+        Argument::Int32(_, v) => write!(f, "={}", v),
+        Argument::UInt32(_, v) => write!(f, "={}", v),
+        Argument::Int64(_, v) => write!(f, "={}", v),
+        Argument::UInt64(_, v) => write!(f, "={}", v),
+        // keep the payload of NaNs
+        Argument::Float(_, v) if v.is_nan() => write!(f, "=bits:{:#x}", v.to_bits()),
+        Argument::Float(_, v) => write!(f, "={:?}", v),
+        Argument::Str(_, v) => {
+            f.write_char('=')?;
+            write_string_ref(f, v)
+        }
+        Argument::Pointer(_, v) => write!(f, "={:#x}", v),
+        Argument::KernelObjectId(_, v) => write!(f, "={}", v),
+        Argument::Boolean(_, v) => write!(f, "={}", v),
+    }
+}
+
+fn write_string_ref(f: &mut Formatter<'_>, s: &StringRef) -> fmt::Result {
+    match s {
+        StringRef::Ref(index) => write!(f, "ref:{}", index),
+        StringRef::Inline(s) => write_quoted(f, s),
+    }
+}
+
+fn write_quoted(f: &mut Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+struct Parser<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> FtfError {
+        let at: String = self.rest.chars().take(24).collect();
+        if at.is_empty() {
+            FtfError::ParseError(format!("{} at end of line", message))
+        } else {
+            FtfError::ParseError(format!("{} at `{}`", message, at))
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        match self.rest.strip_prefix(token) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", token)))
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.skip_spaces();
+        if self.rest.is_empty() {
+            Ok(())
+        } else {
+            Err(self.error("unexpected input"))
+        }
+    }
+
+    /// Identifier such as a record or argument type
+    fn word(&mut self) -> Result<&'a str> {
+        self.skip_spaces();
+        let end = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return Err(self.error("expected a name"));
+        }
+        let (word, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Ok(word)
+    }
+
+    /// Value of a number, up to the next separator
+    fn token(&mut self) -> &'a str {
+        let end = self
+            .rest
+            .find(|c: char| c.is_whitespace() || matches!(c, ',' | '}' | '/' | '='))
+            .unwrap_or(self.rest.len());
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        token
+    }
+
+    /// `key=value`, parsing the value with `value`
+    fn field<T>(&mut self, key: &str, value: fn(&mut Self) -> Result<T>) -> Result<T> {
+        self.skip_spaces();
+        self.expect(key)?;
+        self.expect("=")?;
+        value(self)
+    }
+
+    fn unsigned<T: TryFrom<u64>>(&mut self) -> Result<T> {
+        let token = self.token();
+        let value = match token.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => token.parse(),
+        };
+        value
+            .ok()
+            .and_then(|v| T::try_from(v).ok())
+            .ok_or_else(|| self.error(&format!("invalid number `{}`", token)))
+    }
+
+    fn signed<T: TryFrom<i64>>(&mut self) -> Result<T> {
+        let token = self.token();
+        token
+            .parse::<i64>()
+            .ok()
+            .and_then(|v| T::try_from(v).ok())
+            .ok_or_else(|| self.error(&format!("invalid number `{}`", token)))
+    }
+
+    fn float(&mut self) -> Result<f64> {
+        if self.eat("bits:") {
+            return self.unsigned().map(f64::from_bits);
+        }
+        let token = self.token();
+        token
+            .parse()
+            .map_err(|_| self.error(&format!("invalid float `{}`", token)))
+    }
+
+    fn boolean(&mut self) -> Result<bool> {
+        let token = self.token();
+        token
+            .parse()
+            .map_err(|_| self.error(&format!("invalid bool `{}`", token)))
+    }
+
+    /// String table index, at most 0x7FFF
+    fn index(&mut self) -> Result<u16> {
+        let index = self.unsigned()?;
+        if index > 0x7FFF {
+            return Err(self.error("string index above 0x7FFF"));
+        }
+        Ok(index)
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        self.expect("\"")?;
+        let mut value = String::new();
+        loop {
+            let mut chars = self.rest.chars();
+            let c = chars
+                .next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.rest = chars.as_str();
+            match c {
+                '"' => return Ok(value),
+                '\\' => value.push(self.escape()?),
+                c => value.push(c),
+            }
+        }
+    }
+
+    /// The character escaped after a backslash
+    fn escape(&mut self) -> Result<char> {
+        let mut chars = self.rest.chars();
+        let c = match chars.next() {
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('u') => {
+                let (hex, rest) = chars
+                    .as_str()
+                    .strip_prefix('{')
+                    .and_then(|r| r.split_once('}'))
+                    .ok_or_else(|| self.error("invalid unicode escape"))?;
+                chars = rest.chars();
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| self.error("invalid unicode escape"))?
+            }
+            _ => return Err(self.error("invalid escape")),
+        };
+        self.rest = chars.as_str();
+        Ok(c)
+    }
+
+    fn string_ref(&mut self) -> Result<StringRef> {
+        if self.eat("ref:") {
+            Ok(StringRef::Ref(self.index()?))
+        } else {
+            self.eat("inline:");
+            let value = self.quoted()?;
+            if value.len() > 0x7FFF {
+                return Err(self.error("inline string longer than 32767 bytes"));
+            }
+            Ok(StringRef::Inline(value))
+        }
+    }
+
+    /// Argument name: a string, or a bare identifier
+    fn name(&mut self) -> Result<StringRef> {
+        self.skip_spaces();
+        if self
+            .rest
+            .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && !self.rest.starts_with("ref:")
+            && !self.rest.starts_with("inline:")
+        {
+            return Ok(StringRef::Inline(self.word()?.to_string()));
+        }
+        self.string_ref()
+    }
+
+    fn thread_ref(&mut self) -> Result<ThreadRef> {
+        if self.eat("ref:") {
+            return Ok(ThreadRef::Ref(self.unsigned()?));
+        }
+        self.expect("inline:")?;
+        let process_koid = self.unsigned()?;
+        self.expect("/")?;
+        let thread_koid = self.unsigned()?;
+        Ok(ThreadRef::Inline {
+            process_koid,
+            thread_koid,
+        })
+    }
+
+    fn argument(&mut self) -> Result<Argument> {
+        let ty = self.word()?;
+        let name = self.name()?;
+        if ty == "null" {
+            return Ok(Argument::Null(name));
+        }
+        self.expect("=")?;
+        Ok(match ty {
+            "int32" => Argument::Int32(name, self.signed()?),
+            "uint32" => Argument::UInt32(name, self.unsigned()?),
+            "int64" => Argument::Int64(name, self.signed()?),
+            "uint64" => Argument::UInt64(name, self.unsigned()?),
+            "float" => Argument::Float(name, self.float()?),
+            "str" => Argument::Str(name, self.string_ref()?),
+            "pointer" => Argument::Pointer(name, self.unsigned()?),
+            "koid" => Argument::KernelObjectId(name, self.unsigned()?),
+            "bool" => Argument::Boolean(name, self.boolean()?),
+            other => return Err(self.error(&format!("unknown argument type `{}`", other))),
+        })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Argument>> {
+        let mut arguments = Vec::new();
+        self.skip_spaces();
+        if !self.eat("args{") {
+            return Ok(arguments);
+        }
+        loop {
+            self.skip_spaces();
+            if self.eat("}") {
+                return Ok(arguments);
+            }
+            if !arguments.is_empty() {
+                self.expect(",")?;
+            }
+            if arguments.len() == 15 {
+                return Err(self.error("more than 15 arguments"));
+            }
+            arguments.push(self.argument()?);
+        }
+    }
+
+    fn event(&mut self) -> Result<EventRecord> {
+        let kind = self.word()?;
+        if matches!(
+            kind,
+            "async_begin" | "async_end" | "async_instant" | "flow_begin" | "flow_end" | "flow_step"
+        ) {
+            return Err(self.error(&format!("`event {}` records have no text form", kind)));
+        }
+
+        let timestamp = self.field("ts", Self::unsigned)?;
+        let thread = self.field("thread", Self::thread_ref)?;
+        let category = self.field("cat", Self::string_ref)?;
+        let name = self.field("name", Self::string_ref)?;
+        Ok(match kind {
+            "instant" => EventRecord::Instant(Instant::new(
+                timestamp,
+                thread,
+                category,
+                name,
+                self.arguments()?,
+            )),
+            "counter" => {
+                let id = self.field("id", Self::unsigned)?;
+                let arguments = self.arguments()?;
+                EventRecord::Counter(Counter::new(
+                    timestamp, thread, category, name, arguments, id,
+                ))
+            }
+            "duration_begin" => EventRecord::DurationBegin(DurationBegin::new(
+                timestamp,
+                thread,
+                category,
+                name,
+                self.arguments()?,
+            )),
+            "duration_end" => EventRecord::DurationEnd(DurationEnd::new(
+                timestamp,
+                thread,
+                category,
+                name,
+                self.arguments()?,
+            )),
+            "duration_complete" => {
+                let end_ts = self.field("end_ts", Self::unsigned)?;
+                let arguments = self.arguments()?;
+                EventRecord::DurationComplete(DurationComplete::new(
+                    timestamp, thread, category, name, arguments, end_ts,
+                ))
+            }
+            other => return Err(self.error(&format!("unknown event type `{}`", other))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sample() -> Archive {
+        let inline = |s: &str| StringRef::Inline(s.to_string());
+        let thread = ThreadRef::Inline {
+            process_koid: 0x1234,
+            thread_koid: 0x5678,
+        };
+        Archive {
+            records: vec![
+                Record::create_magic_number(),
+                Record::create_provider_info(1, "provider \"one\""),
+                Record::create_provider_section(1),
+                Record::create_provider_event(1, 0),
+                Record::create_trace_info(3, [0x12, 0x34, 0x56, 0x78, 0x90]),
+                Record::create_initialization(1_000_000),
+                Record::create_string(1, "db"),
+                Record::create_string(2, "tab\tnew\nline \\ \u{1}"),
+                Record::create_thread(1, 10, 11),
+                Record::create_instant_event(
+                    100,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    inline("started"),
+                    vec![Argument::Int32(inline("status_code"), 200)],
+                ),
+                Record::create_counter_event(
+                    200,
+                    thread,
+                    inline("db"),
+                    StringRef::Ref(2),
+                    vec![
+                        Argument::UInt32(StringRef::Ref(1), 7),
+                        Argument::Int64(inline("two words"), -8),
+                        Argument::UInt64(inline("u"), u64::MAX),
+                    ],
+                    9,
+                ),
+                Record::create_duration_begin_event(
+                    300,
+                    ThreadRef::Ref(1),
+                    inline(""),
+                    inline("query"),
+                    vec![
+                        Argument::Null(inline("nothing")),
+                        Argument::Float(inline("ratio"), 0.1),
+                        Argument::Float(inline("nan"), f64::from_bits(0x7ff8_0000_0000_0001)),
+                        Argument::Float(inline("inf"), f64::NEG_INFINITY),
+                    ],
+                ),
+                Record::create_duration_end_event(
+                    400,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    inline("query"),
+                    vec![
+                        Argument::Str(inline("table"), inline("users")),
+                        Argument::Str(inline("interned"), StringRef::Ref(1)),
+                        Argument::Pointer(inline("buffer"), 0x1000),
+                        Argument::KernelObjectId(inline("process"), 42),
+                        Argument::Boolean(inline("ok"), true),
+                    ],
+                ),
+                Record::create_duration_complete_event(
+                    500,
+                    thread,
+                    StringRef::Ref(1),
+                    inline("query"),
+                    Vec::new(),
+                    600,
+                ),
+            ],
+        }
+    }
+
+    fn to_bytes(archive: &Archive) -> Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        archive.write(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    #[test]
+    fn test_binary_round_trip() -> Result<()> {
+        let bytes = to_bytes(&sample())?;
+        let text = Archive::read(Cursor::new(&bytes))?.to_string();
+        let parsed: Archive = text.parse()?;
+        assert_eq!(to_bytes(&parsed)?, bytes);
+        // and printing is stable
+        assert_eq!(parsed.to_string(), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_print() {
+        let text = sample().to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines[0], "magic");
+        assert_eq!(lines[1], r#"provider_info id=1 name="provider \"one\"""#);
+        assert_eq!(lines[4], "trace_info type=3 data=0x9078563412");
+        assert_eq!(
+            lines[7],
+            r#"string index=2 value="tab\tnew\nline \\ \u{1}""#
+        );
+        assert_eq!(
+            lines[9],
+            r#"event instant ts=100 thread=ref:1 cat=ref:1 name="started" args{int32 status_code=200}"#
+        );
+        assert_eq!(
+            lines[10],
+            r#"event counter ts=200 thread=inline:4660/22136 cat="db" name=ref:2 id=9 args{uint32 ref:1=7, int64 "two words"=-8, uint64 u=18446744073709551615}"#
+        );
+        assert_eq!(
+            lines[11],
+            r#"event duration_begin ts=300 thread=ref:1 cat="" name="query" args{null nothing, float ratio=0.1, float nan=bits:0x7ff8000000000001, float inf=-inf}"#
+        );
+        assert_eq!(
+            lines[13],
+            r#"event duration_complete ts=500 thread=inline:4660/22136 cat=ref:1 name="query" end_ts=600"#
+        );
+    }
+
+    #[test]
+    fn test_payloadless_records_are_not_printed() {
+        assert_eq!(
+            Record::create_magic_number().to_text().ok(),
+            Some("magic".to_string())
+        );
+        let records = [Record::Log, Record::Event(EventRecord::FlowStep)];
+        for record in &records {
+            assert!(matches!(record.to_text(), Err(FtfError::Unimplemented(_))));
+        }
+        // printed as comments, so a listing still parses
+        assert_eq!(
+            Record::Event(EventRecord::FlowStep).to_string(),
+            "# event flow_step record without its payload"
+        );
+        assert_eq!(
+            Archive {
+                records: vec![Record::Log, Record::create_magic_number()]
+            }
+            .to_string()
+            .parse::<Archive>()
+            .ok()
+            .map(|a| a.records),
+            Some(vec![Record::create_magic_number()])
+        );
+
+        let mut archive = sample();
+        assert_eq!(archive.to_text().ok(), Some(archive.to_string()));
+        archive.records.insert(2, Record::Blob);
+        match archive.to_text() {
+            Err(FtfError::Unimplemented(message)) => {
+                assert_eq!(message, "record 3: text form of `blob` records")
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_hand_written() -> Result<()> {
+        let archive: Archive = r#"
+            # a comment
+            magic
+
+            event instant ts=100 thread=ref:1 cat="category" name=inline:"started" args{int32 status_code=200}
+            event counter ts=0x10 thread=inline:1/2 cat=ref:3 name="c" id=1 args{ pointer p=4096 ,bool b=false }
+        "#
+        .parse()?;
+        assert_eq!(
+            archive.records,
+            vec![
+                Record::create_magic_number(),
+                Record::create_instant_event(
+                    100,
+                    ThreadRef::Ref(1),
+                    StringRef::Inline("category".to_string()),
+                    StringRef::Inline("started".to_string()),
+                    vec![("status_code", 200).into()],
+                ),
+                Record::create_counter_event(
+                    16,
+                    ThreadRef::Inline {
+                        process_koid: 1,
+                        thread_koid: 2
+                    },
+                    StringRef::Ref(3),
+                    StringRef::Inline("c".to_string()),
+                    vec![
+                        Argument::Pointer(StringRef::Inline("p".to_string()), 0x1000),
+                        ("b", false).into(),
+                    ],
+                    1,
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| match text.parse::<Archive>() {
+            Err(FtfError::ParseError(message)) => message,
+            other => panic!("expected a parse error, got {:?}", other.map(|a| a.records)),
+        };
+        assert_eq!(
+            error("magic\nmagic extra"),
+            "line 2: unexpected input at `extra`"
+        );
+        assert_eq!(
+            error("unknown"),
+            "line 1: unknown record `unknown` at end of line"
+        );
+        assert!(error("string index=32768 value=\"x\"").contains("string index above 0x7FFF"));
+        assert!(error("string index=1 value=\"x").contains("unterminated string"));
+        assert!(error("trace_info type=1 data=0x10000000000").contains("too wide"));
+        assert!(
+            error("event instant ts=1 thread=ref:256 cat=\"\" name=\"\"")
+                .contains("invalid number `256`")
+        );
+        assert!(error("event instant ts=1 cat=\"\"").contains("expected `thread`"));
+        assert!(
+            error("event instant ts=1 thread=ref:1 cat=\"\" name=\"\" args{int8 x=1}")
+                .contains("unknown argument type `int8`")
+        );
+    }
+
+    #[test]
+    fn test_parse_refuses_what_cannot_be_written() {
+        let error = |text: &str| match text.parse::<Record>() {
+            Err(FtfError::ParseError(message)) => message,
+            other => panic!("expected a parse error, got {:?}", other),
+        };
+        assert!(error("log").contains("`log` records have no text form"));
+        assert!(error("blob").contains("`blob` records have no text form"));
+        assert!(
+            error("event async_begin").contains("`event async_begin` records have no text form")
+        );
+        assert!(error("string index=0 value=\"x\"").contains("empty string"));
+
+        let event = |args: &str| {
+            format!(
+                "event instant ts=1 thread=ref:1 cat=ref:1 name=ref:2 {}",
+                args
+            )
+        };
+        let arguments: Vec<_> = (0..16).map(|i| format!("null a{}", i)).collect();
+        assert!(error(&event(&format!("args{{{}}}", arguments.join(", "))))
+            .contains("more than 15 arguments"));
+        assert!(event(&format!("args{{{}}}", arguments[..15].join(", ")))
+            .parse::<Record>()
+            .is_ok());
+
+        let long = "x".repeat(0x8000);
+        assert!(error(&format!("string index=1 value=\"{}\"", long)).contains("longer than 32767"));
+        assert!(error(&event(&format!("args{{str s=\"{}\"}}", long))).contains("longer than 32767"));
+        // each string fits, but not both in one record
+        let half = "x".repeat(0x4000);
+        assert!(error(&event(&format!(
+            "args{{str a=\"{}\", str b=\"{}\"}}",
+            half, half
+        )))
+        .contains("record too large"));
+    }
+}
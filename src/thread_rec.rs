@@ -1,22 +1,31 @@
-use crate::{extract_bits, header::CustomField, wordutils::read_u64_word, RecordHeader, Result};
+use crate::{
+    extract_bits, header::CustomField, preserved::PreservedHeader, wordutils::read_u64_word,
+    RecordHeader, Result,
+};
 use std::io::{Read, Write};
 
 /// Thread record. Represents a Thread interned
 /// in the provider's thread table with the assosciated
 /// index
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ThreadRecord {
     index: u8,
     process_koid: u64,
     thread_koid: u64,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) preserved: PreservedHeader,
 }
 
 impl ThreadRecord {
-    pub(crate) fn new(index: u8, process_koid: u64, thread_koid: u64) -> Self {
+    /// Thread record registering a (process, thread) KOID pair at `index`
+    /// (index 0 is reserved)
+    pub fn new(index: u8, process_koid: u64, thread_koid: u64) -> Self {
         ThreadRecord {
             index,
             process_koid,
             thread_koid,
+            preserved: PreservedHeader::default(),
         }
     }
 
@@ -35,28 +44,45 @@ impl ThreadRecord {
         self.thread_koid
     }
 
+    /// Set the index in the provider's thread table
+    pub fn set_index(&mut self, index: u8) {
+        self.index = index;
+        self.preserved.clear();
+    }
+
+    /// Set the process ID
+    pub fn set_process_koid(&mut self, process_koid: u64) {
+        self.process_koid = process_koid;
+        self.preserved.clear();
+    }
+
+    /// Set the thread ID
+    pub fn set_thread_koid(&mut self, thread_koid: u64) {
+        self.thread_koid = thread_koid;
+        self.preserved.clear();
+    }
+
     pub(super) fn parse<U: Read>(reader: &mut U, header: RecordHeader) -> Result<Self> {
         let index = extract_bits!(header.value, 16, 23) as u8;
 
         let process_koid = read_u64_word(reader)?;
         let thread_koid = read_u64_word(reader)?;
 
-        Ok(ThreadRecord {
-            index,
-            process_koid,
-            thread_koid,
-        })
+        Ok(ThreadRecord::new(index, process_koid, thread_koid))
     }
 
     pub(super) fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let header = RecordHeader::build(
-            crate::header::RecordType::Thread,
-            3,
-            &[CustomField {
-                width: 8,
-                value: self.index as u64,
-            }],
-        )?;
+        let header = match self.preserved.get() {
+            Some(value) => RecordHeader::new(value),
+            None => RecordHeader::build(
+                crate::header::RecordType::Thread,
+                3,
+                &[CustomField {
+                    width: 8,
+                    value: self.index as u64,
+                }],
+            )?,
+        };
 
         writer.write_all(&header.value.to_le_bytes())?;
         writer.write_all(&self.process_koid.to_le_bytes())?;
@@ -112,6 +138,7 @@ mod tests {
             index: 5,
             process_koid: 12345,
             thread_koid: 67890,
+            preserved: PreservedHeader::default(),
         };
 
         // Write it to a buffer
@@ -160,6 +187,7 @@ mod tests {
             index: 255, // Max u8 value
             process_koid: u64::MAX - 10,
             thread_koid: u64::MAX,
+            preserved: PreservedHeader::default(),
         };
 
         // Write it to a buffer
@@ -190,6 +218,7 @@ mod tests {
             index: 42,
             process_koid: 987654321,
             thread_koid: 123456789,
+            preserved: PreservedHeader::default(),
         };
 
         // Write it to a buffer
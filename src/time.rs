@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::{FtfError, InitializationRecord, MetadataRecord, Record, Result};
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Converts raw tick timestamps into time, at a fixed number of ticks per second.
+/// Conversions split ticks into whole seconds and a remainder before scaling,
+/// so they neither overflow nor lose precision for non-round tick rates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timebase {
+    ticks_per_second: u64,
+}
+
+impl Default for Timebase {
+    /// Nanosecond ticks, the rate assumed when a trace has no Initialization record
+    fn default() -> Self {
+        Self {
+            ticks_per_second: NANOS_PER_SEC,
+        }
+    }
+}
+
+impl Timebase {
+    /// Timebase with `ticks_per_second` ticks in a second.
+    /// Fails if `ticks_per_second` is zero.
+    pub fn new(ticks_per_second: u64) -> Result<Self> {
+        if ticks_per_second == 0 {
+            return Err(FtfError::InvalidConfiguration(
+                "ticks per second must be non-zero".to_string(),
+            ));
+        }
+        Ok(Self { ticks_per_second })
+    }
+
+    /// Timebase of an Initialization record
+    pub fn from_initialization(record: &InitializationRecord) -> Result<Self> {
+        Self::new(record.ticks_per_second())
+    }
+
+    /// Number of ticks in a second
+    pub fn ticks_per_second(&self) -> u64 {
+        self.ticks_per_second
+    }
+
+    /// Time since tick 0, rounded down to the nanosecond
+    pub fn to_duration(&self, ticks: u64) -> Duration {
+        let secs = ticks / self.ticks_per_second;
+        let rem = ticks % self.ticks_per_second;
+        // rem < ticks_per_second, so the product fits easily in 128 bits
+        let nanos = rem as u128 * NANOS_PER_SEC as u128 / self.ticks_per_second as u128;
+        Duration::new(secs, nanos as u32)
+    }
+
+    /// Nanoseconds since tick 0, rounded down
+    pub fn to_nanos(&self, ticks: u64) -> u128 {
+        ticks as u128 * NANOS_PER_SEC as u128 / self.ticks_per_second as u128
+    }
+
+    /// Seconds since tick 0
+    pub fn to_secs_f64(&self, ticks: u64) -> f64 {
+        let secs = ticks / self.ticks_per_second;
+        let rem = ticks % self.ticks_per_second;
+        secs as f64 + rem as f64 / self.ticks_per_second as f64
+    }
+
+    /// Time from `start` to `end`, or zero if `end` is before `start`
+    pub fn duration_between(&self, start: u64, end: u64) -> Duration {
+        self.to_duration(end.saturating_sub(start))
+    }
+
+    /// Number of ticks in `duration`, rounded down.
+    /// Saturates at `u64::MAX`.
+    pub fn to_ticks(&self, duration: Duration) -> u64 {
+        let ticks = duration.as_secs() as u128 * self.ticks_per_second as u128
+            + duration.subsec_nanos() as u128 * self.ticks_per_second as u128
+                / NANOS_PER_SEC as u128;
+        u64::try_from(ticks).unwrap_or(u64::MAX)
+    }
+}
+
+/// Follows the Initialization records of a trace, per provider, so raw
+/// timestamps can be converted with the timebase in effect where they appear.
+/// Feed it every record in order; records after an Initialization record
+/// of their provider use its rate, even if it changes mid-trace.
+#[derive(Debug, Clone, Default)]
+pub struct TimebaseTracker {
+    timebases: HashMap<Option<u32>, Timebase>,
+    // None until the trace has a ProviderInfo or ProviderSection
+    current_provider: Option<u32>,
+}
+
+impl TimebaseTracker {
+    /// Create a tracker for the start of a trace
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tracker with the next record of the trace.
+    /// Fails on an Initialization record with zero ticks per second,
+    /// which leaves the previous timebase in effect.
+    pub fn observe(&mut self, record: &Record) -> Result<()> {
+        match record {
+            Record::Metadata(MetadataRecord::MagicNumber) => *self = Self::default(),
+            Record::Metadata(MetadataRecord::ProviderInfo(info)) => {
+                self.current_provider = Some(info.provider_id());
+            }
+            Record::Metadata(MetadataRecord::ProviderSection(section)) => {
+                self.current_provider = Some(section.provider_id());
+            }
+            Record::Initialization(init) => {
+                self.timebases
+                    .insert(self.current_provider, Timebase::from_initialization(init)?);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Provider records are currently attributed to
+    pub fn current_provider(&self) -> Option<u32> {
+        self.current_provider
+    }
+
+    /// Timebase of the current provider. A provider without an Initialization
+    /// record of its own uses the one written before any provider, if any,
+    /// and nanoseconds otherwise.
+    pub fn timebase(&self) -> Timebase {
+        self.timebases
+            .get(&self.current_provider)
+            .or_else(|| self.timebases.get(&None))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_non_round_rate() -> Result<()> {
+        // a typical TSC rate
+        let timebase = Timebase::new(2_899_999_999)?;
+        assert_eq!(
+            timebase.to_duration(2_899_999_999 * 3),
+            Duration::from_secs(3)
+        );
+        assert_eq!(
+            timebase.to_duration(u64::MAX),
+            Duration::new(6_360_946_234, 507_068_223)
+        );
+        assert_eq!(timebase.to_nanos(u64::MAX), 6_360_946_234_507_068_223);
+        assert_eq!(timebase.to_ticks(Duration::from_secs(3)), 2_899_999_999 * 3);
+        assert!((timebase.to_secs_f64(1_449_999_999) - 0.5).abs() < 1e-9);
+        assert_eq!(timebase.duration_between(10, 5), Duration::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_ticks_saturates() -> Result<()> {
+        let timebase = Timebase::new(u64::MAX)?;
+        assert_eq!(timebase.to_ticks(Duration::from_secs(2)), u64::MAX);
+        assert!(Timebase::new(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tracker_per_provider() -> Result<()> {
+        let mut tracker = TimebaseTracker::new();
+        for record in [
+            Record::create_magic_number(),
+            Record::create_initialization(1000),
+            Record::create_provider_info(1, "first"),
+            Record::create_provider_info(2, "second"),
+            Record::create_initialization(1_000_000),
+        ] {
+            tracker.observe(&record)?;
+        }
+        assert_eq!(tracker.timebase().ticks_per_second(), 1_000_000);
+
+        tracker.observe(&Record::create_provider_section(1))?;
+        assert_eq!(tracker.timebase().ticks_per_second(), 1000);
+
+        // the rate changes mid-trace
+        tracker.observe(&Record::create_initialization(10))?;
+        assert_eq!(
+            tracker.timebase().to_duration(5),
+            Duration::from_millis(500)
+        );
+
+        assert!(tracker.observe(&Record::create_initialization(0)).is_err());
+        assert_eq!(tracker.timebase().ticks_per_second(), 10);
+
+        tracker.observe(&Record::create_magic_number())?;
+        assert_eq!(tracker.timebase(), Timebase::default());
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{LazyLock, Mutex, PoisonError};
+
+use crate::StringTable;
+
+// index of a string not registered yet, and of one the registry had no room for
+const UNREGISTERED: u16 = 0;
+const FULL: u16 = u16::MAX;
+
+/// Number of strings which can be registered. Static strings take indices
+/// down from the top of the string table, leaving the rest to interning.
+const MAX_STATIC_STRINGS: usize = 4096;
+
+#[derive(Default)]
+struct Registry {
+    values: Vec<&'static str>,
+    indices: HashMap<&'static str, u16>,
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+/// A string with a string-table index fixed for the life of the process,
+/// usually declared with `trace_str!` as a static.
+/// Every writer defines the registered strings when it starts, so writing
+/// one is a `StringRef::Ref` with no string-table lookup.
+///
+/// ```rust
+/// use ftfrs::{trace_str, TraceStr};
+///
+/// static QUERY: TraceStr = trace_str!("db.query");
+/// assert_eq!(QUERY.index(), QUERY.index());
+/// ```
+#[derive(Debug)]
+pub struct TraceStr {
+    value: &'static str,
+    index: AtomicU16,
+}
+
+impl TraceStr {
+    /// An unregistered string; `trace_str!` is a shorthand for this
+    pub const fn new(value: &'static str) -> Self {
+        Self {
+            value,
+            index: AtomicU16::new(UNREGISTERED),
+        }
+    }
+
+    /// The string
+    pub fn as_str(&self) -> &'static str {
+        self.value
+    }
+
+    /// Index of the string, registering it on the first call.
+    /// Strings with the same value share an index. None once
+    /// 4096 distinct strings are registered.
+    #[inline]
+    pub fn index(&self) -> Option<u16> {
+        match self.index.load(Ordering::Relaxed) {
+            UNREGISTERED => self.register(),
+            FULL => None,
+            index => Some(index),
+        }
+    }
+
+    /// Register the string now, so writers created afterwards define it
+    /// up front rather than on first use
+    pub fn register(&self) -> Option<u16> {
+        let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+        let index = match registry.indices.get(self.value) {
+            Some(index) => *index,
+            None if registry.values.len() < MAX_STATIC_STRINGS => {
+                let index = StringTable::MAX_INDEX - registry.values.len() as u16;
+                registry.values.push(self.value);
+                registry.indices.insert(self.value, index);
+                index
+            }
+            None => FULL,
+        };
+        self.index.store(index, Ordering::Relaxed);
+        (index != FULL).then_some(index)
+    }
+}
+
+/// Declare a `TraceStr`, typically as a static
+///
+/// ```rust
+/// static DB: ftfrs::TraceStr = ftfrs::trace_str!("db");
+/// ```
+#[macro_export]
+macro_rules! trace_str {
+    ($value:expr) => {
+        $crate::TraceStr::new($value)
+    };
+}
+
+/// Position of a static string's index in the registry
+pub(crate) fn position(index: u16) -> usize {
+    (StringTable::MAX_INDEX - index) as usize
+}
+
+/// Registered strings from position `from` on, with their indices
+pub(crate) fn registered_since(from: usize) -> Vec<(u16, &'static str)> {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    registry.values[from.min(registry.values.len())..]
+        .iter()
+        .enumerate()
+        .map(|(i, value)| (StringTable::MAX_INDEX - (from + i) as u16, *value))
+        .collect()
+}
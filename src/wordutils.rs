@@ -6,6 +6,8 @@ pub fn read_u64_word<U: Read>(reader: &mut U) -> Result<u64> {
     reader.read_exact(&mut buf)?;
     Ok(u64::from_ne_bytes(buf))
 }
+// `is_multiple_of` needs a newer toolchain than the crate otherwise does
+#[allow(clippy::manual_is_multiple_of)]
 pub fn read_aligned_str<U: Read>(reader: &mut U, len: usize) -> Result<String> {
     let bytes_to_read = len.div_ceil(8) * 8;
     let mut buf = vec![0; bytes_to_read];
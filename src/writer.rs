@@ -0,0 +1,1209 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::category::{Category, CategoryRegistry};
+use crate::clock::{MonotonicClock, TraceClock};
+use crate::hooks::{self, HookTarget};
+use crate::sampling::Sampler;
+use crate::span::EventBuilder;
+use crate::tables::{StringTable, ThreadTable};
+use crate::trace_str::{self, TraceStr};
+use crate::{
+    Argument, Event, EventRecord, FtfError, MetadataRecord, Record, Result, StringRecord,
+    StringRef, ThreadRecord, ThreadRef,
+};
+
+/// Where a `ThreadWriter` looks up interned strings and threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Interning {
+    /// Every lookup goes through the table shared by all threads
+    #[default]
+    Shared,
+    /// Each thread keeps a private cache in front of the shared table,
+    /// and only takes the shared lock on a cache miss
+    PerThread,
+}
+
+/// How often a `TraceWriter` re-emits its string and thread tables.
+/// Every provider's ProviderSection, Initialization, String and Thread
+/// records are written again at the start of a checkpoint, so the rest of
+/// the stream from there on can be decoded without anything written before
+/// it.
+/// Checkpoints are only taken when the writer flushes, so the actual
+/// spacing is rounded up to the flush granularity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checkpoint {
+    /// Checkpoint once at least this many bytes were written since the last one
+    Bytes(u64),
+    /// Checkpoint once at least this much time passed since the last one
+    Interval(Duration),
+}
+
+type Buffer = Arc<Mutex<Vec<u8>>>;
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Process and thread koids used for events of the calling thread,
+/// unless the `ThreadWriter` or event says otherwise.
+/// Threads are numbered in the order they first write an event.
+fn current_thread() -> (u64, u64) {
+    static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD_KOID: u64 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+    }
+    (std::process::id() as u64, THREAD_KOID.with(|koid| *koid))
+}
+
+#[derive(Default)]
+struct Tables {
+    strings: StringTable,
+    threads: ThreadTable,
+    // static string indices already taken by an interned string
+    shadowed: BTreeSet<u16>,
+}
+
+impl Tables {
+    fn resolve_string<'a>(&'a self, s: &'a StringRef) -> Option<&'a str> {
+        s.resolve(&self.strings)
+    }
+}
+
+/// State of one provider: its string and thread tables,
+/// and the buffers of the `ThreadWriter`s writing its records
+struct Provider {
+    // None for a writer without providers, which never writes ProviderSection records
+    id: Option<u32>,
+    tables: RwLock<Tables>,
+    // String and Thread records, written ahead of the thread buffers
+    // on every flush so that refs always follow their definitions
+    definitions: Mutex<Vec<u8>>,
+    buffers: Mutex<Vec<Buffer>>,
+    // records written by the writer itself, after the thread buffers
+    reports: Mutex<Vec<u8>>,
+    // number of registered static strings defined in the tables
+    statics: AtomicUsize,
+    has_shadowed: AtomicBool,
+}
+
+impl Provider {
+    fn new(id: Option<u32>) -> Self {
+        let provider = Self {
+            id,
+            tables: RwLock::new(Tables::default()),
+            definitions: Mutex::new(Vec::new()),
+            buffers: Mutex::new(Vec::new()),
+            reports: Mutex::new(Vec::new()),
+            statics: AtomicUsize::new(0),
+            has_shadowed: AtomicBool::new(false),
+        };
+        provider.define_statics();
+        provider
+    }
+
+    /// Define the static strings registered since the last call
+    fn define_statics(&self) {
+        let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+        let defined = self.statics.load(Ordering::Relaxed);
+        let strings = trace_str::registered_since(defined);
+        if strings.is_empty() {
+            return;
+        }
+
+        let mut definitions = lock(&self.definitions);
+        for (index, value) in &strings {
+            match tables.strings.get(*index) {
+                None => {
+                    tables.strings.insert(*index, value.to_string());
+                    StringRecord::new(*index, value.to_string())
+                        .write(&mut *definitions)
+                        .expect("writing to a Vec cannot fail");
+                }
+                Some(existing) if existing == *value => (),
+                // interning filled the table up to the static indices
+                Some(_) => {
+                    tables.shadowed.insert(*index);
+                    self.has_shadowed.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        // published while the table is still locked, like interned strings
+        self.statics
+            .store(defined + strings.len(), Ordering::Release);
+    }
+
+    fn static_string(&self, value: &TraceStr) -> StringRef {
+        let Some(index) = value.index() else {
+            return StringRef::Inline(value.as_str().to_string());
+        };
+        if trace_str::position(index) >= self.statics.load(Ordering::Acquire) {
+            self.define_statics();
+        }
+        if self.has_shadowed.load(Ordering::Relaxed)
+            && self
+                .tables
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .shadowed
+                .contains(&index)
+        {
+            return StringRef::Inline(value.as_str().to_string());
+        }
+        StringRef::Ref(index)
+    }
+
+    fn intern_string(&self, value: &str) -> Option<u16> {
+        if let Some(index) = self
+            .tables
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .strings
+            .index_of(value)
+        {
+            return Some(index);
+        }
+
+        let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+        let (index, is_new) = tables.strings.intern(value)?;
+        if is_new {
+            // written while the table is still locked, so no other thread
+            // can see the index before its definition is queued
+            StringRecord::new(index, value.to_string())
+                .write(&mut *lock(&self.definitions))
+                .expect("writing to a Vec cannot fail");
+        }
+        Some(index)
+    }
+
+    fn intern_thread(&self, process_koid: u64, thread_koid: u64) -> Option<u8> {
+        if let Some(index) = self
+            .tables
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .threads
+            .index_of(process_koid, thread_koid)
+        {
+            return Some(index);
+        }
+
+        let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+        let (index, is_new) = tables.threads.intern(process_koid, thread_koid)?;
+        if is_new {
+            ThreadRecord::new(index, process_koid, thread_koid)
+                .write(&mut *lock(&self.definitions))
+                .expect("writing to a Vec cannot fail");
+        }
+        Some(index)
+    }
+
+    /// Drain the thread buffers and queued definitions
+    fn take(&self) -> (Vec<u8>, Vec<Vec<u8>>) {
+        // Take the thread buffers before the definitions: anything referenced
+        // by an event already in a buffer was queued as a definition before it.
+        let mut chunks = Vec::new();
+        lock(&self.buffers).retain(|b| {
+            // the writer holds the only reference once its ThreadWriter is dropped;
+            // check before draining, since a live one may still append afterwards
+            let alive = Arc::strong_count(b) > 1;
+            chunks.push(std::mem::take(&mut *lock(b)));
+            alive
+        });
+        chunks.push(std::mem::take(&mut *lock(&self.reports)));
+        chunks.retain(|c| !c.is_empty());
+
+        let definitions = std::mem::take(&mut *lock(&self.definitions));
+        (definitions, chunks)
+    }
+
+    fn write_tables(&self, writer: &mut Vec<u8>) -> Result<()> {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        for record in tables.strings.records().chain(tables.threads.records()) {
+            record.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+struct Sink {
+    writer: Box<dyn Write + Send>,
+    // provider the records last written are attributed to
+    active_provider: Option<u32>,
+    checkpoint: Option<Checkpoint>,
+    bytes_since_checkpoint: u64,
+    last_checkpoint: Instant,
+    // Initialization records of the preamble, by the provider they were
+    // written for, re-emitted at every checkpoint
+    initializations: Vec<(Option<u32>, Record)>,
+}
+
+impl Sink {
+    fn checkpoint_due(&self) -> bool {
+        match self.checkpoint {
+            None => false,
+            Some(Checkpoint::Bytes(n)) => self.bytes_since_checkpoint >= n,
+            Some(Checkpoint::Interval(d)) => self.last_checkpoint.elapsed() >= d,
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        self.writer.write_all(bytes)?;
+        self.bytes_since_checkpoint += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Start a checkpoint of `provider`: its section, even if it is already
+    /// active, so a reader starting here knows who the records belong to,
+    /// and its Initialization record, or the one of the whole trace
+    fn start_checkpoint(&mut self, provider: Option<u32>) -> Result<()> {
+        let mut bytes = Vec::new();
+        if let Some(id) = provider {
+            Record::create_provider_section(id).write(&mut bytes)?;
+            self.active_provider = Some(id);
+        }
+        let initialization = self
+            .initializations
+            .iter()
+            .find(|(p, _)| *p == provider)
+            .or_else(|| self.initializations.iter().find(|(p, _)| p.is_none()));
+        if let Some((_, record)) = initialization {
+            record.write(&mut bytes)?;
+        }
+        self.write_all(&bytes)
+    }
+
+    fn switch_provider(&mut self, provider: Option<u32>) -> Result<()> {
+        if let Some(id) = provider {
+            if self.active_provider != Some(id) {
+                let mut section = Vec::new();
+                Record::create_provider_section(id).write(&mut section)?;
+                self.write_all(&section)?;
+                self.active_provider = Some(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Shared {
+    sink: Mutex<Sink>,
+    providers: Vec<Arc<Provider>>,
+    interning: Interning,
+    categories: CategoryRegistry,
+    sampler: Sampler,
+    clock: Arc<dyn TraceClock>,
+    // set when the writer is finished, wakes the background flusher
+    stop: Mutex<bool>,
+    stop_signal: Condvar,
+    // first error hit by the background flusher
+    error: Mutex<Option<FtfError>>,
+}
+
+impl Shared {
+    /// Write a counter event for every event the sampler dropped since the
+    /// last flush, with the number of dropped events as an argument
+    fn report_drops(&self) -> Result<()> {
+        for report in self.sampler.take_drops() {
+            let Some(provider) = self.providers.iter().find(|p| p.id == report.provider) else {
+                continue;
+            };
+            let dropped = match provider.intern_string("dropped") {
+                Some(index) => StringRef::Ref(index),
+                None => StringRef::Inline("dropped".to_string()),
+            };
+            Record::create_counter_event(
+                self.clock.now(),
+                report.thread,
+                report.category,
+                report.name,
+                vec![Argument::UInt64(dropped, report.dropped)],
+                report.counter_id,
+            )
+            .write(&mut *lock(&provider.reports))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.flush_sink(lock(&self.sink))
+    }
+
+    fn flush_sink(&self, mut sink: MutexGuard<'_, Sink>) -> Result<()> {
+        self.report_drops()?;
+
+        let taken: Vec<_> = self.providers.iter().map(|p| p.take()).collect();
+        let checkpoint = sink.checkpoint_due()
+            && taken
+                .iter()
+                .any(|(defs, chunks)| !defs.is_empty() || !chunks.is_empty());
+
+        if checkpoint {
+            // the tables hold everything in the queued definitions
+            // too, so write them instead
+            for provider in &self.providers {
+                let mut tables = Vec::new();
+                provider.write_tables(&mut tables)?;
+                sink.start_checkpoint(provider.id)?;
+                sink.writer.write_all(&tables)?;
+            }
+            sink.bytes_since_checkpoint = 0;
+            sink.last_checkpoint = Instant::now();
+        }
+
+        for (provider, (definitions, chunks)) in self.providers.iter().zip(&taken) {
+            if (checkpoint || definitions.is_empty()) && chunks.is_empty() {
+                continue;
+            }
+            sink.switch_provider(provider.id)?;
+            if !checkpoint {
+                sink.write_all(definitions)?;
+            }
+            for chunk in chunks {
+                sink.write_all(chunk)?;
+            }
+        }
+        sink.writer.flush()?;
+        Ok(())
+    }
+
+    fn take_error(&self) -> Result<()> {
+        match lock(&self.error).take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl HookTarget for Shared {
+    fn record_panic(&self, message: &str, location: &str) {
+        let (process_koid, thread_koid) = current_thread();
+        let record = Record::create_instant_event(
+            self.clock.now(),
+            ThreadRef::Inline {
+                process_koid,
+                thread_koid,
+            },
+            StringRef::Inline("ftfrs".to_string()),
+            StringRef::Inline("panic".to_string()),
+            vec![
+                Argument::Str(
+                    StringRef::Inline("message".to_string()),
+                    StringRef::Inline(message.to_string()),
+                ),
+                Argument::Str(
+                    StringRef::Inline("location".to_string()),
+                    StringRef::Inline(location.to_string()),
+                ),
+            ],
+        );
+        // the panic may have happened with the lock held
+        if let Some(Ok(mut reports)) = self.providers.first().map(|p| p.reports.try_lock()) {
+            let _ = record.write(&mut *reports);
+        }
+    }
+
+    fn flush_now(&self) {
+        // the panic may have happened with the lock held, so only wait a
+        // little while in case another thread is flushing
+        for _ in 0..100 {
+            match self.sink.try_lock() {
+                Ok(sink) => {
+                    let _ = self.flush_sink(sink);
+                    return;
+                }
+                Err(TryLockError::Poisoned(e)) => {
+                    let _ = self.flush_sink(e.into_inner());
+                    return;
+                }
+                Err(TryLockError::WouldBlock) => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+    }
+}
+
+/// Writes a single trace from many threads.
+/// Each thread encodes records into its own buffer through a
+/// `ThreadWriter`, and the buffers are merged into the sink on `flush`,
+/// either explicitly or by a background flusher. Records from the same
+/// `ThreadWriter` keep their relative order in the output, and String and
+/// Thread records are always written before the events referring to them.
+pub struct TraceWriter {
+    shared: Arc<Shared>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl TraceWriter {
+    /// Create a writer over `sink`. The magic number record
+    /// is written to the sink immediately.
+    /// Events built with `ThreadWriter::event` are timestamped with the
+    /// monotonic clock, in nanoseconds, the rate assumed by readers
+    /// without an Initialization record.
+    /// * sink: where the merged trace is written
+    /// * interning: how `ThreadWriter`s look up interned strings and threads
+    pub fn new<W: Write + Send + 'static>(sink: W, interning: Interning) -> Result<Self> {
+        Self::with_preamble(
+            sink,
+            interning,
+            Arc::new(MonotonicClock::new()),
+            &[Record::create_magic_number()],
+            &[None],
+        )
+    }
+
+    /// Create a writer which writes `preamble` to the sink immediately,
+    /// and keeps separate tables for each of `providers`
+    pub(crate) fn with_preamble<W: Write + Send + 'static>(
+        mut sink: W,
+        interning: Interning,
+        clock: Arc<dyn TraceClock>,
+        preamble: &[Record],
+        providers: &[Option<u32>],
+    ) -> Result<Self> {
+        let mut active_provider = None;
+        let mut initializations = Vec::new();
+        for record in preamble {
+            record.write(&mut sink)?;
+            match record {
+                Record::Metadata(MetadataRecord::ProviderInfo(info)) => {
+                    active_provider = Some(info.provider_id());
+                }
+                Record::Initialization(_) => {
+                    initializations.push((active_provider, record.clone()));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            shared: Arc::new(Shared {
+                sink: Mutex::new(Sink {
+                    writer: Box::new(sink),
+                    active_provider,
+                    checkpoint: None,
+                    bytes_since_checkpoint: 0,
+                    last_checkpoint: Instant::now(),
+                    initializations,
+                }),
+                providers: providers
+                    .iter()
+                    .map(|id| Arc::new(Provider::new(*id)))
+                    .collect(),
+                interning,
+                categories: CategoryRegistry::new(),
+                sampler: Sampler::default(),
+                clock,
+                stop: Mutex::new(false),
+                stop_signal: Condvar::new(),
+                error: Mutex::new(None),
+            }),
+            flusher: None,
+        })
+    }
+
+    /// Create a handle for writing records from the current thread.
+    /// Handles are cheap, but each one owns a buffer, so create one per
+    /// thread rather than one per record.
+    pub fn thread_writer(&self) -> ThreadWriter {
+        self.provider_writer(0, false)
+    }
+
+    /// Create a handle writing records of the `index`th provider.
+    /// A managed handle refuses records which the writer itself is
+    /// responsible for (magic number, initialization, provider metadata).
+    pub(crate) fn provider_writer(&self, index: usize, managed: bool) -> ThreadWriter {
+        let provider = self.shared.providers[index].clone();
+        let buffer = Buffer::default();
+        lock(&provider.buffers).push(buffer.clone());
+
+        ThreadWriter {
+            shared: self.shared.clone(),
+            provider,
+            buffer,
+            managed,
+            default_thread: None,
+            strings: HashMap::new(),
+            threads: HashMap::new(),
+            static_categories: Vec::new(),
+        }
+    }
+
+    /// Categories of events this writer traces. Events of disabled
+    /// categories are dropped by `ThreadWriter`s before they are encoded.
+    pub fn categories(&self) -> &CategoryRegistry {
+        &self.shared.categories
+    }
+
+    /// Flush this writer if the process panics or exits, and write an
+    /// instant event describing the panic, with the panic message and location
+    /// as arguments. Installs the panic hook and at-exit handler the first
+    /// time it is called. The writer is unregistered when it is dropped.
+    ///
+    /// The at-exit handler is only available on unix: elsewhere, only panics
+    /// flush the writer, and the writer must be flushed or finished before
+    /// exiting. Returns whether the writer is also flushed at exit.
+    pub fn register_hooks(&self) -> bool {
+        let shared: Arc<dyn HookTarget> = self.shared.clone();
+        hooks::register(Arc::downgrade(&shared));
+        hooks::FLUSHES_AT_EXIT
+    }
+
+    /// Sampling and rate-limiting rules of this writer
+    pub fn sampler(&self) -> &Sampler {
+        &self.shared.sampler
+    }
+
+    /// Periodically re-emit the string and thread tables,
+    /// or stop doing so with `None`
+    pub fn set_checkpoint(&self, checkpoint: Option<Checkpoint>) {
+        let mut sink = lock(&self.shared.sink);
+        sink.checkpoint = checkpoint;
+        sink.bytes_since_checkpoint = 0;
+        sink.last_checkpoint = Instant::now();
+    }
+
+    /// Start a background thread which flushes the buffers every `interval`.
+    /// Does nothing if a flusher is already running.
+    pub fn spawn_flusher(&mut self, interval: Duration) {
+        if self.flusher.is_some() {
+            return;
+        }
+
+        let shared = self.shared.clone();
+        self.flusher = Some(std::thread::spawn(move || loop {
+            let stop = lock(&shared.stop);
+            let (stop, _) = shared
+                .stop_signal
+                .wait_timeout(stop, interval)
+                .unwrap_or_else(PoisonError::into_inner);
+            if *stop {
+                return;
+            }
+            drop(stop);
+
+            if let Err(e) = shared.flush() {
+                lock(&shared.error).get_or_insert(e);
+            }
+        }));
+    }
+
+    /// Merge every thread's buffered records into the sink.
+    /// Also reports the first error hit by the background flusher, if any.
+    pub fn flush(&self) -> Result<()> {
+        self.shared.take_error()?;
+        self.shared.flush()
+    }
+
+    /// Stop the background flusher, if any, and flush everything
+    /// buffered so far.
+    pub fn finish(mut self) -> Result<()> {
+        self.stop_flusher();
+        self.flush()
+    }
+
+    fn stop_flusher(&mut self) {
+        if let Some(flusher) = self.flusher.take() {
+            *lock(&self.shared.stop) = true;
+            self.shared.stop_signal.notify_all();
+            let _ = flusher.join();
+        }
+    }
+}
+
+impl Drop for TraceWriter {
+    fn drop(&mut self) {
+        self.stop_flusher();
+        let _ = self.shared.flush();
+    }
+}
+
+/// Per-thread handle to a `TraceWriter`.
+/// Records are encoded into a buffer owned by this handle,
+/// which the `TraceWriter` drains when it flushes.
+pub struct ThreadWriter {
+    shared: Arc<Shared>,
+    provider: Arc<Provider>,
+    buffer: Buffer,
+    managed: bool,
+    default_thread: Option<(u64, u64)>,
+    // private caches, only used with Interning::PerThread
+    strings: HashMap<String, u16>,
+    threads: HashMap<(u64, u64), u8>,
+    // enabled bits of static categories, by position in the registry
+    static_categories: Vec<Option<Category>>,
+}
+
+impl ThreadWriter {
+    /// ID of the provider this handle writes records for,
+    /// if the trace has providers
+    pub fn provider_id(&self) -> Option<u32> {
+        self.provider.id
+    }
+
+    /// Current time of the writer's clock, as ticks
+    pub fn now(&self) -> u64 {
+        self.shared.clock.now()
+    }
+
+    /// Attribute events built with `event` to this thread, instead of
+    /// the calling thread
+    pub fn set_default_thread(&mut self, process_koid: u64, thread_koid: u64) {
+        self.default_thread = Some((process_koid, thread_koid));
+    }
+
+    /// Thread events built with `event` are attributed to by default
+    pub(crate) fn default_thread(&mut self) -> ThreadRef {
+        let (process_koid, thread_koid) = self.default_thread.unwrap_or_else(current_thread);
+        self.thread(process_koid, thread_koid)
+    }
+
+    /// Whether events of `category` are traced by the writer
+    pub fn category_enabled(&self, category: &str) -> bool {
+        self.shared.categories.is_enabled(category)
+    }
+
+    /// Whether events of the static `category` are traced by the writer,
+    /// caching its enabled bit so later checks are a single atomic load
+    pub(crate) fn static_category_enabled(&mut self, category: &TraceStr) -> bool {
+        let Some(index) = category.index() else {
+            return self.category_enabled(category.as_str());
+        };
+        let position = trace_str::position(index);
+        if self.static_categories.len() <= position {
+            self.static_categories.resize(position + 1, None);
+        }
+        self.static_categories[position]
+            .get_or_insert_with(|| self.shared.categories.category(category.as_str()))
+            .is_enabled()
+    }
+
+    /// Start building an event, timestamped with `now` and attributed
+    /// to the default thread unless the builder is told otherwise.
+    /// If the category is disabled, the builder does nothing.
+    pub fn event(&mut self, category: &str, name: &str) -> EventBuilder<'_> {
+        EventBuilder::new(self, category, name)
+    }
+
+    /// Start building an event named by static strings, which are written
+    /// as refs without looking them up in the string table
+    pub fn static_event(&mut self, category: &TraceStr, name: &TraceStr) -> EventBuilder<'_> {
+        EventBuilder::new_static(self, category, name)
+    }
+
+    /// A handle sharing this one's buffer, so records written through
+    /// either keep their relative order
+    pub(crate) fn detach(&self) -> ThreadWriter {
+        ThreadWriter {
+            shared: self.shared.clone(),
+            provider: self.provider.clone(),
+            buffer: self.buffer.clone(),
+            managed: self.managed,
+            default_thread: self.default_thread,
+            strings: HashMap::new(),
+            threads: HashMap::new(),
+            static_categories: Vec::new(),
+        }
+    }
+
+    /// Intern a string into the provider's string table.
+    /// Falls back to an inline string once the table is full.
+    pub fn string(&mut self, value: &str) -> StringRef {
+        if self.shared.interning == Interning::PerThread {
+            if let Some(index) = self.strings.get(value) {
+                return StringRef::Ref(*index);
+            }
+        }
+
+        match self.provider.intern_string(value) {
+            Some(index) => {
+                if self.shared.interning == Interning::PerThread {
+                    self.strings.insert(value.to_string(), index);
+                }
+                StringRef::Ref(index)
+            }
+            None => StringRef::Inline(value.to_string()),
+        }
+    }
+
+    /// Ref to a static string, defining it first if it was registered
+    /// after this writer started.
+    /// Falls back to an inline string if the registry is full, or if
+    /// interning already took its index.
+    pub fn static_string(&self, value: &TraceStr) -> StringRef {
+        self.provider.static_string(value)
+    }
+
+    /// Intern a thread into the provider's thread table.
+    /// Falls back to an inline thread once the table is full.
+    pub fn thread(&mut self, process_koid: u64, thread_koid: u64) -> ThreadRef {
+        let key = (process_koid, thread_koid);
+        if self.shared.interning == Interning::PerThread {
+            if let Some(index) = self.threads.get(&key) {
+                return ThreadRef::Ref(*index);
+            }
+        }
+
+        match self.provider.intern_thread(process_koid, thread_koid) {
+            Some(index) => {
+                if self.shared.interning == Interning::PerThread {
+                    self.threads.insert(key, index);
+                }
+                ThreadRef::Ref(index)
+            }
+            None => ThreadRef::Inline {
+                process_koid,
+                thread_koid,
+            },
+        }
+    }
+
+    /// Whether to write `record`: its category is enabled and the sampler keeps it.
+    /// `checked` is whether its category is already known to be enabled, and
+    /// `names` its category and name, if known without a string-table lookup.
+    fn keep(
+        &self,
+        record: &EventRecord,
+        event: &dyn Event,
+        checked: bool,
+        names: Option<(&str, &str)>,
+    ) -> bool {
+        let sampling = self.shared.sampler.is_active();
+        if checked && !sampling {
+            return true;
+        }
+        let check = |category: Option<&str>, name: Option<&str>| {
+            // strings not interned by this writer can't be checked
+            let Some(category) = category else {
+                return true;
+            };
+            (checked || self.shared.categories.is_enabled(category))
+                && (!sampling
+                    || self.shared.sampler.sample(
+                        self.provider.id,
+                        category,
+                        name.unwrap_or_default(),
+                        record,
+                    ))
+        };
+
+        match (names, event.category(), event.name()) {
+            (Some((category, name)), _, _) => check(Some(category), Some(name)),
+            (None, StringRef::Inline(category), StringRef::Inline(name)) => {
+                check(Some(category), Some(name))
+            }
+            (None, category, name) => {
+                let tables = self
+                    .provider
+                    .tables
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                check(tables.resolve_string(category), tables.resolve_string(name))
+            }
+        }
+    }
+
+    /// Encode a record into this thread's buffer.
+    /// Events of disabled categories, and events the sampler drops, are
+    /// not written.
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        self.write_filtered(record, false, None)
+    }
+
+    /// Encode a record whose category was already found enabled, e.g. by
+    /// an `EventBuilder`, with its category and name if known, so only the
+    /// sampler is consulted
+    pub(crate) fn write_checked(
+        &mut self,
+        record: &Record,
+        names: Option<(&str, &str)>,
+    ) -> Result<()> {
+        self.write_filtered(record, true, names)
+    }
+
+    fn write_filtered(
+        &mut self,
+        record: &Record,
+        checked: bool,
+        names: Option<(&str, &str)>,
+    ) -> Result<()> {
+        if self.managed {
+            match record {
+                Record::Metadata(MetadataRecord::MagicNumber)
+                | Record::Metadata(MetadataRecord::ProviderInfo(_))
+                | Record::Metadata(MetadataRecord::ProviderSection(_))
+                | Record::Initialization(_) => {
+                    return Err(FtfError::InvalidRecordOrder(format!(
+                        "{:?} is written by the session, not by a provider",
+                        record
+                    )))
+                }
+                _ => (),
+            }
+        }
+
+        if let Record::Event(event) = record {
+            if let Some(e) = event.as_event() {
+                if !self.keep(event, e, checked, names) {
+                    return Ok(());
+                }
+            }
+        }
+
+        record.write(&mut *lock(&self.buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Archive;
+    use std::io::Cursor;
+
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            lock(&self.0).extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedSink {
+        fn archive(&self) -> Archive {
+            Archive::read(Cursor::new(lock(&self.0).clone())).unwrap()
+        }
+    }
+
+    fn instant(w: &mut ThreadWriter, ts: u64, name: &str) -> Result<()> {
+        let thread = w.thread(1, 2);
+        let category = w.string("test");
+        let name = w.string(name);
+        w.write(&Record::create_instant_event(
+            ts,
+            thread,
+            category,
+            name,
+            Vec::new(),
+        ))
+    }
+
+    #[test]
+    fn test_single_thread_writer() -> Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+
+        let mut w = writer.thread_writer();
+        instant(&mut w, 10, "first")?;
+        instant(&mut w, 20, "first")?;
+        writer.finish()?;
+
+        let archive = sink.archive();
+        assert_eq!(
+            archive.records,
+            vec![
+                Record::create_magic_number(),
+                Record::create_thread(1, 1, 2),
+                Record::create_string(1, "test"),
+                Record::create_string(2, "first"),
+                Record::create_instant_event(
+                    10,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    StringRef::Ref(2),
+                    Vec::new()
+                ),
+                Record::create_instant_event(
+                    20,
+                    ThreadRef::Ref(1),
+                    StringRef::Ref(1),
+                    StringRef::Ref(2),
+                    Vec::new()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    fn check_concurrent(interning: Interning) -> Result<()> {
+        let sink = SharedSink::default();
+        let mut writer = TraceWriter::new(sink.clone(), interning)?;
+        writer.spawn_flusher(Duration::from_millis(1));
+
+        std::thread::scope(|s| {
+            for t in 0..4_u64 {
+                let mut w = writer.thread_writer();
+                s.spawn(move || {
+                    for i in 0..500_u64 {
+                        let thread = w.thread(1, t);
+                        let category = w.string("test");
+                        let name = w.string(&format!("event_{}", i % 50));
+                        w.write(&Record::create_instant_event(
+                            i,
+                            thread,
+                            category,
+                            name,
+                            Vec::new(),
+                        ))
+                        .unwrap();
+                    }
+                });
+            }
+        });
+        writer.finish()?;
+
+        let archive = sink.archive();
+        assert_eq!(
+            archive.records[0],
+            Record::Metadata(MetadataRecord::MagicNumber)
+        );
+
+        // every ref resolves to an earlier definition, and
+        // each thread's events are in the order they were written
+        let mut strings = StringTable::new();
+        let mut threads = ThreadTable::new();
+        let mut last_ts: HashMap<u8, u64> = HashMap::new();
+        let mut events = 0;
+        for record in &archive.records {
+            match record {
+                Record::String(s) => strings.insert(s.index(), s.value().clone()),
+                Record::Thread(t) => threads.insert(t.index(), t.process_koid(), t.thread_koid()),
+                Record::Event(EventRecord::Instant(e)) => {
+                    use crate::Event;
+                    let (StringRef::Ref(c), StringRef::Ref(n), ThreadRef::Ref(t)) =
+                        (e.category(), e.name(), e.thread())
+                    else {
+                        panic!("expected refs");
+                    };
+                    assert_eq!(strings.get(*c), Some("test"));
+                    assert!(strings.get(*n).is_some());
+                    assert!(threads.get(*t).is_some());
+
+                    if let Some(prev) = last_ts.insert(*t, e.timestamp()) {
+                        assert!(prev < e.timestamp());
+                    }
+                    events += 1;
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(events, 4 * 500);
+        assert_eq!(strings.len(), 51);
+        assert_eq!(threads.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_shared_interning() -> Result<()> {
+        check_concurrent(Interning::Shared)
+    }
+
+    #[test]
+    fn test_concurrent_per_thread_interning() -> Result<()> {
+        check_concurrent(Interning::PerThread)
+    }
+
+    #[test]
+    fn test_string_table_overflow_falls_back_to_inline() -> Result<()> {
+        let writer = TraceWriter::new(std::io::sink(), Interning::Shared)?;
+        let mut w = writer.thread_writer();
+        for i in 1..=StringTable::MAX_INDEX {
+            assert_eq!(w.string(&i.to_string()), StringRef::Ref(i));
+        }
+        assert_eq!(
+            w.string("overflow"),
+            StringRef::Inline("overflow".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_makes_sections_standalone() -> Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        writer.set_checkpoint(Some(Checkpoint::Bytes(1)));
+
+        let mut w = writer.thread_writer();
+        instant(&mut w, 1, "a")?;
+        writer.flush()?;
+        let section_start = lock(&sink.0).len();
+
+        instant(&mut w, 2, "a")?;
+        instant(&mut w, 3, "b")?;
+        writer.flush()?;
+
+        // the second section redefines everything it uses
+        let section = lock(&sink.0)[section_start..].to_vec();
+        let records = Archive::read(Cursor::new(section))?.records;
+        assert_eq!(
+            &records[..4],
+            &[
+                Record::create_string(1, "test"),
+                Record::create_string(2, "a"),
+                Record::create_string(3, "b"),
+                Record::create_thread(1, 1, 2),
+            ]
+        );
+        assert_eq!(records.len(), 6);
+
+        // and without a checkpoint due, only new definitions are written
+        writer.set_checkpoint(Some(Checkpoint::Interval(Duration::from_secs(3600))));
+        let section_start = lock(&sink.0).len();
+        instant(&mut w, 4, "c")?;
+        writer.flush()?;
+        let section = lock(&sink.0)[section_start..].to_vec();
+        let records = Archive::read(Cursor::new(section))?.records;
+        assert_eq!(records[0], Record::create_string(4, "c"));
+        assert_eq!(records.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_flushes() -> Result<()> {
+        let sink = SharedSink::default();
+        {
+            let writer = TraceWriter::new(sink.clone(), Interning::PerThread)?;
+            let mut w = writer.thread_writer();
+            instant(&mut w, 1, "dropped")?;
+        }
+        assert_eq!(sink.archive().records.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_disabled_categories_are_dropped() -> Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        let mut w = writer.thread_writer();
+
+        writer.categories().set_patterns(["-te*"]);
+        instant(&mut w, 1, "dropped")?;
+        w.event("test", "builder")
+            .arg_str("never", "interned")
+            .write()?;
+
+        // changed while the trace keeps going
+        writer.categories().enable_all();
+        instant(&mut w, 2, "kept")?;
+        writer.finish()?;
+
+        let events: Vec<_> = sink
+            .archive()
+            .records
+            .into_iter()
+            .filter(|r| matches!(r, Record::Event(_)))
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0],
+            Record::create_instant_event(
+                2,
+                ThreadRef::Ref(1),
+                StringRef::Ref(1),
+                StringRef::Ref(3),
+                Vec::new()
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sampled_events_are_reported() -> Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        writer
+            .sampler()
+            .add_rule("test", Some("hot"), crate::SamplingPolicy::OneInN(2));
+
+        let mut w = writer.thread_writer();
+        for ts in 0..5 {
+            instant(&mut w, ts, "hot")?;
+        }
+        instant(&mut w, 5, "cold")?;
+        writer.flush()?;
+        // nothing more to report
+        writer.flush()?;
+        writer.finish()?;
+
+        let events: Vec<_> = sink
+            .archive()
+            .records
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Event(e) => Some(e),
+                _ => None,
+            })
+            .collect();
+        let timestamps: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                EventRecord::Instant(i) => Some(i.timestamp()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(timestamps, vec![0, 2, 4, 5]);
+
+        let counters: Vec<_> = events
+            .iter()
+            .filter_map(|e| match e {
+                EventRecord::Counter(c) => Some(c),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(counters.len(), 1);
+        assert_eq!(counters[0].name(), &StringRef::Ref(2));
+        assert_eq!(
+            counters[0].arguments(),
+            &[crate::Argument::UInt64(StringRef::Ref(4), 2)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_panic_hook_flushes() -> Result<()> {
+        let sink = SharedSink::default();
+        let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+        assert_eq!(writer.register_hooks(), cfg!(unix));
+
+        let mut w = writer.thread_writer();
+        instant(&mut w, 1, "before")?;
+        let result = std::thread::spawn(|| panic!("writer boom")).join();
+        assert!(result.is_err());
+
+        // flushed by the hook, before the writer is
+        let panics: Vec<_> = sink
+            .archive()
+            .records
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Event(EventRecord::Instant(i)) => Some(i),
+                _ => None,
+            })
+            .collect();
+        // other tests may panic while the hook is installed
+        let message = Argument::Str(
+            StringRef::Inline("message".to_string()),
+            StringRef::Inline("writer boom".to_string()),
+        );
+        let panic = panics
+            .iter()
+            .find(|p| p.arguments().first() == Some(&message))
+            .expect("no panic event for this test");
+        assert_eq!(panic.name(), &StringRef::Inline("panic".to_string()));
+        let arguments = panic.arguments();
+        match &arguments[1] {
+            Argument::Str(_, StringRef::Inline(location)) => {
+                assert!(location.starts_with(file!()), "{}", location)
+            }
+            other => panic!("unexpected argument {:?}", other),
+        }
+        Ok(())
+    }
+}
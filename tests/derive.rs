@@ -0,0 +1,116 @@
+#![cfg(feature = "derive")]
+
+use ftfrs::{
+    Archive, Argument, Event, EventRecord, FtfArgs, Interning, Record, Result, StringRef,
+    TraceWriter,
+};
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(FtfArgs)]
+struct Request<'a> {
+    id: u64,
+    #[ftf(rename = "table")]
+    table_name: &'a str,
+    retries: Option<u8>,
+    #[ftf(pointer)]
+    buffer: u64,
+    #[ftf(koid)]
+    process: u64,
+    #[ftf(skip)]
+    #[allow(dead_code)]
+    body: Vec<u8>,
+}
+
+#[derive(FtfArgs)]
+struct Empty {}
+
+#[test]
+fn test_derived_arguments() -> Result<()> {
+    assert_eq!(Request::ARGUMENT_COUNT, 5);
+    assert_eq!(Empty::ARGUMENT_COUNT, 0);
+
+    let sink = SharedSink::default();
+    let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+    let mut w = writer.thread_writer();
+    let request = Request {
+        id: 7,
+        table_name: "users",
+        retries: None,
+        buffer: 0x1000,
+        process: 42,
+        body: Vec::new(),
+    };
+    w.event("db", "query")
+        .args(&request)
+        .args(&Empty {})
+        .write()?;
+    w.event("db", "query").args(&request).write()?;
+    writer.finish()?;
+
+    let records = Archive::read(Cursor::new(sink.0.lock().unwrap().clone()))?.records;
+    let string = |s: &StringRef| match s {
+        StringRef::Ref(index) => records
+            .iter()
+            .find_map(|r| match r {
+                Record::String(s) if s.index() == *index => Some(s.value().clone()),
+                _ => None,
+            })
+            .unwrap(),
+        StringRef::Inline(s) => s.clone(),
+    };
+    let events: Vec<_> = records
+        .iter()
+        .filter_map(|r| match r {
+            Record::Event(EventRecord::Instant(e)) => Some(e),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(events.len(), 2);
+
+    let arguments: Vec<_> = events[0]
+        .arguments()
+        .iter()
+        .map(|a| match a {
+            Argument::UInt64(name, v) => (string(name), format!("u64 {}", v)),
+            Argument::Str(name, v) => (string(name), format!("str {}", string(v))),
+            Argument::Null(name) => (string(name), "null".to_string()),
+            Argument::Pointer(name, v) => (string(name), format!("pointer {:#x}", v)),
+            Argument::KernelObjectId(name, v) => (string(name), format!("koid {}", v)),
+            other => panic!("unexpected argument {:?}", other),
+        })
+        .collect();
+    let expected = [
+        ("id", "u64 7"),
+        ("table", "str users"),
+        ("retries", "null"),
+        ("buffer", "pointer 0x1000"),
+        ("process", "koid 42"),
+    ];
+    assert_eq!(
+        arguments,
+        expected.map(|(n, v)| (n.to_string(), v.to_string()))
+    );
+
+    // names are the same static strings every time
+    assert_eq!(events[0].arguments(), events[1].arguments());
+    assert!(matches!(
+        events[0].arguments()[0],
+        Argument::UInt64(StringRef::Ref(_), 7)
+    ));
+    Ok(())
+}
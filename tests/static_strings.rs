@@ -0,0 +1,106 @@
+// Static strings are registered process-wide, and every writer defines
+// them, so they are tested apart from the writer's unit tests.
+
+use ftfrs::{
+    trace_str, Archive, Event, EventRecord, Interning, Record, Result, StringRef, TraceStr,
+    TraceWriter,
+};
+use std::io::{Cursor, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_registration() {
+    static FIRST: TraceStr = trace_str!("first");
+    static SAME: TraceStr = trace_str!("first");
+    static OTHER: TraceStr = trace_str!("other");
+
+    let first = FIRST.index().unwrap();
+    assert_eq!(FIRST.index(), Some(first));
+    assert_eq!(SAME.index(), Some(first));
+    assert_ne!(OTHER.index(), Some(first));
+    assert_eq!(OTHER.as_str(), "other");
+}
+
+#[test]
+fn test_static_events() -> Result<()> {
+    static CATEGORY: TraceStr = trace_str!("db");
+    static EARLY: TraceStr = trace_str!("query");
+    static LATE: TraceStr = trace_str!("commit");
+    let (category, early) = (CATEGORY.register().unwrap(), EARLY.register().unwrap());
+
+    let sink = SharedSink::default();
+    let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+    let mut w = writer.thread_writer();
+    w.static_event(&CATEGORY, &EARLY).timestamp(1).write()?;
+    // registered after the writer started
+    w.static_event(&CATEGORY, &LATE).timestamp(2).write()?;
+    let late = LATE.index().unwrap();
+    // interning finds the static index
+    assert_eq!(w.string("query"), StringRef::Ref(early));
+    writer.finish()?;
+
+    let records = Archive::read(Cursor::new(sink.0.lock().unwrap().clone()))?.records;
+    let defined = |index: u16, value: &str| {
+        records
+            .iter()
+            .position(|r| *r == Record::create_string(index, value))
+            .unwrap()
+    };
+    let events: Vec<_> = records
+        .iter()
+        .enumerate()
+        .filter_map(|(i, r)| match r {
+            Record::Event(EventRecord::Instant(e)) => Some((i, e)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].1.category(), &StringRef::Ref(category));
+    assert_eq!(events[0].1.name(), &StringRef::Ref(early));
+    assert_eq!(events[1].1.name(), &StringRef::Ref(late));
+    assert!(defined(category, "db") < events[0].0);
+    assert!(defined(late, "commit") < events[1].0);
+    Ok(())
+}
+
+#[test]
+fn test_static_category_follows_patterns() -> Result<()> {
+    static CATEGORY: TraceStr = trace_str!("cache");
+    static NAME: TraceStr = trace_str!("hit");
+
+    let sink = SharedSink::default();
+    let writer = TraceWriter::new(sink.clone(), Interning::Shared)?;
+    let mut w = writer.thread_writer();
+    w.static_event(&CATEGORY, &NAME).timestamp(1).write()?;
+    // the cached enabled bit follows pattern changes
+    writer.categories().disable("cache");
+    w.static_event(&CATEGORY, &NAME).timestamp(2).write()?;
+    writer.categories().enable_all();
+    w.static_event(&CATEGORY, &NAME).timestamp(3).write()?;
+    writer.finish()?;
+
+    let records = Archive::read(Cursor::new(sink.0.lock().unwrap().clone()))?.records;
+    let timestamps: Vec<_> = records
+        .iter()
+        .filter_map(|r| match r {
+            Record::Event(EventRecord::Instant(e)) => Some(e.timestamp()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(timestamps, vec![1, 3]);
+    Ok(())
+}